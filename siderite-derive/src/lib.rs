@@ -0,0 +1,109 @@
+//! Derive macros for siderite. Use them through the `derive` feature of
+//! siderite, which re-exports them.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{Data, DeriveInput, Fields, Lit, Meta, NestedMeta, parse_macro_input, spanned::Spanned};
+
+/// Implement `siderite::document::DdpDocument` for a struct with named fields.
+///
+/// Each field maps to the document field of the same name, unless renamed
+/// with `#[ddp(rename = "name")]`. Fields marked `#[ddp(skip)]` are not part
+/// of the document, and start from their `Default` value.
+#[proc_macro_derive(DdpDocument, attributes(ddp))]
+pub fn derive_ddp_document(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// A struct field, and the document field it maps to, if any.
+struct Field {
+    ident: syn::Ident,
+    name: Option<String>,
+}
+
+fn parse_field(field: &syn::Field) -> syn::Result<Field> {
+    let ident = field.ident.clone().expect("named field");
+    let mut name = Some(ident.to_string());
+    for attr in field.attrs.iter().filter(|attr| attr.path.is_ident("ddp")) {
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            meta => return Err(syn::Error::new(meta.span(), "expected #[ddp(...)]")),
+        };
+        for nested in list.nested {
+            match nested {
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("skip") => name = None,
+                NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("rename") => match nv.lit {
+                    Lit::Str(s) => name = Some(s.value()),
+                    lit => return Err(syn::Error::new(lit.span(), "expected a string")),
+                },
+                other => return Err(syn::Error::new(other.span(), "unknown ddp attribute")),
+            }
+        }
+    }
+    Ok(Field { ident, name })
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(syn::Error::new(input.span(), "DdpDocument needs named fields")),
+        },
+        _ => return Err(syn::Error::new(input.span(), "DdpDocument can only be derived for structs")),
+    };
+    let fields = fields.iter().map(parse_field).collect::<syn::Result<Vec<_>>>()?;
+
+    let construct = fields.iter().map(|Field { ident, name }| match name {
+        Some(name) => quote! { #ident: ::siderite::document::read_field(fields, #name)? },
+        None => quote! { #ident: ::std::default::Default::default() },
+    });
+
+    let mapped: Vec<_> = fields.iter().filter_map(|f| f.name.as_ref().map(|name| (&f.ident, name))).collect();
+    let apply = mapped.iter().map(|(ident, name)| quote! {
+        if let ::std::option::Option::Some(value) = fields.get(#name) {
+            self.#ident = ::siderite::document::read_value(value, #name)?;
+        }
+        if cleared.iter().any(|field| field == #name) {
+            self.#ident = ::siderite::document::cleared_field(#name)?;
+        }
+    });
+    let write = mapped.iter().map(|(ident, name)| quote! {
+        ::siderite::document::write_field(&mut fields, #name, &self.#ident)?;
+    });
+    let diff = mapped.iter().map(|(ident, name)| quote! {
+        changes.diff_field(#name, &self.#ident, &old.#ident)?;
+    });
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        #[allow(unused_variables, unused_mut)]
+        impl #impl_generics ::siderite::document::DdpDocument for #ident #ty_generics #where_clause {
+            fn from_fields(fields: &::siderite::document::Document) -> ::siderite::Result<Self> {
+                ::std::result::Result::Ok(Self { #(#construct,)* })
+            }
+
+            fn apply_changes(&mut self, fields: &::siderite::document::Document, cleared: &[::std::string::String]) -> ::siderite::Result<()> {
+                #(#apply)*
+                ::std::result::Result::Ok(())
+            }
+
+            fn to_fields(&self) -> ::siderite::Result<::siderite::document::Document> {
+                let mut fields = ::siderite::document::Document::new();
+                #(#write)*
+                ::std::result::Result::Ok(fields)
+            }
+
+            fn changes_from(&self, old: &Self) -> ::siderite::Result<::siderite::document::Changes> {
+                let mut changes = ::siderite::document::Changes::default();
+                #(#diff)*
+                ::std::result::Result::Ok(changes)
+            }
+        }
+    })
+}
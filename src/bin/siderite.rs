@@ -0,0 +1,131 @@
+//! A command line client for DDP endpoints, built with the `cli` feature.
+//!
+//! ```text
+//! siderite <url> call <method> [params...]
+//! siderite <url> sub <publication> [params...]
+//! siderite <url> repl
+//! ```
+//!
+//! Parameters are parsed as JSON, or taken as strings if they are not valid
+//! JSON. Messages from the server are printed as pretty JSON.
+
+use futures::StreamExt;
+use serde_json::Value;
+use siderite::{Connection, Handle, ServerMessage};
+use std::error::Error;
+use std::process::exit;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+const USAGE: &str = "\
+usage: siderite <url> call <method> [params...]
+       siderite <url> sub <publication> [params...]
+       siderite <url> repl
+
+In the REPL, type `call <method> [params...]`, `sub <publication> [params...]`,
+`unsub <id>` or `quit`.";
+
+fn params(args: &[String]) -> Vec<Value> {
+    args.iter()
+        .map(|arg| serde_json::from_str(arg).unwrap_or_else(|_| Value::String(arg.clone())))
+        .collect()
+}
+
+fn print(msg: &ServerMessage) {
+    match serde_json::to_string_pretty(msg) {
+        Ok(json) => println!("{}", json),
+        Err(e) => eprintln!("unprintable message: {}", e),
+    }
+}
+
+async fn call(handle: &mut Handle, method: &str, args: &[String]) -> siderite::Result<()> {
+    match handle.call(method.to_string(), params(args)).await? {
+        Ok(result) => println!("{}", serde_json::to_string_pretty(&result)?),
+        Err(e) => eprintln!("error: {}", e),
+    }
+    Ok(())
+}
+
+async fn sub(handle: &mut Handle, name: &str, args: &[String]) -> siderite::Result<String> {
+    let id = handle.subscription_id();
+    handle.subscribe(id.clone(), name.to_string(), params(args)).await?.detach();
+    Ok(id)
+}
+
+async fn repl(connection: Connection) -> Result<(), Box<dyn Error>> {
+    let (mut messages, mut handle) = connection.split();
+    let printer = tokio::spawn(async move {
+        while let Some(msg) = messages.next().await {
+            print(&msg);
+        }
+        match messages.take_error() {
+            Some(e) => eprintln!("connection lost: {}", e),
+            None => eprintln!("connection closed"),
+        }
+        exit(1);
+    });
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Some(line) = lines.next_line().await? {
+        let words: Vec<String> = line.split_whitespace().map(String::from).collect();
+        let res = match words.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+            [] => Ok(()),
+            ["quit"] | ["exit"] => break,
+            ["call", method, ..] => call(&mut handle, method, &words[2..]).await,
+            ["sub", name, ..] => sub(&mut handle, name, &words[2..]).await.map(|id| println!("subscribed as {}", id)),
+            ["unsub", id] => handle.unsubscribe(id.to_string()).await,
+            _ => {
+                eprintln!("{}", USAGE);
+                Ok(())
+            },
+        };
+        if let Err(e) = res {
+            eprintln!("error: {}", e);
+        }
+    }
+    printer.abort();
+    Ok(handle.close().await?)
+}
+
+async fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let (url, command) = match args {
+        [url, command, ..] => (url, command.as_str()),
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(2)
+        },
+    };
+
+    let mut connection = Connection::connect(url).await?;
+    match (command, &args[2..]) {
+        ("call", [method, rest @ ..]) => {
+            let mut handle = connection.handle();
+            call(&mut handle, method, rest).await?;
+            Ok(connection.close().await?)
+        },
+        ("sub", [name, rest @ ..]) => {
+            let mut handle = connection.handle();
+            sub(&mut handle, name, rest).await?;
+            while let Some(msg) = connection.recv().await {
+                print(&msg);
+            }
+            match connection.take_error() {
+                Some(e) => Err(e.into()),
+                None => Ok(()),
+            }
+        },
+        ("repl", []) => repl(connection).await,
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(2)
+        },
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(e) = run(&args).await {
+        eprintln!("error: {}", e);
+        exit(1);
+    }
+}
@@ -0,0 +1,457 @@
+//! A local copy of the documents published by the server, in the manner of
+//! Meteor's minimongo. Enable it with [`crate::ConnectionBuilder::cache`],
+//! then read it through [`crate::Handle::cache`].
+
+use futures::{Stream, stream};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::watch;
+use crate::protocol::{Fields, ServerMessage};
+
+/// The fields of a document, without its id.
+pub type Document = Map<String, Value>;
+
+/// The documents matching a query, by id.
+pub type ResultSet = BTreeMap<String, Document>;
+
+type Collections = HashMap<String, HashMap<String, Document>>;
+
+/// A copy of all the documents of a [`Cache`], by collection and id, as
+/// taken by [`Cache::snapshot`]. It serializes as a JSON object of
+/// collections, to be saved between runs.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Snapshot {
+    pub collections: BTreeMap<String, BTreeMap<String, Document>>,
+}
+
+/// A live query, see [`Cache::query`].
+struct Query {
+    collection: String,
+    predicate: Box<dyn Fn(&Document) -> bool + Send + Sync>,
+    results: ResultSet,
+    tx: watch::Sender<ResultSet>,
+}
+
+impl Query {
+
+    /// Account for the new state of a document. Returns whether the result set changed.
+    fn update(&mut self, id: &str, doc: Option<&Document>) -> bool {
+        match (doc.filter(|doc| (self.predicate)(doc)), self.results.get_mut(id)) {
+            (Some(doc), Some(result)) if result == doc => false,
+            (Some(doc), Some(result)) => {
+                *result = doc.clone();
+                true
+            },
+            (Some(doc), None) => {
+                self.results.insert(id.to_string(), doc.clone());
+                true
+            },
+            (None, _) => self.results.remove(id).is_some(),
+        }
+    }
+
+    /// Publish the result set. Returns false once the stream is gone.
+    fn publish(&self) -> bool {
+        self.tx.send(self.results.clone()).is_ok()
+    }
+
+}
+
+impl std::fmt::Debug for Query {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Query")
+            .field("collection", &self.collection)
+            .field("results", &self.results.len())
+            .finish()
+    }
+}
+
+/// The writes of method stubs, see [`crate::ConnectionBuilder::stub`].
+#[derive(Debug, Default)]
+struct Simulation {
+    /// The server's version of the documents written by stubs.
+    server_docs: HashMap<(String, String), Option<Document>>,
+    /// The documents written by the stub of each method in flight.
+    writes: HashMap<String, Vec<(String, String)>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    collections: RwLock<Collections>,
+    queries: Mutex<Vec<Query>>,
+    simulation: Mutex<Simulation>,
+}
+
+/// Apply a document message to the current state of a document.
+fn update(doc: &mut Option<Document>, msg: &ServerMessage) {
+    match msg {
+        ServerMessage::Added { fields, .. } | ServerMessage::AddedBefore { fields, .. } => {
+            *doc = Some(fields.as_ref().map(Fields::to_object).unwrap_or_default());
+        },
+        ServerMessage::Changed { fields, cleared, .. } => {
+            let doc = doc.get_or_insert_with(Document::new);
+            if let Some(fields) = fields {
+                doc.extend(fields.to_object());
+            }
+            for field in cleared.iter().flatten() {
+                doc.remove(field);
+            }
+        },
+        ServerMessage::Removed { .. } => *doc = None,
+        _ => (),
+    }
+}
+
+/// Put a document back in its collection, taken out beforehand. The
+/// collection name is only copied for the first document of a collection.
+fn store(collections: &mut Collections, collection: &str, id: String, doc: Option<Document>) {
+    match doc {
+        Some(doc) => match collections.get_mut(collection) {
+            Some(docs) => {
+                docs.insert(id, doc);
+            },
+            None => {
+                collections.entry(collection.to_string()).or_default().insert(id, doc);
+            },
+        },
+        None => {
+            if matches!(collections.get(collection), Some(docs) if docs.is_empty()) {
+                collections.remove(collection);
+            }
+        },
+    }
+}
+
+/// A shared, per-collection map of documents, kept up to date with the
+/// `added`, `changed` and `removed` messages of the server.
+/// Clones refer to the same cache.
+#[derive(Clone, Debug, Default)]
+pub struct Cache {
+    inner: Arc<Inner>,
+}
+
+impl Cache {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, Collections> {
+        // A panic while holding the lock cannot leave a document half-updated
+        self.inner.collections.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, Collections> {
+        self.inner.collections.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn queries(&self) -> std::sync::MutexGuard<'_, Vec<Query>> {
+        self.inner.queries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn simulation(&self) -> std::sync::MutexGuard<'_, Simulation> {
+        self.inner.simulation.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Let the queries on a collection know about the new state of a document,
+    /// and forget those whose stream was dropped.
+    fn notify(&self, collection: &str, id: &str, doc: Option<&Document>) {
+        self.queries().retain_mut(|query| {
+            if query.collection != collection || !query.update(id, doc) {
+                return true
+            }
+            query.publish()
+        });
+    }
+
+    /// Update the cache with a server message. Returns whether the message
+    /// concerned a document at all. Documents written by method stubs are
+    /// left alone until the methods are done, see [`Cache::updated`].
+    pub fn apply(&self, msg: &ServerMessage) -> bool {
+        let (collection, id) = match msg {
+            ServerMessage::Added { collection, id, .. } |
+            ServerMessage::AddedBefore { collection, id, .. } |
+            ServerMessage::Changed { collection, id, .. } |
+            ServerMessage::Removed { collection, id } => (collection.as_str(), id.as_str()),
+            _ => return false,
+        };
+        // Queries are notified under the write lock, so that they see updates in order
+        let mut collections = self.write();
+        let mut simulation = self.simulation();
+        // Most of the time, no stub is pending: spare building the key
+        if !simulation.server_docs.is_empty() {
+            if let Some(doc) = simulation.server_docs.get_mut(&(collection.to_string(), id.to_string())) {
+                update(doc, msg);
+                return true
+            }
+        }
+        drop(simulation);
+
+        // Reuse the id of the document already there, if any
+        let (id, mut doc) = match collections.get_mut(collection).and_then(|docs| docs.remove_entry(id)) {
+            Some((id, doc)) => (id, Some(doc)),
+            None => (id.to_string(), None),
+        };
+        update(&mut doc, msg);
+        self.notify(collection, &id, doc.as_ref());
+        store(&mut collections, collection, id, doc);
+        true
+    }
+
+    /// Write a document on behalf of a method stub, until the method is done.
+    pub(crate) fn simulate(&self, method: &str, collection: &str, id: &str, doc: Option<Document>) {
+        let mut collections = self.write();
+        let key = (collection.to_string(), id.to_string());
+        let mut simulation = self.simulation();
+        if !simulation.server_docs.contains_key(&key) {
+            // Keep the server's version aside, to be restored
+            let current = collections.get(collection).and_then(|docs| docs.get(id)).cloned();
+            simulation.server_docs.insert(key.clone(), current);
+        }
+        let writes = simulation.writes.entry(method.to_string()).or_default();
+        if !writes.contains(&key) {
+            writes.push(key);
+        }
+        drop(simulation);
+
+        self.notify(collection, id, doc.as_ref());
+        if let Some(docs) = collections.get_mut(collection) {
+            docs.remove(id);
+        }
+        store(&mut collections, collection, id.to_string(), doc);
+    }
+
+    /// The methods are done, and the server has sent their writes: the
+    /// documents written by their stubs get back in sync with the server,
+    /// once no other stub is pending on them.
+    pub fn updated(&self, methods: &[String]) {
+        let mut collections = self.write();
+        let mut simulation = self.simulation();
+        let mut done = Vec::new();
+        for method in methods {
+            done.extend(simulation.writes.remove(method).into_iter().flatten());
+        }
+        for key in done {
+            if simulation.writes.values().flatten().any(|k| *k == key) {
+                continue
+            }
+            if let Some(doc) = simulation.server_docs.remove(&key) {
+                let (collection, id) = key;
+                self.notify(&collection, &id, doc.as_ref());
+                if let Some(docs) = collections.get_mut(&collection) {
+                    docs.remove(&id);
+                }
+                store(&mut collections, &collection, id, doc);
+            }
+        }
+    }
+
+    /// Drop the writes of all method stubs, as when their methods can no
+    /// longer complete.
+    pub(crate) fn revert_stubs(&self) {
+        let methods: Vec<String> = self.simulation().writes.keys().cloned().collect();
+        self.updated(&methods);
+    }
+
+    /// A copy of all the documents, to be restored later with
+    /// [`Cache::restore`]. Documents written by method stubs are copied as
+    /// the server last sent them.
+    pub fn snapshot(&self) -> Snapshot {
+        let collections = self.read();
+        let mut snapshot = Snapshot::default();
+        for (collection, docs) in collections.iter() {
+            let docs = docs.iter().map(|(id, doc)| (id.clone(), doc.clone()));
+            snapshot.collections.insert(collection.clone(), docs.collect());
+        }
+        for ((collection, id), doc) in &self.simulation().server_docs {
+            let docs = snapshot.collections.entry(collection.clone()).or_default();
+            match doc {
+                Some(doc) => docs.insert(id.clone(), doc.clone()),
+                None => docs.remove(id),
+            };
+            if docs.is_empty() {
+                snapshot.collections.remove(collection);
+            }
+        }
+        snapshot
+    }
+
+    /// Add the documents of a snapshot, replacing the cached ones with the
+    /// same ids, e.g. to show the state of the last run before the server
+    /// sends the documents anew. Restore it before subscribing, such as on
+    /// a [`crate::Connection::connect_lazy`] connection: documents that the
+    /// server no longer publishes stay until the cache is cleared.
+    pub fn restore(&self, snapshot: Snapshot) {
+        let mut collections = self.write();
+        for (collection, docs) in snapshot.collections {
+            for (id, doc) in docs {
+                self.notify(&collection, &id, Some(&doc));
+                store(&mut collections, &collection, id, Some(doc));
+            }
+        }
+    }
+
+    /// Forget all documents, e.g. when the server session is lost.
+    pub fn clear(&self) {
+        let mut collections = self.write();
+        collections.clear();
+        *self.simulation() = Simulation::default();
+        self.queries().retain_mut(|query| {
+            if query.results.is_empty() {
+                return true
+            }
+            query.results.clear();
+            query.publish()
+        });
+    }
+
+    /// Watch the documents of a collection matching a predicate. The stream
+    /// yields the current result set first, then the new one whenever a
+    /// document enters, leaves, or changes within it. A slow consumer only
+    /// sees the latest result set.
+    pub fn query<F>(&self, collection: &str, predicate: F) -> impl Stream<Item = ResultSet>
+        where F: Fn(&Document) -> bool + Send + Sync + 'static
+    {
+        // Hold the documents still while the query is registered
+        let collections = self.read();
+        let results: ResultSet = collections.get(collection).into_iter().flatten()
+            .filter(|(_, doc)| predicate(doc))
+            .map(|(id, doc)| (id.clone(), doc.clone()))
+            .collect();
+
+        let (tx, rx) = watch::channel(results.clone());
+        self.queries().push(Query {
+            collection: collection.to_string(),
+            predicate: Box::new(predicate),
+            results,
+            tx,
+        });
+        drop(collections);
+
+        stream::unfold((rx, true), |(mut rx, first)| async move {
+            if !first {
+                rx.changed().await.ok()?;
+            }
+            let results = rx.borrow().clone();
+            Some((results, (rx, false)))
+        })
+    }
+
+    /// A copy of a document.
+    pub fn get(&self, collection: &str, id: &str) -> Option<Document> {
+        self.read().get(collection)?.get(id).cloned()
+    }
+
+    /// A document, deserialized from EJSON. Returns `None` if it is absent,
+    /// or if it cannot be deserialized as a `T`.
+    pub fn get_as<T: DeserializeOwned>(&self, collection: &str, id: &str) -> Option<T> {
+        let doc = self.get(collection, id)?;
+        crate::ejson::from_value(Value::Object(doc)).ok()
+    }
+
+    /// The names of the collections holding at least one document.
+    pub fn collections(&self) -> Vec<String> {
+        self.read().keys().cloned().collect()
+    }
+
+    /// The number of documents in a collection.
+    pub fn len(&self, collection: &str) -> usize {
+        self.read().get(collection).map_or(0, HashMap::len)
+    }
+
+    pub fn is_empty(&self, collection: &str) -> bool {
+        self.len(collection) == 0
+    }
+
+    /// A copy of all the documents of a collection, with their ids, in no particular order.
+    pub fn documents(&self, collection: &str) -> Vec<(String, Document)> {
+        self.read().get(collection)
+            .map(|docs| docs.iter().map(|(id, doc)| (id.clone(), doc.clone())).collect())
+            .unwrap_or_default()
+    }
+
+    /// Run a closure over the documents of a collection without copying them.
+    /// The cache cannot be updated while it runs, so keep it short.
+    pub fn for_each<F: FnMut(&str, &Document)>(&self, collection: &str, mut f: F) {
+        if let Some(docs) = self.read().get(collection) {
+            for (id, doc) in docs {
+                f(id, doc)
+            }
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::protocol::SmartString;
+    use serde_json::json;
+
+    #[test]
+    fn test_apply() {
+        let cache = Cache::new();
+        let users = SmartString::from("users");
+
+        cache.apply(&ServerMessage::Added { collection: users.clone(), id: "a".into(),
+                                            fields: Some(json!({"name": "alice", "age": 30}).into()) });
+        cache.apply(&ServerMessage::Changed { collection: users.clone(), id: "a".into(),
+                                              fields: Some(json!({"age": 31}).into()),
+                                              cleared: Some(vec!["name".to_string()]) });
+        assert_eq!(Value::Object(cache.get("users", "a").unwrap()), json!({"age": 31}));
+        assert_eq!(cache.len("users"), 1);
+
+        assert!(!cache.apply(&ServerMessage::Ready { subs: vec![] }));
+        assert!(cache.apply(&ServerMessage::Removed { collection: users, id: "a".into() }));
+        assert_eq!(cache.get("users", "a"), None);
+        assert!(cache.collections().is_empty());
+    }
+
+    #[test]
+    fn test_query() {
+        use futures::{executor::block_on, StreamExt};
+
+        let cache = Cache::new();
+        let add = |id: &str, age: u32| cache.apply(&ServerMessage::Added {
+            collection: "users".into(), id: id.into(), fields: Some(json!({"age": age}).into())
+        });
+
+        add("a", 20);
+        let adults = cache.query("users", |doc| doc["age"].as_u64() >= Some(18));
+        futures::pin_mut!(adults);
+        let ids = |results: ResultSet| results.keys().cloned().collect::<Vec<_>>();
+
+        assert_eq!(ids(block_on(adults.next()).unwrap()), vec!["a"]);
+        add("b", 10);
+        add("c", 40);
+        assert_eq!(ids(block_on(adults.next()).unwrap()), vec!["a", "c"]);
+        cache.apply(&ServerMessage::Removed { collection: "users".into(), id: "a".into() });
+        assert_eq!(ids(block_on(adults.next()).unwrap()), vec!["c"]);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let cache = Cache::new();
+        for (id, name) in &[("a", "alice"), ("b", "bob")] {
+            cache.apply(&ServerMessage::Added {
+                collection: "users".into(), id: (*id).into(), fields: Some(json!({"name": name}).into())
+            });
+        }
+        // Stub writes are not saved
+        cache.simulate("rename", "users", "a", Some(json!({"name": "alicia"}).as_object().unwrap().clone()));
+        cache.simulate("create", "users", "c", Some(Document::new()));
+
+        let saved = serde_json::to_string(&cache.snapshot()).unwrap();
+        assert_eq!(saved, r#"{"users":{"a":{"name":"alice"},"b":{"name":"bob"}}}"#);
+
+        let restored = Cache::new();
+        restored.restore(serde_json::from_str(&saved).unwrap());
+        assert_eq!(restored.get_as::<Value>("users", "b"), Some(json!({"name": "bob"})));
+        assert_eq!(restored.len("users"), 2);
+    }
+
+}
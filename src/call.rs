@@ -0,0 +1,172 @@
+use futures::channel::oneshot;
+use futures::future::{Future, FutureExt};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use crate::connection::{Handle, MethodResult};
+use crate::error::{Error, Result};
+use crate::ids::RandomStream;
+
+/// The result of a method call in flight, returned by [`Handle::start_call`].
+/// Dropping it before the result arrives cancels the call: the result will be
+/// discarded when it arrives.
+#[derive(Debug)]
+pub struct CallHandle {
+    result: Option<oneshot::Receiver<Result<MethodResult>>>,
+    handle: Handle,
+    random_seed: Option<String>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+    #[cfg(feature = "tracing")]
+    started: std::time::Instant,
+}
+
+impl CallHandle {
+
+    pub(crate) fn new(result: oneshot::Receiver<Result<MethodResult>>, handle: Handle, _method: &str,
+                      random_seed: Option<String>) -> Self {
+        Self {
+            result: Some(result),
+            handle,
+            random_seed,
+            #[cfg(feature = "tracing")]
+            span: tracing::debug_span!("ddp_method", method = _method),
+            #[cfg(feature = "tracing")]
+            started: std::time::Instant::now(),
+        }
+    }
+
+    /// The random streams of the call, if it was sent with a `randomSeed`,
+    /// to predict the ids of the documents it inserts.
+    pub fn random_stream(&self) -> Option<RandomStream> {
+        self.random_seed.as_ref().map(RandomStream::new)
+    }
+
+    /// Whether the result was already returned.
+    pub(crate) fn is_done(&self) -> bool {
+        self.result.is_none()
+    }
+
+    /// Abandon the call, and wait until the connection has forgotten about it.
+    /// The server is not told, as DDP has no way to do so.
+    pub async fn cancel(mut self) -> Result<()> {
+        if let Some(mut rx) = self.result.take() {
+            rx.close();
+            #[cfg(feature = "tracing")]
+            tracing::debug!(parent: &self.span, "method call cancelled");
+            self.handle.purge().await?;
+        }
+        Ok(())
+    }
+
+}
+
+impl Future for CallHandle {
+    type Output = Result<MethodResult>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let rx = self.result.as_mut().expect("CallHandle polled after completion");
+        match rx.poll_unpin(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(result) => {
+                self.result = None;
+                let result = result.map_err(Error::from).and_then(|r| r);
+                #[cfg(feature = "tracing")]
+                tracing::debug!(parent: &self.span,
+                                latency_ms = self.started.elapsed().as_millis() as u64,
+                                outcome = match &result {
+                                    Ok(Ok(_)) => "ok",
+                                    Ok(Err(_)) => "error",
+                                    Err(_) => "failed",
+                                },
+                                "method call completed");
+                Poll::Ready(result)
+            }
+        }
+    }
+}
+
+impl Drop for CallHandle {
+    fn drop(&mut self) {
+        if let Some(mut rx) = self.result.take() {
+            // Mark the call as abandoned before asking the worker to clean up
+            rx.close();
+            self.handle.purge_nowait();
+            #[cfg(feature = "tracing")]
+            tracing::debug!(parent: &self.span, "method call cancelled");
+        }
+    }
+}
+
+/// A method call in flight, returned by [`Handle::method`].
+/// The server reports the result of a call, and the moment its writes have
+/// been sent to our subscriptions, separately and in either order.
+#[derive(Debug)]
+pub struct MethodCall {
+    call: CallHandle,
+    outcome: Option<MethodResult>,
+    updated: Option<oneshot::Receiver<()>>,
+}
+
+impl MethodCall {
+
+    pub(crate) fn new(call: CallHandle, updated: oneshot::Receiver<()>) -> Self {
+        Self { call, outcome: None, updated: Some(updated) }
+    }
+
+    /// Wait for the result of the call.
+    pub async fn result(&mut self) -> Result<MethodResult> {
+        if !self.call.is_done() {
+            self.outcome = Some((&mut self.call).await?);
+        }
+        // If we get here without an outcome, the error was already returned
+        self.outcome.clone().ok_or(Error::Disconnected)
+    }
+
+    /// Wait until the documents modified by the call have been sent to
+    /// our subscriptions, as signaled by the `updated` message.
+    pub async fn updated(&mut self) -> Result<()> {
+        if let Some(rx) = self.updated.take() {
+            rx.await?;
+        }
+        Ok(())
+    }
+
+    /// Wait for both the result and the update. Calls that fail without
+    /// reaching the server never see the update, so the result comes first.
+    pub async fn finished(mut self) -> Result<MethodResult> {
+        let result = self.result().await?;
+        self.updated().await?;
+        Ok(result)
+    }
+
+    /// See [`CallHandle::random_stream`].
+    pub fn random_stream(&self) -> Option<RandomStream> {
+        self.call.random_stream()
+    }
+
+    /// See [`CallHandle::cancel`].
+    pub async fn cancel(self) -> Result<()> {
+        self.call.cancel().await
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::Connection;
+    use crate::error::Error;
+    use crate::testing::{block_on, MockServer};
+
+    #[test]
+    fn test_finished_refused() {
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            let mut connection = Connection::builder(&server.url()).pending_call_limit(1).connect().await.unwrap();
+            let _stuck = connection.method("sleep".to_string(), ()).await.unwrap();
+            let refused = connection.method("sleep".to_string(), ()).await.unwrap();
+            assert!(matches!(refused.finished().await, Err(Error::TooManyPendingCalls)));
+        });
+    }
+
+}
@@ -0,0 +1,198 @@
+//! A batteries-included client, for applications that don't need the
+//! low-level [`Connection`] API.
+//!
+//! A [`DdpClient`] keeps a local [`Cache`] of the subscribed documents,
+//! reconnects when the link is lost, and logs in again when the server
+//! could not resume the session. The inbound stream of messages is consumed
+//! in the background; use the cache, [`DdpClient::collection`] or the
+//! callbacks of [`Handle::on_added`] to follow documents.
+
+use futures::{StreamExt, future};
+use serde::{Serialize, de::DeserializeOwned};
+use std::sync::{Arc, Mutex};
+use crate::cache::Cache;
+use crate::collection::Collection;
+use crate::config::ConnectionBuilder;
+use crate::connection::{Connection, ConnectionStatus, Handle};
+use crate::error::{MethodError, Result};
+use crate::login::{self, LoginResult, Password, User};
+use crate::runtime;
+use crate::subscription::SubscriptionGuard;
+use log::{info, warn};
+
+/// A DDP client, see the [module documentation](self).
+#[derive(Debug)]
+pub struct DdpClient {
+    handle: Handle,
+    cache: Cache,
+    login: Arc<Mutex<Option<LoginResult>>>,
+}
+
+impl DdpClient {
+
+    /// Connect to a server, with the default settings.
+    pub async fn connect(url: &str) -> Result<Self> {
+        Self::connect_with(Connection::builder(url)).await
+    }
+
+    /// Connect with custom settings. The cache is always enabled.
+    pub async fn connect_with(builder: ConnectionBuilder) -> Result<Self> {
+        let connection = builder.cache(true).connect().await?;
+        let cache = connection.cache().expect("cache enabled").clone();
+        let (messages, handle) = connection.split();
+        let login = Arc::new(Mutex::new(None));
+
+        runtime::spawn(messages.for_each(|_| future::ready(())));
+        runtime::spawn(relogin(handle.clone(), login.clone()));
+
+        Ok(Self { handle, cache, login })
+    }
+
+    /// The underlying handle, for everything else.
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
+    /// The local copy of the subscribed documents.
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    /// Log in with a password. The login is renewed with its token after
+    /// reconnecting to a new session.
+    pub async fn login_with_password(&mut self, user: &User, password: &Password) -> std::result::Result<LoginResult, MethodError> {
+        let result = login::login_with_password(&mut self.handle, user, password).await?;
+        *self.login() = Some(result.clone());
+        Ok(result)
+    }
+
+    /// Log in with a resume token. The login is renewed after reconnecting
+    /// to a new session.
+    pub async fn login_with_token(&mut self, token: &str) -> std::result::Result<LoginResult, MethodError> {
+        let result = login::login_with_token(&mut self.handle, token).await?;
+        *self.login() = Some(result.clone());
+        Ok(result)
+    }
+
+    /// Log out, and stop renewing the login.
+    pub async fn logout(&mut self) -> std::result::Result<(), MethodError> {
+        self.login().take();
+        self.handle.call_wait("logout".to_string(), ()).await??;
+        Ok(())
+    }
+
+    /// The id of the logged in user, if any.
+    pub fn user_id(&self) -> Option<String> {
+        self.login().as_ref().map(|login| login.id.clone())
+    }
+
+    /// Call a method, with typed parameters and result.
+    pub async fn call<P, R>(&mut self, method: &str, params: P) -> std::result::Result<R, MethodError>
+        where P: Serialize, R: DeserializeOwned
+    {
+        self.handle.call_typed(method, params).await
+    }
+
+    /// Subscribe to a publication, and wait until it is ready. The
+    /// subscription is renewed after reconnecting, and stopped when
+    /// the guard is dropped.
+    pub async fn subscribe<P: Serialize>(&mut self, name: &str, params: P) -> std::result::Result<SubscriptionGuard, MethodError> {
+        let id = self.handle.subscription_id();
+        let mut subscription = self.handle.subscribe(id, name.to_string(), params).await?;
+        subscription.ready().await??;
+        Ok(subscription)
+    }
+
+    /// See [`Handle::collection`]
+    pub async fn collection<T: DeserializeOwned>(&mut self, name: &str) -> Result<Collection<T>> {
+        self.handle.collection(name).await
+    }
+
+    /// Close the connection.
+    pub async fn close(mut self) -> Result<()> {
+        self.handle.close().await
+    }
+
+    fn login(&self) -> std::sync::MutexGuard<'_, Option<LoginResult>> {
+        self.login.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+}
+
+/// Log in again with the last login token whenever the connection comes
+/// back with a new session, until the connection is closed.
+async fn relogin(mut handle: Handle, login: Arc<Mutex<Option<LoginResult>>>) {
+    let mut status = handle.status();
+    let mut session = handle.session();
+    while status.changed().await.is_ok() {
+        if *status.borrow() != ConnectionStatus::Connected || handle.session() == session {
+            continue
+        }
+        session = handle.session();
+        let token = match &*login.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(login) => login.token.clone(),
+            None => continue,
+        };
+        match login::login_with_token(&mut handle, &token).await {
+            Ok(result) => {
+                info!("Logged in again as {}", result.id);
+                *login.lock().unwrap_or_else(|e| e.into_inner()) = Some(result);
+            },
+            Err(e) => warn!("Could not log in again: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::{block_on, reconnect_every, MockServer};
+    use crate::protocol::{ClientMessage, ServerMessage};
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[test]
+    fn test_client() {
+        block_on(async {
+            let mut server = MockServer::start().await.unwrap();
+            server.on_method("login", |_| Ok(json!({"id": "u1", "token": "t0k3n"})));
+            server.on_method("add", |params| Ok(json!(params[0].as_i64().unwrap() + params[1].as_i64().unwrap())));
+            server.publish("rooms", vec![ServerMessage::Added {
+                collection: "rooms".into(), id: "r1".into(), fields: Some(json!({"name": "general"}).into())
+            }]);
+            server.refuse("secrets", json!({"error": 403}));
+            server.fresh_sessions();
+
+            let builder = Connection::builder(&server.url()).reconnect(reconnect_every(Duration::from_millis(10)));
+            let mut client = DdpClient::connect_with(builder).await.unwrap();
+            client.login_with_password(&User::username("bob"), &Password::hash("pw")).await.unwrap();
+            assert_eq!(client.user_id().as_deref(), Some("u1"));
+            assert_eq!(client.call::<_, i64>("add", (1, 2)).await.unwrap(), 3);
+
+            let _rooms = client.subscribe("rooms", ()).await.unwrap();
+            assert!(client.cache().get("rooms", "r1").is_some());
+            assert!(matches!(client.subscribe("secrets", ()).await, Err(MethodError::Rpc(_))));
+            // More messages than the inbound channel holds, drained in the background
+            for i in 0..20 {
+                assert_eq!(client.call::<_, i64>("add", (i, 1)).await.unwrap(), i + 1);
+            }
+
+            // After reconnecting to a new session, the login is renewed
+            server.disconnect();
+            let mut logins = 0;
+            while let Some(msg) = server.next_message().await {
+                if let ClientMessage::Method { method, params, .. } = msg {
+                    if method == "login" {
+                        logins += 1;
+                    }
+                    if logins == 2 {
+                        assert_eq!(params, vec![json!({"resume": "t0k3n"})]);
+                        break
+                    }
+                }
+            }
+        });
+    }
+
+}
@@ -0,0 +1,157 @@
+//! Typed views of the documents of a collection, see [`crate::Handle::collection`].
+
+use futures::{Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use crate::cache::Document;
+use crate::error::Result;
+use crate::protocol::{Fields, ServerMessage};
+use crate::queue;
+
+/// What happened to a document of a [`Collection`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change<T> {
+    /// A new document, or one sent again after a resubscription.
+    Added { id: String, doc: T },
+    /// Some fields of the document changed; `doc` is its new state.
+    Changed { id: String, doc: T },
+    Removed { id: String },
+}
+
+impl<T> Change<T> {
+
+    /// The id of the document concerned.
+    pub fn id(&self) -> &str {
+        match self {
+            Change::Added { id, .. } | Change::Changed { id, .. } | Change::Removed { id } => id,
+        }
+    }
+
+}
+
+/// The changes to the documents of a collection, deserialized as `T` from
+/// EJSON. Since `changed` messages only carry the modified fields, the
+/// documents are tracked, and every change yields the full new document.
+///
+/// A document that cannot be deserialized yields an error, and the stream
+/// goes on.
+pub struct Collection<T> {
+    name: String,
+    messages: queue::Receiver<ServerMessage>,
+    documents: HashMap<String, Document>,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> Collection<T> {
+
+    pub(crate) fn new(name: &str, messages: queue::Receiver<ServerMessage>) -> Self {
+        Self { name: name.to_string(), messages, documents: HashMap::new(), _type: PhantomData }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The current state of a document, as far as this stream has seen.
+    pub fn get(&self, id: &str) -> Option<Result<T>> {
+        self.documents.get(id).map(deserialize)
+    }
+
+    /// Track a document message, and describe the change it made.
+    fn apply(&mut self, msg: ServerMessage) -> Option<Result<Change<T>>> {
+        match msg {
+            ServerMessage::Added { id, fields, .. } |
+            ServerMessage::AddedBefore { id, fields, .. } => {
+                let doc = fields.as_ref().map(Fields::to_object).unwrap_or_default();
+                let change = deserialize(&doc).map(|doc| Change::Added { id: id.to_string(), doc });
+                self.documents.insert(id.into(), doc);
+                Some(change)
+            },
+            ServerMessage::Changed { id, fields, cleared, .. } => {
+                let doc = self.documents.entry(id.to_string()).or_default();
+                if let Some(fields) = fields {
+                    doc.extend(fields.to_object());
+                }
+                for field in cleared.iter().flatten() {
+                    doc.remove(field);
+                }
+                Some(deserialize(doc).map(|doc| Change::Changed { id: id.into(), doc }))
+            },
+            ServerMessage::Removed { id, .. } => {
+                self.documents.remove(id.as_str());
+                Some(Ok(Change::Removed { id: id.into() }))
+            },
+            _ => None,
+        }
+    }
+
+}
+
+fn deserialize<T: DeserializeOwned>(doc: &Document) -> Result<T> {
+    Ok(crate::ejson::from_value(Value::Object(doc.clone()))?)
+}
+
+impl<T: DeserializeOwned> Stream for Collection<T> {
+    type Item = Result<Change<T>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let msg = match self.messages.poll_next_unpin(cx) {
+                Poll::Ready(Some(msg)) => msg,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            if let Some(change) = self.apply(msg) {
+                return Poll::Ready(Some(change))
+            }
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for Collection<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Collection")
+            .field("name", &self.name)
+            .field("documents", &self.documents.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::Connection;
+    use crate::testing::{block_on, MockServer};
+    use serde_json::json;
+
+    #[test]
+    fn test_typed_collection() {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Room {
+            name: String,
+            topic: Option<String>,
+        }
+
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            let mut connection = Connection::connect(&server.url()).await.unwrap();
+            let mut rooms = connection.collection::<Room>("rooms").await.unwrap();
+
+            server.added("rooms", "r1", json!({"name": "general"}));
+            server.changed("rooms", "r1", json!({"topic": "rust"}), vec![]);
+            server.removed("rooms", "r1");
+
+            let room = Room { name: "general".to_string(), topic: None };
+            assert_eq!(rooms.next().await.unwrap().unwrap(), Change::Added { id: "r1".into(), doc: room });
+            let room = Room { name: "general".to_string(), topic: Some("rust".to_string()) };
+            assert_eq!(rooms.next().await.unwrap().unwrap(), Change::Changed { id: "r1".into(), doc: room });
+            assert_eq!(rooms.next().await.unwrap().unwrap(), Change::Removed { id: "r1".into() });
+        });
+    }
+
+}
@@ -0,0 +1,696 @@
+use std::{collections::HashMap, convert::TryFrom, sync::Arc, time::Duration};
+use async_tungstenite::tungstenite::http::{HeaderMap, HeaderName, HeaderValue};
+use async_tungstenite::tungstenite::handshake::client::Request;
+use tokio_rustls::rustls::ClientConfig;
+use crate::connection::{Connection, MethodResult};
+use crate::error::{Error, ErrorCode, MeteorError, Result};
+use crate::ids::IdGenerator;
+use crate::intercept::{Interceptor, Interceptors, Redactor};
+use crate::metrics::Metrics;
+use crate::proxy::Proxy;
+use crate::resolve::Resolver;
+use crate::stub::{Stub, StubContext};
+use crate::transport::Transport;
+
+/// Client-initiated keepalive. When nothing has been received from the server
+/// for `interval`, a ping is sent; if the server stays silent for another `timeout`,
+/// the link is considered dead and is re-established.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    /// The same values as the Meteor client.
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(17_500),
+            timeout: Duration::from_secs(15),
+        }
+    }
+}
+
+/// At most `requests` in each `interval`, on average. Bursts of `requests`
+/// are allowed after a quiet period.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub interval: Duration,
+}
+
+/// The DDP versions known to siderite, in the order preferred by the Meteor client.
+/// `pre1` differs in that its servers do not answer heartbeats.
+pub const DDP_VERSIONS: &[&str] = &["1", "pre2", "pre1"];
+
+/// Decides which document messages to keep, see [`ConnectionBuilder::document_filter`].
+pub type DocumentFilter = Arc<dyn Fn(&str, &str) -> bool + Send + Sync>;
+
+/// What to do when the websocket of a connection is lost.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReconnectPolicy {
+    /// Give up, and close the connection.
+    Never,
+    /// Dial again after `delay`, doubling it after each failed attempt
+    /// up to `max_delay`. With no `max_attempts`, retry forever.
+    Backoff {
+        delay: Duration,
+        max_delay: Duration,
+        max_attempts: Option<u32>,
+    },
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy::Backoff {
+            delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+/// When and how often [`crate::Handle::call_with_retry`] calls a method again.
+///
+/// Errors returned by the server with one of the `codes` are always
+/// retried, as are calls refused locally by
+/// [`ConnectionBuilder::pending_call_limit`]. Failures of the link and
+/// timeouts are only retried for `idempotent` calls, as the call may have
+/// been performed by the server nonetheless.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many times to call the method at most, including the first time.
+    pub max_attempts: u32,
+    /// The wait before the second attempt, doubled after each attempt up to
+    /// `max_delay`. The server may ask for a longer wait, see
+    /// [`crate::MeteorError::retry_after`].
+    pub delay: Duration,
+    pub max_delay: Duration,
+    /// Whether calling the method several times has the same effect as once.
+    pub idempotent: bool,
+    /// The codes of the Meteor errors to retry on.
+    pub codes: Vec<ErrorCode>,
+}
+
+impl Default for RetryPolicy {
+    /// Three attempts, starting with a 100ms delay, retrying on the errors of
+    /// Meteor's rate limiter.
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            idempotent: false,
+            codes: vec![ErrorCode::Name("too-many-requests".to_string())],
+        }
+    }
+}
+
+impl RetryPolicy {
+
+    /// How long to wait before calling again after this outcome, unless
+    /// the call should not be made again.
+    pub(crate) fn wait(&self, outcome: &Result<MethodResult>, delay: Duration) -> Option<Duration> {
+        match outcome {
+            Err(Error::TooManyPendingCalls) => Some(delay),
+            Err(e) if self.idempotent && e.is_retryable() => Some(delay),
+            Ok(Err(e)) => match MeteorError::try_from(e.clone()) {
+                Ok(e) if self.codes.contains(&e.error) => Some(e.retry_after().map_or(delay, |after| after.max(delay))),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+}
+
+/// What to do with inbound server messages when the consumer of the
+/// [`Connection`] does not keep up and the inbound channel is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait for the consumer. This stalls the connection, including heartbeats.
+    #[default]
+    Block,
+    /// Discard the oldest queued message to make room.
+    DropOldest,
+    /// Close the connection with [`Error::Overflow`].
+    Error,
+}
+
+/// The rustls settings used by default: the platform's certificate store,
+/// and no client certificate. Use it as a starting point for custom settings,
+/// e.g. calling `set_single_client_cert` for mutual TLS.
+///
+/// With the `webpki-roots` feature, the bundled roots of [`webpki_tls_config`]
+/// are used instead when the platform has no certificate store.
+pub fn default_tls_config() -> Result<ClientConfig> {
+    let mut tlsconfig = ClientConfig::new();
+    tlsconfig.root_store = match rustls_native_certs::load_native_certs() {
+        #[cfg(feature = "webpki-roots")]
+        Ok(store) if store.is_empty() => return Ok(webpki_tls_config()),
+        Ok(store) => store,
+        #[cfg(feature = "webpki-roots")]
+        Err((_store, err)) => {
+            log::warn!("No native certificate store ({}), using the bundled roots", err);
+            return Ok(webpki_tls_config())
+        },
+        #[cfg(not(feature = "webpki-roots"))]
+        Err((_store, err)) => return Err(Error::Tls(err)),
+    };
+    Ok(tlsconfig)
+}
+
+/// TLS settings trusting the Mozilla roots bundled with the `webpki-roots`
+/// crate only, for containers without a certificate store. Pass them to
+/// [`ConnectionBuilder::tls_config`] to ignore the platform's store altogether.
+#[cfg(feature = "webpki-roots")]
+pub fn webpki_tls_config() -> ClientConfig {
+    let mut tlsconfig = ClientConfig::new();
+    tlsconfig.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    tlsconfig
+}
+
+/// Settings for a [`Connection`].
+#[derive(Clone)]
+pub struct Config {
+    /// Send our own pings to detect stalled links. Disabled by default.
+    pub heartbeat: Option<Heartbeat>,
+    /// Send websocket pings at this interval, to keep proxies from closing
+    /// idle connections. Disabled by default.
+    pub keepalive: Option<Duration>,
+    /// The largest message accepted from the server, in bytes; 64 MiB by default.
+    pub max_message_size: Option<usize>,
+    /// The largest websocket frame accepted from the server, in bytes; 16 MiB by default.
+    pub max_frame_size: Option<usize>,
+    /// Timeout applied by [`crate::Handle::call`]. By default, calls wait forever.
+    pub call_timeout: Option<Duration>,
+    /// Give up on connecting, from dialing to the end of the DDP handshake,
+    /// after this long. This applies to reconnections too. Unlimited by default.
+    pub connect_timeout: Option<Duration>,
+    /// Capacity of the channel carrying server messages to the [`Connection`].
+    pub inbound_capacity: usize,
+    /// Capacity of the channel carrying requests to the connection worker.
+    pub outbound_capacity: usize,
+    /// Coalesce outgoing messages, flushing them once no more requests are
+    /// waiting, or on [`crate::Handle::flush`].
+    pub batching: bool,
+    /// Behaviour when the inbound channel is full.
+    pub overflow: OverflowPolicy,
+    /// TLS settings for `wss://` urls. By default, [`default_tls_config`] is used.
+    /// They are ignored with the `native-tls` feature.
+    pub tls: Option<Arc<ClientConfig>>,
+    /// The name sent in the TLS handshake (SNI) and checked against the
+    /// server certificate, instead of the host of the url. For servers
+    /// reached by IP address behind a load balancer.
+    pub tls_server_name: Option<String>,
+    /// Accept server certificates issued for another name. The certificate
+    /// chain is still verified. This is meant for test environments only.
+    pub danger_accept_invalid_hostnames: bool,
+    /// Accept any server certificate, without verifying it at all. This is
+    /// meant for test environments only.
+    pub danger_accept_invalid_certs: bool,
+    /// Extra HTTP headers sent with the websocket upgrade request.
+    pub headers: HeaderMap,
+    pub reconnect: ReconnectPolicy,
+    /// While reconnecting, queue up to this many method calls to send once
+    /// reconnected, and fail the others with [`Error::Disconnected`]. By
+    /// default, requests wait in the outbound channel.
+    pub offline_queue: Option<usize>,
+    /// While reconnecting, keep the method calls in flight and those made
+    /// meanwhile for up to this long, sending them once reconnected.
+    pub resume_calls: Option<Duration>,
+    /// Tunnel the connection through a proxy.
+    pub proxy: Option<Proxy>,
+    /// Resolve host names with this instead of the system resolver.
+    pub resolver: Option<Arc<dyn Resolver>>,
+    /// Talk to the SockJS endpoint of the server, whose base url is then
+    /// given instead of a websocket url.
+    pub sockjs: bool,
+    /// Maintain a [`crate::cache::Cache`] of the documents sent by the server.
+    pub cache: bool,
+    /// Share one server subscription between the subscriptions to the same
+    /// publication with the same parameters.
+    pub share_subscriptions: bool,
+    /// Client-side simulations of methods, by method name.
+    pub stubs: HashMap<String, Stub>,
+    /// Treat unknown messages and fields from the server as errors, rather
+    /// than passing on or ignoring them.
+    pub strict: bool,
+    /// Which document messages to keep, by collection and id.
+    pub document_filter: Option<DocumentFilter>,
+    /// The DDP versions we speak, most preferred first.
+    pub versions: Vec<String>,
+    /// How method and subscription ids are made. By default, method ids
+    /// are chosen by the connection, and subscription ids are random.
+    pub ids: Option<Arc<dyn IdGenerator>>,
+    /// Send a `randomSeed` with method calls, see [`crate::CallHandle::random_stream`].
+    pub random_seeds: bool,
+    /// Throttle method calls and subscriptions. Unlimited by default.
+    pub rate_limit: Option<RateLimit>,
+    /// Limits for specific methods, replacing [`Config::rate_limit`] for them.
+    pub method_rate_limits: HashMap<String, RateLimit>,
+    /// How many method calls may wait for their result at once. Unlimited by default.
+    pub max_pending_calls: Option<usize>,
+    /// How many method calls the connection tracks at once, failing the
+    /// others with [`Error::TooManyPendingCalls`]. Unlimited by default.
+    pub pending_call_limit: Option<usize>,
+    /// Hooks called on every frame, in order.
+    pub interceptors: Interceptors,
+    /// Rewrites the frames written to the trace log.
+    pub redactor: Option<Redactor>,
+    /// Where to report connection metrics.
+    pub metrics: Option<Arc<dyn Metrics>>,
+    /// Where to pass the trace context of method calls to the server, if at all.
+    #[cfg(feature = "otel")]
+    pub trace_injection: Option<crate::otel::ParamSlot>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            heartbeat: None,
+            keepalive: None,
+            max_message_size: None,
+            max_frame_size: None,
+            call_timeout: None,
+            connect_timeout: None,
+            inbound_capacity: 16,
+            outbound_capacity: 16,
+            batching: false,
+            overflow: OverflowPolicy::default(),
+            tls: None,
+            tls_server_name: None,
+            danger_accept_invalid_hostnames: false,
+            danger_accept_invalid_certs: false,
+            headers: HeaderMap::new(),
+            reconnect: ReconnectPolicy::default(),
+            offline_queue: None,
+            resume_calls: None,
+            proxy: None,
+            resolver: None,
+            sockjs: false,
+            cache: false,
+            share_subscriptions: false,
+            stubs: HashMap::new(),
+            strict: false,
+            document_filter: None,
+            versions: DDP_VERSIONS.iter().map(|v| v.to_string()).collect(),
+            ids: None,
+            random_seeds: false,
+            rate_limit: None,
+            method_rate_limits: HashMap::new(),
+            max_pending_calls: None,
+            pending_call_limit: None,
+            interceptors: Vec::new(),
+            redactor: None,
+            metrics: None,
+            #[cfg(feature = "otel")]
+            trace_injection: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut f = f.debug_struct("Config");
+        f.field("heartbeat", &self.heartbeat)
+            .field("keepalive", &self.keepalive)
+            .field("max_message_size", &self.max_message_size)
+            .field("max_frame_size", &self.max_frame_size)
+            .field("call_timeout", &self.call_timeout)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("inbound_capacity", &self.inbound_capacity)
+            .field("outbound_capacity", &self.outbound_capacity)
+            .field("batching", &self.batching)
+            .field("overflow", &self.overflow)
+            .field("tls", &self.tls.as_ref().map(|_| "<custom>"))
+            .field("tls_server_name", &self.tls_server_name)
+            .field("danger_accept_invalid_hostnames", &self.danger_accept_invalid_hostnames)
+            .field("danger_accept_invalid_certs", &self.danger_accept_invalid_certs)
+            .field("headers", &self.headers)
+            .field("reconnect", &self.reconnect)
+            .field("offline_queue", &self.offline_queue)
+            .field("resume_calls", &self.resume_calls)
+            .field("proxy", &self.proxy)
+            .field("resolver", &self.resolver.as_ref().map(|_| "<custom>"))
+            .field("sockjs", &self.sockjs)
+            .field("cache", &self.cache)
+            .field("share_subscriptions", &self.share_subscriptions)
+            .field("stubs", &self.stubs.keys().collect::<Vec<_>>())
+            .field("strict", &self.strict)
+            .field("document_filter", &self.document_filter.as_ref().map(|_| "<custom>"))
+            .field("versions", &self.versions)
+            .field("ids", &self.ids.as_ref().map(|_| "<custom>"))
+            .field("random_seeds", &self.random_seeds)
+            .field("rate_limit", &self.rate_limit)
+            .field("method_rate_limits", &self.method_rate_limits)
+            .field("max_pending_calls", &self.max_pending_calls)
+            .field("pending_call_limit", &self.pending_call_limit)
+            .field("interceptors", &self.interceptors.len())
+            .field("redactor", &self.redactor.as_ref().map(|_| "<custom>"))
+            .field("metrics", &self.metrics.as_ref().map(|_| "<custom>"));
+        #[cfg(feature = "otel")]
+        f.field("trace_injection", &self.trace_injection);
+        f.finish()
+    }
+}
+
+/// Configures a [`Connection`] before establishing it. See [`Connection::builder`].
+#[derive(Debug)]
+pub struct ConnectionBuilder {
+    url: String,
+    config: Config,
+}
+
+impl ConnectionBuilder {
+
+    pub(crate) fn new(url: &str) -> Self {
+        Self { url: url.to_string(), config: Config::default() }
+    }
+
+    /// Start from a complete websocket upgrade request. Its url and headers are
+    /// used for the initial connection as well as for reconnections.
+    pub fn from_request(request: Request) -> Self {
+        let (parts, ()) = request.into_parts();
+        let config = Config { headers: parts.headers, ..Config::default() };
+        Self { url: parts.uri.to_string(), config }
+    }
+
+    /// Enable client-initiated heartbeats.
+    pub fn heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.config.heartbeat = Some(Heartbeat { interval, timeout });
+        self
+    }
+
+    /// Send websocket pings at every `interval`, independently of DDP heartbeats.
+    /// Some proxies close websockets that carry no traffic for a while.
+    pub fn keepalive(mut self, interval: Duration) -> Self {
+        self.config.keepalive = Some(interval);
+        self
+    }
+
+    /// Close the connection with [`Error::MessageTooLarge`] when the server
+    /// sends a message larger than `bytes`, rather than buffering and
+    /// parsing it. Such connections are not re-established.
+    pub fn max_message_size(mut self, bytes: usize) -> Self {
+        self.config.max_message_size = Some(bytes);
+        self
+    }
+
+    /// Like [`ConnectionBuilder::max_message_size`], for each websocket
+    /// frame of a message.
+    pub fn max_frame_size(mut self, bytes: usize) -> Self {
+        self.config.max_frame_size = Some(bytes);
+        self
+    }
+
+    /// Default timeout for method calls.
+    pub fn call_timeout(mut self, timeout: Duration) -> Self {
+        self.config.call_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail with [`Error::ConnectTimeout`] if the connection, from dialing
+    /// to the end of the DDP handshake, is not established in time.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Capacities of the inbound (server messages) and outbound (requests) channels.
+    pub fn channel_capacity(mut self, inbound: usize, outbound: usize) -> Self {
+        self.config.inbound_capacity = inbound;
+        self.config.outbound_capacity = outbound;
+        self
+    }
+
+    /// Write the requests waiting in the outbound channel together, with a
+    /// single flush, rather than flushing each message. This saves syscalls
+    /// for bulk workloads, like thousands of method calls in a row.
+    pub fn batching(mut self, enabled: bool) -> Self {
+        self.config.batching = enabled;
+        self
+    }
+
+    /// What to do when the consumer does not keep up with inbound messages.
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.config.overflow = policy;
+        self
+    }
+
+    /// Use this TLS configuration instead of the native certificate store.
+    /// This allows custom root stores, client certificates or verifiers.
+    pub fn tls_config(mut self, tls: impl Into<Arc<ClientConfig>>) -> Self {
+        self.config.tls = Some(tls.into());
+        self
+    }
+
+    /// Send this name in the TLS handshake (SNI) and check the server
+    /// certificate against it, instead of the host of the url.
+    pub fn tls_server_name(mut self, name: &str) -> Self {
+        self.config.tls_server_name = Some(name.to_string());
+        self
+    }
+
+    /// Accept server certificates that are not valid for the name of the
+    /// server, while still checking that they are issued by a trusted
+    /// authority. **This is dangerous**: anyone with a certificate for any
+    /// name can impersonate the server. Only use it in test environments.
+    pub fn danger_accept_invalid_hostnames(mut self, enabled: bool) -> Self {
+        self.config.danger_accept_invalid_hostnames = enabled;
+        self
+    }
+
+    /// Accept any server certificate: self-signed, expired, or issued for
+    /// another name. **This is dangerous**: the connection is encrypted, but
+    /// anyone on the way can impersonate the server. Only use it against
+    /// staging servers with self-signed certificates.
+    pub fn danger_accept_invalid_certs(mut self, enabled: bool) -> Self {
+        self.config.danger_accept_invalid_certs = enabled;
+        self
+    }
+
+    /// Add a header to the websocket upgrade request.
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.config.headers.append(name, value);
+        self
+    }
+
+    /// Add several headers to the websocket upgrade request, replacing
+    /// previous values of the same names.
+    pub fn headers(mut self, headers: HeaderMap) -> Self {
+        self.config.headers.extend(headers);
+        self
+    }
+
+    pub fn reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.config.reconnect = policy;
+        self
+    }
+
+    /// Keep taking method calls while reconnecting, queueing up to `limit` of
+    /// them until the connection is back. Calls beyond that fail right away
+    /// with [`Error::Disconnected`] rather than waiting.
+    pub fn offline_queue(mut self, limit: usize) -> Self {
+        self.config.offline_queue = Some(limit);
+        self
+    }
+
+    /// Park method calls while reconnecting, instead of failing those in
+    /// flight when the link is lost: they are sent again on the new link,
+    /// and calls made meanwhile are sent after them. The calls still parked
+    /// `deadline` after the link was lost fail with [`Error::Disconnected`],
+    /// as do the calls made after that until the connection is back.
+    ///
+    /// A call sent again may run twice on the server, if the link was lost
+    /// after the server got it but before its result came back.
+    pub fn resume_calls(mut self, deadline: Duration) -> Self {
+        self.config.resume_calls = Some(deadline);
+        self
+    }
+
+    /// Connect through an HTTP or SOCKS5 proxy.
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.config.proxy = Some(proxy);
+        self
+    }
+
+    /// Resolve host names with this instead of the system resolver, e.g. a
+    /// [`crate::resolve::StaticResolver`]. Not used with a proxy.
+    pub fn resolver(mut self, resolver: impl Resolver + 'static) -> Self {
+        self.config.resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Connect to the SockJS endpoint of a Meteor server, for deployments that
+    /// do not expose the raw websocket one. The url is then the server's base
+    /// url, such as `wss://example.com/`.
+    pub fn sockjs(mut self, enabled: bool) -> Self {
+        self.config.sockjs = enabled;
+        self
+    }
+
+    /// Keep a local copy of the documents published by the server,
+    /// available from [`crate::Handle::cache`].
+    pub fn cache(mut self, enabled: bool) -> Self {
+        self.config.cache = enabled;
+        self
+    }
+
+    /// Subscribe only once to a publication with given parameters, as the
+    /// Meteor client does: further subscriptions to it share the server
+    /// subscription, which is stopped when the last of them is. Their ids
+    /// are then only known locally.
+    pub fn share_subscriptions(mut self, enabled: bool) -> Self {
+        self.config.share_subscriptions = enabled;
+        self
+    }
+
+    /// Simulate calls to `method` on the client, so that their effects
+    /// show in the cache without waiting for the server, see [`crate::stub`].
+    /// This enables the cache.
+    pub fn stub<F>(mut self, method: &str, stub: F) -> Self
+        where F: Fn(&mut StubContext<'_>, &[serde_json::Value]) + Send + Sync + 'static
+    {
+        self.config.cache = true;
+        self.config.stubs.insert(method.to_string(), Arc::new(stub));
+        self
+    }
+
+    /// Fail on unknown messages and fields from the server, as when testing a
+    /// server implementation. By default, parsing is lenient: unknown fields are
+    /// ignored, and unknown messages passed on as [`crate::ServerMessage::Unknown`].
+    pub fn strict(mut self, enabled: bool) -> Self {
+        self.config.strict = enabled;
+        self
+    }
+
+    /// Drop the document messages for which `filter(collection, id)` is
+    /// false, as soon as they are received: their fields are never decoded,
+    /// unless in [`ConnectionBuilder::strict`] mode, and they reach neither
+    /// the cache nor the inbound stream.
+    pub fn document_filter<F>(mut self, filter: F) -> Self
+        where F: Fn(&str, &str) -> bool + Send + Sync + 'static
+    {
+        self.config.document_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// The DDP versions to offer, most preferred first. Legacy servers may
+    /// need `pre2` or `pre1`; by default, all of them are supported.
+    pub fn versions<V: Into<String>>(mut self, versions: impl IntoIterator<Item = V>) -> Self {
+        self.config.versions = versions.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Make method and subscription ids with a custom generator, for instance
+    /// [`crate::ids::PrefixedIds`].
+    pub fn ids(mut self, ids: impl IdGenerator + 'static) -> Self {
+        self.config.ids = Some(Arc::new(ids));
+        self
+    }
+
+    /// Send a random seed with every method call, so that the ids of the
+    /// documents it inserts can be predicted, as with Meteor's method stubs.
+    pub fn random_seeds(mut self, enabled: bool) -> Self {
+        self.config.random_seeds = enabled;
+        self
+    }
+
+    /// Send at most `requests` method calls and subscriptions per `interval`,
+    /// to stay clear of the server's `DDPRateLimiter`. Requests over the limit
+    /// wait for their turn.
+    pub fn rate_limit(mut self, requests: u32, interval: Duration) -> Self {
+        self.config.rate_limit = Some(RateLimit { requests, interval });
+        self
+    }
+
+    /// A separate limit for calls to `method`, which then do not count
+    /// against the one set by [`ConnectionBuilder::rate_limit`].
+    pub fn method_rate_limit(mut self, method: &str, requests: u32, interval: Duration) -> Self {
+        self.config.method_rate_limits.insert(method.to_string(), RateLimit { requests, interval });
+        self
+    }
+
+    /// Allow at most `limit` method calls in flight; further calls wait for
+    /// one of them to complete, or to be given up by its caller.
+    pub fn max_pending_calls(mut self, limit: usize) -> Self {
+        self.config.max_pending_calls = Some(limit);
+        self
+    }
+
+    /// Track at most `limit` method calls waiting for their result, so that
+    /// calls the server never answers cannot pile up. Calls whose caller has
+    /// given up are forgotten first; beyond that, new calls fail right away
+    /// with [`Error::TooManyPendingCalls`]. Unlike
+    /// [`ConnectionBuilder::max_pending_calls`], callers never wait.
+    pub fn pending_call_limit(mut self, limit: usize) -> Self {
+        self.config.pending_call_limit = Some(limit);
+        self
+    }
+
+    /// Add a hook called on every inbound and outbound frame.
+    pub fn interceptor(mut self, interceptor: impl Interceptor + 'static) -> Self {
+        self.config.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    /// Rewrite the frames written to the trace log, which are otherwise
+    /// logged in full, passwords and login tokens included. See
+    /// [`crate::intercept::mask_fields`] for a ready-made redactor.
+    pub fn redact<F>(mut self, redactor: F) -> Self
+        where F: Fn(&str) -> String + Send + Sync + 'static
+    {
+        self.config.redactor = Some(Arc::new(redactor));
+        self
+    }
+
+    /// Report connection metrics.
+    pub fn metrics(mut self, metrics: impl Metrics + 'static) -> Self {
+        self.config.metrics = Some(Arc::new(metrics));
+        self
+    }
+
+    /// Pass the trace context of method calls to the server in their parameters.
+    #[cfg(feature = "otel")]
+    pub fn trace_injection(mut self, slot: crate::otel::ParamSlot) -> Self {
+        self.config.trace_injection = Some(slot);
+        self
+    }
+
+    /// Replace all the settings at once, keeping the headers if the new
+    /// settings have none.
+    pub fn config(mut self, config: Config) -> Self {
+        let headers = std::mem::take(&mut self.config.headers);
+        self.config = config;
+        if self.config.headers.is_empty() {
+            self.config.headers = headers;
+        }
+        self
+    }
+
+    pub async fn connect(self) -> Result<Connection> {
+        Connection::connect_with_config(&self.url, self.config).await
+    }
+
+    /// See [`Connection::connect_lazy`].
+    pub fn connect_lazy(self) -> Connection {
+        Connection::connect_lazy_with_config(&self.url, self.config)
+    }
+
+    /// Connect over a stream established by the caller, doing the websocket
+    /// handshake with the builder's url and headers.
+    /// See [`Connection::connect_over`].
+    pub async fn connect_over<S>(self, stream: S) -> Result<Connection>
+        where S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin + 'static
+    {
+        Connection::connect_over_with_config(stream, self.url.as_str(), self.config).await
+    }
+
+    /// Connect over any [`Transport`]; the builder's url is not used.
+    /// See [`Connection::connect_with_transport`].
+    pub async fn connect_with_transport(self, transport: impl Transport) -> Result<Connection> {
+        Connection::connect_with_transport_and_config(transport, self.config).await
+    }
+
+}
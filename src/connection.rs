@@ -1,15 +1,42 @@
-use anyhow::{Error, Result, anyhow};
+use serde::{Serialize, de::DeserializeOwned};
 use serde_json::{self, Value};
-use futures::{Stream, channel::{mpsc, oneshot}, future::ready, select, sink::SinkExt, stream::StreamExt};
-use std::sync::Arc;
-use async_tungstenite::tungstenite;
+use futures::{AsyncRead, AsyncWrite, Sink, Stream, channel::{mpsc, oneshot}, pin_mut, select, sink::SinkExt};
+use futures::future::{Fuse, Future, FutureExt};
+use futures::stream::StreamExt;
+use async_tungstenite::WebSocketStream;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, watch};
+use std::{collections::{HashMap, VecDeque}, pin::Pin, sync::{Arc, Mutex}, task::{Context, Poll}};
+use async_tungstenite::tungstenite::{self, client::{IntoClientRequest, uri_mode}, error::UrlError, http::{HeaderMap, StatusCode}, protocol::WebSocketConfig, stream::Mode};
+use async_tungstenite::tungstenite::handshake::client::{Request as HttpRequest, Response as HttpResponse};
+#[cfg(not(feature = "native-tls"))]
+use tokio_rustls::{rustls, webpki};
+use crate::cache::{Cache, Document};
+use crate::call::{CallHandle, MethodCall};
+use crate::collection::Collection;
+use crate::config::{Config, ConnectionBuilder, DDP_VERSIONS, DocumentFilter, ReconnectPolicy, RetryPolicy};
+#[cfg(not(feature = "native-tls"))]
+use crate::config::default_tls_config;
+use crate::error::{Error, MethodError, Result};
+use crate::events::Handlers;
+use crate::intercept::{self, Interceptors, Redactor};
+use crate::metrics::Metrics;
+use crate::queue;
+use crate::runtime::{self, Instant};
+use crate::sockjs;
+use crate::stub::StubContext;
+use crate::transport::{self, CloseReason, Transport};
+use crate::ids::{Ids, RandomStream, random_id};
 use crate::randomslab::Slab;
+use crate::ratelimit::RateLimiter;
+use crate::resolve;
+use crate::subscription::{Subscription, SubscriptionGuard, SubscriptionResult};
 use crate::protocol::{ClientMessage, ServerMessage, MethodResponse};
-use log::{debug, trace, error};
+use log::{debug, trace, info, warn, error};
 
 /// RPC method calls may fail with a JSON error. If it is the case, 
 /// we wrap them in this.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct RPCError(pub Value);
 
 impl std::fmt::Display for RPCError {
@@ -21,6 +48,17 @@ impl std::fmt::Display for RPCError {
 impl std::error::Error for RPCError {}
 
 
+/// Serialize method or subscription parameters. A value serializing to
+/// an array gives one parameter per element, `()` gives no parameters,
+/// and anything else is a single parameter.
+pub(crate) fn to_params<P: Serialize>(params: P) -> Result<Vec<Value>> {
+    match serde_json::to_value(params)? {
+        Value::Array(params) => Ok(params),
+        Value::Null => Ok(vec![]),
+        param => Ok(vec![param]),
+    }
+}
+
 /// DDP RPC calls return either a value or an error. Both can
 /// be arbitrary JSON values.
 pub type MethodResult = std::result::Result<Value,RPCError>;
@@ -39,222 +77,2734 @@ enum Request {
     Method {
         name: String,
         params: Vec<Value>,
-        result: oneshot::Sender<MethodResult>,
+        result: oneshot::Sender<Result<MethodResult>>,
+        /// Signaled when the call's `updated` message arrives.
+        updated: Option<oneshot::Sender<()>>,
+        random_seed: Option<String>,
+        /// Held until the call completes, with a concurrency cap.
+        permit: Option<OwnedSemaphorePermit>,
+        /// Send only once the calls before are done, and hold the calls after
+        /// until this one is done.
+        wait: bool,
+        /// The trace context of the caller.
+        #[cfg(feature = "otel")]
+        context: opentelemetry::Context,
     },
     Subscribe {
         name: String,
         id: String,
         params: Vec<Value>,
+        ready: oneshot::Sender<SubscriptionResult>,
     },
     Unsubscribe {
         id: String,
-    }
+    },
+    /// Start a subscription, then stop another, in one go.
+    Resubscribe {
+        old: String,
+        name: String,
+        id: String,
+        params: Vec<Value>,
+        ready: oneshot::Sender<SubscriptionResult>,
+    },
+    /// Deliver the document messages of a collection to a dedicated stream.
+    Route {
+        collection: String,
+        stream: oneshot::Sender<queue::Receiver<ServerMessage>>,
+    },
+    /// Deliver a copy of the messages passed down to another stream.
+    Tap {
+        stream: oneshot::Sender<queue::Receiver<ServerMessage>>,
+    },
+    /// Send a frame as-is.
+    Raw {
+        payload: String,
+    },
+    /// Forget the calls whose caller has given up waiting.
+    Purge,
+    /// Fail all the calls in flight or queued, giving their number.
+    AbortPending {
+        reply: oneshot::Sender<usize>,
+    },
+    /// Describe the calls in flight.
+    PendingCalls {
+        reply: oneshot::Sender<Vec<PendingCallInfo>>,
+    },
+    /// Describe the active subscriptions.
+    Subscriptions {
+        reply: oneshot::Sender<Vec<SubscriptionInfo>>,
+    },
+    /// Write out the messages buffered in batching mode.
+    Flush {
+        done: oneshot::Sender<()>,
+    },
+    /// Close the websocket and terminate the worker.
+    Close,
 
 }
 
+/// The state of the link to the server, as reported by [`Connection::status`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    /// The initial handshake is in progress.
+    Connecting,
+    /// The DDP session is established.
+    Connected,
+    /// The websocket was lost, and is being re-established.
+    Reconnecting,
+    /// The connection is permanently closed.
+    Closed,
+}
+
+/// What a connection was established with, as reported by [`Connection::info`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectInfo {
+    /// The negotiated DDP version.
+    pub version: String,
+    /// The DDP session id given by the server.
+    pub session: String,
+    /// The status of the HTTP response upgrading to a websocket, unless
+    /// the connection was made over an existing transport.
+    pub http_status: Option<StatusCode>,
+    /// The headers of that response, such as `Set-Cookie`.
+    pub http_headers: Option<HeaderMap>,
+}
+
+/// A method call waiting for its result, as reported by [`Handle::pending_calls`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PendingCallInfo {
+    pub id: String,
+    pub method: String,
+    /// How long ago the call was sent.
+    pub age: Duration,
+}
+
+/// An active subscription, as reported by [`Handle::subscriptions`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubscriptionInfo {
+    pub id: String,
+    pub name: String,
+    /// How long ago the subscription was started.
+    pub age: Duration,
+    /// Whether the server has sent its `ready` message.
+    pub ready: bool,
+    /// How many [`Subscription`]s share it, see
+    /// [`ConnectionBuilder::share_subscriptions`].
+    pub users: usize,
+}
+
 /// A handle to an active DDP connection. 
 #[derive(Debug)]
 pub struct Connection {
-    stream: mpsc::Receiver<ServerMessage>,
+    stream: queue::Receiver<ServerMessage>,
     handle: Handle,
+    info: ConnectInfo,
+    worker: Option<WorkerHandle>,
+    error: ErrorSlot,
+}
+
+/// The prefix of the ids of calls made with [`Handle::call_no_result`],
+/// whose results are ignored.
+const NO_RESULT_ID: &str = "noresult-";
+
+/// Where the worker leaves the error that ended the connection.
+type ErrorSlot = Arc<Mutex<Option<Error>>>;
+
+fn take_error(slot: &ErrorSlot) -> Option<Error> {
+    slot.lock().unwrap_or_else(|e| e.into_inner()).take()
+}
+
+/// The task running a connection in the background, as returned by
+/// [`Connection::take_worker`]. It resolves when the worker terminates, with
+/// the error that ended the connection, if any, or [`Error::Closed`] if it
+/// was aborted. Dropping it lets the worker run on its own.
+///
+/// The error is only given once, either here or by [`Connection::take_error`].
+#[derive(Debug)]
+pub struct WorkerHandle {
+    task: runtime::Task<()>,
+    error: ErrorSlot,
+}
+
+impl WorkerHandle {
+
+    /// Stop the worker right away. Pending calls and subscriptions fail,
+    /// and the inbound stream ends. Unlike [`Handle::close`], nothing is
+    /// said to the server.
+    pub fn abort(&self) {
+        self.task.abort()
+    }
+
+}
+
+impl Future for WorkerHandle {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.task.poll_unpin(cx).map(|res| match res {
+            Some(()) => take_error(&self.error).map_or(Ok(()), Err),
+            None => Err(Error::Closed),
+        })
+    }
+}
+
+/// The inbound half of a [`Connection`], as returned by [`Connection::split`].
+#[derive(Debug)]
+pub struct Messages {
+    stream: queue::Receiver<ServerMessage>,
+    error: ErrorSlot,
+}
+
+impl Messages {
+
+    /// See [`Connection::take_error`]
+    pub fn take_error(&mut self) -> Option<Error> {
+        take_error(&self.error)
+    }
+
+}
+
+impl Stream for Messages {
+    type Item = ServerMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ServerMessage>> {
+        self.stream.poll_next_unpin(cx)
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Handle {
     rpc: mpsc::Sender<Request>,
+    session: watch::Receiver<Option<String>>,
+    close_reason: watch::Receiver<Option<CloseReason>>,
+    status: watch::Receiver<ConnectionStatus>,
+    call_timeout: Option<Duration>,
+    cache: Option<Cache>,
+    ids: Ids,
+    random_seeds: bool,
+    limiter: Option<Arc<RateLimiter>>,
+    max_pending: Option<Arc<Semaphore>>,
+    handlers: Arc<Mutex<Handlers>>,
 }
 
+#[cfg(not(feature = "native-tls"))]
+type TlsConnector = tokio_rustls::TlsConnector;
+#[cfg(not(feature = "native-tls"))]
+type TlsStream = tokio_rustls::client::TlsStream<runtime::TcpStream>;
+#[cfg(feature = "native-tls")]
+type TlsConnector = tokio_native_tls::TlsConnector;
+#[cfg(feature = "native-tls")]
+type TlsStream = tokio_native_tls::TlsStream<runtime::TcpStream>;
+
 // this is cursed
-type WSStream = async_tungstenite::WebSocketStream<
-    async_tungstenite::stream::Stream<
-        async_tungstenite::tokio::TokioAdapter<tokio::net::TcpStream>,
-        async_tungstenite::tokio::TokioAdapter<
-            tokio_rustls::client::TlsStream<
-                tokio::net::TcpStream
-            >
-        >
-    >>;
+type WSStreamInner = async_tungstenite::stream::Stream<
+        async_tungstenite::tokio::TokioAdapter<runtime::TcpStream>,
+        async_tungstenite::tokio::TokioAdapter<TlsStream>
+    >;
+type WSStream = async_tungstenite::WebSocketStream<WSStreamInner>;
 
+/// Unless the config provides its own TLS settings, the native certificate store is used.
+#[cfg(not(feature = "native-tls"))]
+fn tls_connector(config: &Config) -> Result<TlsConnector> {
 
-impl Connection {
+    let mut tlsconfig = match &config.tls {
+        Some(tlsconfig) => tlsconfig.clone(),
+        // No need for trusted roots when nothing is verified
+        None if config.danger_accept_invalid_certs => Arc::new(rustls::ClientConfig::new()),
+        None => Arc::new(default_tls_config()?),
+    };
 
-    /// Create a new connection to the given websocket endpoint.
-    /// the url parameter is passed as-is to [`async_tungstenite::tokio`]
-    pub async fn connect(url: &str) -> Result<Self> {
+    if config.danger_accept_invalid_certs {
+        warn!("Accepting invalid TLS certificates");
+        Arc::make_mut(&mut tlsconfig).dangerous()
+            .set_certificate_verifier(Arc::new(AnyCertificate));
+    } else if config.danger_accept_invalid_hostnames {
+        Arc::make_mut(&mut tlsconfig).dangerous()
+            .set_certificate_verifier(Arc::new(AnyHostname(rustls::WebPKIVerifier::new())));
+    }
 
-        let tlsconfig = {
-            let mut tlsconfig = tokio_rustls::rustls::ClientConfig::new();
-            tlsconfig.root_store = rustls_native_certs::load_native_certs()
-                .map_err(|(_store, err)| err)?;
-            Arc::new(tlsconfig)
-        };
+    Ok(TlsConnector::from(tlsconfig))
+}
+
+/// The platform's TLS stack, with its certificate store. The rustls settings
+/// of the config do not apply.
+#[cfg(feature = "native-tls")]
+fn tls_connector(config: &Config) -> Result<TlsConnector> {
+    let connector = tokio_native_tls::native_tls::TlsConnector::builder()
+        .danger_accept_invalid_hostnames(config.danger_accept_invalid_hostnames || config.danger_accept_invalid_certs)
+        .danger_accept_invalid_certs(config.danger_accept_invalid_certs)
+        .build()
+        .map_err(|e| Error::Tls(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
+    if config.danger_accept_invalid_certs {
+        warn!("Accepting invalid TLS certificates");
+    }
+    Ok(TlsConnector::from(connector))
+}
+
+/// Set up TLS over `tcp`, for a server certificate issued to `name`.
+#[cfg(not(feature = "native-tls"))]
+async fn tls_handshake(tls: TlsConnector, name: &str, tcp: runtime::TcpStream) -> Result<TlsStream> {
+    let name = webpki::DNSNameRef::try_from_ascii_str(name)
+        .map_err(|_| Error::Tls(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid TLS server name")))?;
+    Ok(tls.connect(name, tcp).await.map_err(tungstenite::Error::Io)?)
+}
 
-        let tls = tokio_rustls::TlsConnector::from(tlsconfig);
+/// Set up TLS over `tcp`, for a server certificate issued to `name`.
+#[cfg(feature = "native-tls")]
+async fn tls_handshake(tls: TlsConnector, name: &str, tcp: runtime::TcpStream) -> Result<TlsStream> {
+    tls.connect(name, tcp).await
+        .map_err(|e| Error::Tls(std::io::Error::new(std::io::ErrorKind::Other, e)))
+}
 
-        let (stream, response) =
-            async_tungstenite::tokio::connect_async_with_tls_connector(url, Some(tls)).await?;
+/// Verifies certificates like the default verifier, except for the name they were issued for.
+#[cfg(not(feature = "native-tls"))]
+struct AnyHostname(rustls::WebPKIVerifier);
 
-        debug!(target: "websocket", "Got HTTP response: {:?}", response);
+#[cfg(not(feature = "native-tls"))]
+impl rustls::ServerCertVerifier for AnyHostname {
+    fn verify_server_cert(&self, roots: &rustls::RootCertStore, presented_certs: &[rustls::Certificate],
+                          dns_name: webpki::DNSNameRef<'_>, ocsp_response: &[u8])
+        -> std::result::Result<rustls::ServerCertVerified, rustls::TLSError>
+    {
+        match self.0.verify_server_cert(roots, presented_certs, dns_name, ocsp_response) {
+            Err(rustls::TLSError::WebPKIError(webpki::Error::CertNotValidForName)) => Ok(rustls::ServerCertVerified::assertion()),
+            other => other,
+        }
+    }
+}
 
+/// Accepts all certificates.
+#[cfg(not(feature = "native-tls"))]
+struct AnyCertificate;
 
-        Self::connect_with_websocket(stream).await
+#[cfg(not(feature = "native-tls"))]
+impl rustls::ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(&self, _roots: &rustls::RootCertStore, _presented_certs: &[rustls::Certificate],
+                          _dns_name: webpki::DNSNameRef<'_>, _ocsp_response: &[u8])
+        -> std::result::Result<rustls::ServerCertVerified, rustls::TLSError>
+    {
+        Ok(rustls::ServerCertVerified::assertion())
     }
+}
 
-    /// Create a new connection from an existing tungstenite websocket stream.
-    pub async fn connect_with_websocket(stream: WSStream) -> Result<Self> {
-        
+/// Open a websocket to the given url. TLS is only set up for `wss://` urls.
+/// For SockJS, the url is the base url of the server.
+async fn dial(url: &str, config: &Config) -> Result<(WSStream, HttpResponse)> {
+
+    let mut request = match config.sockjs {
+        true => sockjs::websocket_url(url).into_client_request()?,
+        false => url.into_client_request()?,
+    };
+    request.headers_mut().extend(config.headers.clone());
+
+    let mode = uri_mode(request.uri())?;
+    let tls = match mode {
+        Mode::Plain => None,
+        Mode::Tls => Some(tls_connector(config)?),
+    };
+
+    let ws_config = Some(websocket_config(config));
+    let host = request.uri().host()
+        .ok_or(tungstenite::Error::Url(UrlError::NoHostName))?
+        .trim_start_matches('[').trim_end_matches(']')
+        .to_string();
+    let port = request.uri().port_u16().unwrap_or(match mode {
+        Mode::Plain => 80,
+        Mode::Tls => 443,
+    });
+    let tcp = match (&config.proxy, &config.resolver) {
+        (Some(proxy), _) => proxy.connect(&host, port).await.map_err(Error::Proxy)?,
+        (None, Some(resolver)) => resolve::connect(&**resolver, &host, port).await.map_err(tungstenite::Error::Io)?,
+        (None, None) => runtime::connect((host.as_str(), port)).await.map_err(tungstenite::Error::Io)?,
+    };
+    let (stream, response) = match (tls, &config.tls_server_name) {
+        (Some(tls), Some(name)) => {
+            let stream = tls_handshake(tls, name, tcp).await?;
+            let stream = async_tungstenite::stream::Stream::Tls(async_tungstenite::tokio::TokioAdapter::new(stream));
+            async_tungstenite::client_async_with_config(request, stream, ws_config).await?
+        },
+        (tls, _) => async_tungstenite::tokio::client_async_tls_with_connector_and_config(request, tcp, tls, ws_config).await?,
+    };
+
+    debug!(target: "websocket", "Got HTTP response: {:?}", response);
+
+    Ok((stream, response))
+}
+
+
+/// The websocket settings, with our size limits.
+fn websocket_config(config: &Config) -> WebSocketConfig {
+    let default = WebSocketConfig::default();
+    WebSocketConfig {
+        max_message_size: config.max_message_size.or(default.max_message_size),
+        max_frame_size: config.max_frame_size.or(default.max_frame_size),
+        ..default
+    }
+}
+
+/// Dial again after losing a link, asking the server to resume `session`.
+/// Returns the new link, and the session it got.
+async fn relink(url: String, config: Config, version: String, session: Option<String>) -> Result<(Link, String)> {
+    with_connect_timeout(&config, async {
+        let (stream, _) = dial(&url, &config).await?;
+        let mut link = Link::new(websocket(stream, &config), &config);
+        let session = link.handshake(session, &version, &config.versions).await?.session;
+        Ok((link, session))
+    }).await
+}
+
+/// Give up on establishing a link after the configured connect timeout.
+async fn with_connect_timeout<T>(config: &Config, connect: impl Future<Output = Result<T>>) -> Result<T> {
+    match config.connect_timeout {
+        Some(timeout) => runtime::timeout(timeout, connect).await.unwrap_or(Err(Error::ConnectTimeout)),
+        None => connect.await,
+    }
+}
+
+/// The transport over a websocket, with our keepalive.
+fn websocket<S>(stream: WebSocketStream<S>, config: &Config) -> transport::WebSocket<S>
+    where S: AsyncRead + AsyncWrite + Send + Unpin + 'static
+{
+    match config.keepalive {
+        Some(interval) => transport::websocket(stream).keepalive(interval),
+        None => transport::websocket(stream),
+    }
+}
+
+/// The version to propose first in a `connect` message.
+fn preferred_version(config: &Config) -> String {
+    config.versions.first().cloned().unwrap_or_else(|| DDP_VERSIONS[0].to_string())
+}
+
+/// A single transport connection, carrying DDP messages.
+struct Link {
+    transport: Box<dyn Transport>,
+    interceptors: Interceptors,
+    redactor: Option<Redactor>,
+    metrics: Option<Arc<dyn Metrics>>,
+    /// Whether frames are wrapped in SockJS framing.
+    sockjs: bool,
+    /// Whether unknown messages and fields are errors.
+    strict: bool,
+    document_filter: Option<DocumentFilter>,
+    /// Whether requests from handles are written without flushing.
+    batching: bool,
+    /// Messages decoded and not yet handled.
+    backlog: VecDeque<ServerMessage>,
+}
+
+impl Link {
+
+    fn new(transport: impl Transport, config: &Config) -> Self {
+        Self {
+            transport: Box::new(transport),
+            interceptors: config.interceptors.clone(),
+            redactor: config.redactor.clone(),
+            metrics: config.metrics.clone(),
+            sockjs: config.sockjs,
+            strict: config.strict,
+            document_filter: config.document_filter.clone(),
+            batching: config.batching,
+            backlog: VecDeque::new(),
+        }
+    }
 
-        let (ws_up, mut ws_down) = stream.split();
+    /// A frame as it should appear in the logs. Only called when tracing.
+    fn loggable<'a>(&self, frame: &'a str) -> std::borrow::Cow<'a, str> {
+        match &self.redactor {
+            Some(redact) => redact(frame).into(),
+            None => frame.into(),
+        }
+    }
+
+    async fn send(&mut self, msg: ClientMessage) -> Result<()> {
+        self.send_text(serde_json::to_string(&msg)?).await
+    }
+
+    async fn send_text(&mut self, payload: String) -> Result<()> {
+        self.write(payload).await?;
+        self.flush().await
+    }
+
+    /// Send a message on behalf of a handle. In batching mode the message
+    /// is only buffered, and goes out with the next flush.
+    async fn submit(&mut self, msg: ClientMessage) -> Result<()> {
+        self.submit_text(serde_json::to_string(&msg)?).await
+    }
 
-        let mut ws_up = ws_up.with(|m: ClientMessage| {
-            let payload = serde_json::to_string(&m).unwrap();
-            trace!("=> {}", payload);
-            ready(Ok::<_,tungstenite::Error>(tungstenite::Message::Text(payload)))
-        } );
+    async fn submit_text(&mut self, payload: String) -> Result<()> {
+        self.write(payload).await?;
+        if !self.batching {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.transport.flush().await
+    }
 
-        let connect_msg = ClientMessage::Connect { version: "1".to_string(),
-                                                     support: vec!["1".to_string()],
-                                                     session: None };
+    /// Buffer a frame in the transport, without flushing it.
+    async fn write(&mut self, mut payload: String) -> Result<()> {
+        if !intercept::run(&self.interceptors, &mut payload, |i, f| i.outbound(f)) {
+            trace!("=> (dropped) {}", self.loggable(&payload));
+            return Ok(())
+        }
+        trace!("=> {}", self.loggable(&payload));
+        if self.sockjs {
+            payload = sockjs::encode(&payload);
+        }
+        self.transport.feed(payload).await?;
+        if let Some(metrics) = &self.metrics {
+            metrics.message_sent();
+        }
+        Ok(())
+    }
 
-        ws_up.send(connect_msg).await?;
+    /// Decode a frame from the server into the backlog. Frames dropped by an
+    /// interceptor, and the server's greeting, are skipped.
+    fn decode(&mut self, frame: Option<Result<String>>) -> Result<()> {
+        let txt = frame.unwrap_or_else(|| Err(tungstenite::Error::ConnectionClosed.into()))?;
+        if let Some(metrics) = &self.metrics {
+            metrics.message_received();
+        }
 
-        //TODO actually check these
-        let _server_version = ws_down.next().await.ok_or(anyhow!("no server version"))?;
-        let _connected = ws_down.next().await.ok_or(anyhow!("no connected msg"))?;
+        let payloads = match self.sockjs {
+            false => vec![txt],
+            true => match sockjs::decode(&txt)? {
+                sockjs::Frame::Messages(payloads) => payloads,
+                sockjs::Frame::Close { code, reason } => {
+                    warn!("SockJS session closed: {} {}", code, reason);
+                    return Err(tungstenite::Error::ConnectionClosed.into())
+                },
+                _ => vec![],
+            },
+        };
 
-        let mut ws_down = ws_down.map(|m| {
-            match m {
-                Ok(tungstenite::Message::Text(txt)) => {
-                    trace!("<= {}", txt);
-                    serde_json::from_str::<ServerMessage>(&txt)
-                    .map_err(Error::from)
+        for mut payload in payloads {
+            if !intercept::run(&self.interceptors, &mut payload, |i, f| i.inbound(f)) {
+                trace!("<= (dropped) {}", self.loggable(&payload));
+                continue
+            }
+            trace!("<= {}", self.loggable(&payload));
+            let filter = self.document_filter.as_deref();
+            let keep = |collection: &str, id: &str| match filter {
+                Some(filter) => filter(collection, id),
+                None => true,
+            };
+            let msg = match self.strict {
+                true => match serde_json::from_str::<Value>(&payload)? {
+                    value if value.get("msg").is_none() => None,
+                    value => Some(ServerMessage::from_value_strict(value)?).filter(|msg| match msg {
+                        ServerMessage::Added { collection, id, .. } |
+                        ServerMessage::Changed { collection, id, .. } |
+                        ServerMessage::Removed { collection, id } |
+                        ServerMessage::AddedBefore { collection, id, .. } => keep(collection, id),
+                        _ => true,
+                    }),
                 },
-                other => Err(anyhow!("unhandled down message: {:?}", other))
+                false => ServerMessage::from_frame_filtered(&payload, keep)?,
+            };
+            // None for frames that are not DDP, such as the `server_id`
+            // greeting of Meteor servers, and for filtered documents
+            if let Some(msg) = msg {
+                self.backlog.push_back(msg)
             }
-        }).fuse();
+        }
+        Ok(())
+    }
 
-        let (mut down_tx, down_rx) = mpsc::channel::<ServerMessage>(16);
-        let (up_tx, mut up_rx) = mpsc::channel::<Request>(16);
+    async fn recv(&mut self) -> Result<ServerMessage> {
+        loop {
+            if let Some(msg) = self.backlog.pop_front() {
+                return Ok(msg)
+            }
+            let frame = self.transport.next().await;
+            self.decode(frame)?;
+        }
+    }
 
-        let actor = tokio::spawn(async move {
+    /// Perform the DDP handshake, asking to resume the given session if any.
+    /// Returns the session id granted by the server.
+    async fn handshake(&mut self, session: Option<String>, version: &str, support: &[String]) -> Result<ConnectInfo> {
 
-            let mut pending: Slab<oneshot::Sender<MethodResult>> = Slab::new();
-            //let mut up_rx = ReceiverStream::new(up_rx).fuse();
+        let version = version.to_string();
+        let connect_msg = ClientMessage::Connect { version: version.clone(),
+                                                     support: support.to_vec(),
+                                                     session };
 
-            loop {
+        self.send(connect_msg).await?;
 
-                select! {
-                    msg = ws_down.next() => {
-
-                        let msg = msg.ok_or(anyhow!("end of ws stream"))??;
-
-                        match msg {
-                            ServerMessage::Ping { id } => {
-                                debug!("Answering ping request");
-                                ws_up.send(ClientMessage::Pong { id }).await?;
-                            },
-                    
-                            ServerMessage::Result(r) => {
-                                if let Some(chan) = pending.remove(&r.id) {
-                                    // Our caller dropped, what're we gonna do?
-                                    let _ = chan.send(r.into());
-                                } else {
-                                    return Err::<(),Error>(anyhow!("Unknown call response ID"))
-                                }
-
-                            },
-
-                            other => {
-                                down_tx.send(other).await?;
-                            }
-                            
-                        }
-                    },
+        match self.recv().await? {
+            ServerMessage::Connected { session } => Ok(ConnectInfo { version, session, http_status: None, http_headers: None }),
+            ServerMessage::Failed { version } => Err(Error::VersionMismatch { suggested: version }),
+            other => Err(Error::Handshake(format!("unexpected message: {:?}", other))),
+        }
+    }
 
-                    msg = up_rx.next() => {
-                        match msg.ok_or(anyhow!("end of method stream"))? {
-                            Request::Method { name, params, result } => {
-                                let id = pending.insert(result);
-                                let message = ClientMessage::Method { id, method: name, params };
-                                ws_up.send(message).await?
-                            },
-                            Request::Subscribe { name, id, params } => {
-                                let message = ClientMessage::Sub { id, name, params };
-                                ws_up.send(message).await?
-                            },
-                            Request::Unsubscribe { id } => {
-                                let message = ClientMessage::Unsub { id };
-                                ws_up.send(message).await?
-                            }
-                        }
-                    }
+}
+
+/// A method call waiting for its result.
+struct PendingCall {
+    method: String,
+    sent: Instant,
+    result: oneshot::Sender<Result<MethodResult>>,
+    /// Released when the call is forgotten, letting another one in.
+    _permit: Option<OwnedSemaphorePermit>,
+    /// The parameters and seed to send the call again with after
+    /// reconnecting, see [`ConnectionBuilder::resume_calls`].
+    resend: Option<(Vec<Value>, Option<String>)>,
+    #[cfg(feature = "otel")]
+    span: crate::otel::CallSpan,
+}
+
+impl PendingCall {
+
+    /// Give up on the call, telling its caller why.
+    fn fail(self, error: Error) {
+        #[cfg(feature = "otel")]
+        self.span.fail(&error);
+        let _ = self.result.send(Err(error));
+    }
+
+}
+
+/// What a subscription was started with.
+struct ActiveSubscription {
+    name: String,
+    params: Vec<Value>,
+    started: Instant,
+    /// The local subscriptions sharing it.
+    users: usize,
+}
+
+/// The background task owning the websocket.
+struct Worker {
+    /// Where to reconnect to; connections built from an existing websocket
+    /// cannot be re-established.
+    url: Option<String>,
+    config: Config,
+    /// The negotiated DDP version, kept across reconnections.
+    version: String,
+    session: watch::Sender<Option<String>>,
+    /// The reason given by the server when it last closed the websocket.
+    close_reason: watch::Sender<Option<CloseReason>>,
+    status: watch::Sender<ConnectionStatus>,
+    pending: Slab<PendingCall>,
+    /// Calls waiting for their `updated` message.
+    updating: HashMap<String, oneshot::Sender<()>>,
+    /// Subscriptions waiting for their `ready` or `nosub` message.
+    subscribing: HashMap<String, Vec<oneshot::Sender<SubscriptionResult>>>,
+    /// Subscriptions started and not stopped, ready or not, to be restored
+    /// on a new session.
+    subscriptions: HashMap<String, ActiveSubscription>,
+    /// The ids of local subscriptions sharing the server subscription of
+    /// another id.
+    aliases: HashMap<String, String>,
+    /// Streams receiving the document messages of a given collection.
+    routes: HashMap<String, Vec<queue::Sender<ServerMessage>>>,
+    /// Streams receiving a copy of the messages passed down.
+    taps: Vec<queue::Sender<ServerMessage>>,
+    cache: Option<Cache>,
+    handlers: Arc<Mutex<Handlers>>,
+    /// Requests taken while reconnecting, to be handled on the new link.
+    offline: VecDeque<Request>,
+    /// The last call made with `wait`, holding back the calls after it.
+    waiting: Option<String>,
+    /// Calls held back by a call made with `wait`.
+    held: VecDeque<Request>,
+    /// When the link was lost, while the calls are parked until the next one.
+    parked: Option<Instant>,
+    /// The inbound stream, until it is dropped while taps are left.
+    down_tx: Option<queue::Sender<ServerMessage>>,
+    up_rx: mpsc::Receiver<Request>,
+    error: ErrorSlot,
+}
+
+impl Worker {
+
+    /// Serve the connection until it ends. The error that ended it, if any,
+    /// is left in the error slot before the inbound stream ends.
+    async fn run(mut self, link: Link) {
+        let res = self.run_links(link).await;
+        self.finish(res);
+    }
+
+    /// Dial, then serve the connection until it ends.
+    async fn run_lazy(mut self, url: String) {
+        let res = match self.first_link(&url).await {
+            Ok(Some(link)) => self.run_links(link).await,
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        self.finish(res);
+    }
+
+    /// Fail the requests left, and report how the connection ended.
+    fn finish(&mut self, res: Result<()>) {
+        // Before failing the calls, whose callers may look for the error
+        match res {
+            Ok(()) => debug!("Siderite worker has terminated"),
+            Err(e) => {
+                error!("Siderite worker has terminated: {}", e);
+                *self.error.lock().unwrap_or_else(|e| e.into_inner()) = Some(e);
+            },
+        }
+        for call in self.pending.drain() {
+            call.fail(Error::Closed);
+        }
+        // Including the requests never taken, such as those made before a
+        // lazy connection could be established
+        self.up_rx.close();
+        let mut untaken = Vec::new();
+        while let Some(Some(request)) = self.up_rx.next().now_or_never() {
+            untaken.push(request);
+        }
+        for request in self.offline.drain(..).chain(self.held.drain(..)).chain(untaken) {
+            if let Request::Method { result, .. } = request {
+                let _ = result.send(Err(Error::Closed));
+            }
+        }
+        self.report_pending();
+        let _ = self.status.send(ConnectionStatus::Closed);
+    }
+
+    /// Establish the first link of a lazy connection, dialing again as
+    /// after losing a link if the first attempt fails.
+    /// Returns `None` if the connection is closed in the meantime.
+    async fn first_link(&mut self, url: &str) -> Result<Option<Link>> {
+        let config = self.config.clone();
+        let attempt = with_connect_timeout(&config, Connection::establish(url, &config));
+        match self.offline(attempt).await {
+            None => Ok(None),
+            Some(Ok((link, info))) => {
+                debug!("Connected with session {}", info.session);
+                self.version = info.version;
+                let _ = self.session.send(Some(info.session));
+                let _ = self.status.send(ConnectionStatus::Connected);
+                Ok(Some(link))
+            },
+            Some(Err(e)) if matches!(config.reconnect, ReconnectPolicy::Backoff { .. }) => {
+                warn!("Connection failed: {}", e);
+                self.reconnect(url).await
+            },
+            Some(Err(e)) => Err(e),
+        }
+    }
+
+    async fn run_links(&mut self, mut link: Link) -> Result<()> {
+        loop {
+            let err = match self.serve(&mut link).await {
+                // The server ending the connection is a clean end
+                Err(Error::ClosedByServer(reason)) if reason.is_normal() => {
+                    let _ = self.close_reason.send(Some(reason));
+                    return Ok(())
+                },
+                Err(e) if e.is_link_failure() => e,
+                other => return other,
+            };
+
+            if let Error::ClosedByServer(reason) = &err {
+                let _ = self.close_reason.send(Some(reason.clone()));
+            }
+
+            let url = match (&self.url, self.config.reconnect) {
+                (Some(url), ReconnectPolicy::Backoff { .. }) => url.clone(),
+                _ if matches!(err, Error::ClosedByServer(_)) => return Ok(()),
+                _ => return Err(err),
+            };
+
+            warn!("Connection lost: {}", err);
+            let _ = self.status.send(ConnectionStatus::Reconnecting);
+
+            // Calls in flight on the old link will never see their result,
+            // unless we send them again on the next one.
+            if self.config.resume_calls.is_some() {
+                self.parked.get_or_insert_with(Instant::now);
+            } else {
+                for call in self.pending.drain() {
+                    call.fail(Error::Disconnected);
                 }
+                self.updating.clear();
+                self.report_pending();
+            }
+            if let Some(cache) = &self.cache {
+                cache.revert_stubs();
             }
 
-        });
+            link = match self.reconnect(&url).await? {
+                Some(link) => link,
+                // Closed while reconnecting
+                None => return Ok(()),
+            };
+        }
+    }
 
-        tokio::spawn(async move {
-            let res = actor.await;
-            error!("Siderite worker has terminated: {:?}", res);
-        });
+    /// Dial again until a new link is established, resuming the previous session.
+    /// Returns `None` if the connection is closed in the meantime.
+    async fn reconnect(&mut self, url: &str) -> Result<Option<Link>> {
+
+        let (mut delay, max_delay, max_attempts) = match self.config.reconnect {
+            ReconnectPolicy::Backoff { delay, max_delay, max_attempts } => (delay, max_delay, max_attempts),
+            ReconnectPolicy::Never => return Err(Error::Closed),
+        };
+
+        let mut attempts = 0;
+
+        loop {
+            if matches!(max_attempts, Some(max) if attempts >= max) {
+                return Err(Error::Closed)
+            }
+            attempts += 1;
 
-        Ok(Self { stream: down_rx, handle: Handle { rpc: up_tx } })
+            if self.offline(runtime::sleep(delay)).await.is_none() {
+                return Ok(None)
+            }
+            delay = std::cmp::min(delay * 2, max_delay);
+
+            if self.unheard() {
+                return Err(Error::Closed)
+            }
+
+            let previous = self.session.borrow().clone();
+
+            let attempt = relink(url.to_string(), self.config.clone(), self.version.clone(), previous.clone());
+            let attempt = match self.offline(attempt).await {
+                Some(attempt) => attempt,
+                None => return Ok(None),
+            };
+
+            match attempt {
+                Ok((mut link, session)) => {
+                    if previous.as_ref() == Some(&session) {
+                        info!("Reconnected, resumed session {}", session);
+                    } else {
+                        info!("Reconnected with new session {}", session);
+                        // The server will not update documents from the old session
+                        if let Some(cache) = &self.cache {
+                            cache.clear();
+                        }
+                        if let Err(e) = self.resubscribe(&mut link).await {
+                            warn!("Reconnection failed: {}", e);
+                            continue
+                        }
+                    }
+                    let _ = self.session.send(Some(session));
+                    let _ = self.close_reason.send(None);
+                    let _ = self.status.send(ConnectionStatus::Connected);
+                    if let Some(metrics) = &self.config.metrics {
+                        metrics.reconnected();
+                    }
+                    return Ok(Some(link))
+                },
+                Err(e) => warn!("Reconnection failed: {}", e),
+            }
+        }
     }
 
-    /// Access the inbound stream of messages. Pings are automatically answered,
-    /// all subscription-related messages will be passed down indiscriminatedly.
-    pub fn stream(&mut self) -> &mut impl Stream<Item = ServerMessage> {
-        &mut self.stream
+    /// Run `future` while we have no link. With an offline queue configured,
+    /// or calls parked, requests are taken meanwhile, to be handled once
+    /// reconnected; otherwise, they wait in the channel. Returns `None` if the
+    /// connection is closed.
+    async fn offline<F: Future>(&mut self, future: F) -> Option<F::Output> {
+        let limit = match (self.config.offline_queue, self.config.resume_calls) {
+            (None, None) => return Some(future.await),
+            (limit, _) => limit.unwrap_or(usize::MAX),
+        };
+        let deadline = self.parked.zip(self.config.resume_calls).map(|(lost, deadline)| lost + deadline);
+        let timer = match deadline {
+            Some(deadline) => runtime::sleep_until(deadline).fuse(),
+            None => Fuse::terminated(),
+        };
+        let future = future.fuse();
+        pin_mut!(future, timer);
+        loop {
+            select! {
+                output = future => return Some(output),
+                () = timer => self.expire_parked(),
+                request = self.up_rx.next() => match request {
+                    None | Some(Request::Close) => return None,
+                    Some(Request::Route { collection, stream }) => self.route(collection, stream),
+                    Some(Request::Tap { stream }) => self.tap(stream),
+                    Some(Request::Purge) => self.purge(),
+                    Some(Request::AbortPending { reply }) => {
+                        let _ = reply.send(self.abort_pending());
+                    },
+                    Some(Request::PendingCalls { reply }) => self.pending_calls(reply),
+                    Some(Request::Subscriptions { reply }) => self.subscriptions(reply),
+                    Some(Request::Method { result, .. }) if self.queued_calls() >= limit || matches!(deadline, Some(deadline) if Instant::now() >= deadline) => {
+                        let _ = result.send(Err(Error::Disconnected));
+                    },
+                    Some(request) => self.offline.push_back(request),
+                },
+            }
+        }
     }
 
-    /// Convenience method to consume a single message from the inbound stream.
-    pub async fn recv(&mut self) -> Option<ServerMessage> {
-        self.stream.next().await
+    fn queued_calls(&self) -> usize {
+        self.offline.iter().filter(|request| matches!(request, Request::Method { .. })).count()
     }
 
-    /// Acquire a handle that can be used to make RPC calls without borrowing
-    /// the main connection.
-    pub fn handle(&self) -> Handle {
-        self.handle.clone()
+    /// Start our subscriptions again on a new session. The server sends
+    /// their documents and `ready` messages anew.
+    async fn resubscribe(&self, link: &mut Link) -> Result<()> {
+        for (id, sub) in &self.subscriptions {
+            debug!("Restoring subscription {}", id);
+            let message = ClientMessage::Sub { id: id.clone(), name: sub.name.clone(), params: sub.params.clone() };
+            link.send(message).await?;
+        }
+        Ok(())
     }
 
-    /// See [`Handle::call`]
-    pub async fn call(&mut self, name: String, params: Vec<Value>) -> Result<MethodResult> {
-        self.handle.call(name, params).await
+    fn report_pending(&self) {
+        if let Some(metrics) = &self.config.metrics {
+            metrics.pending_calls(self.pending.len());
+        }
     }
 
-    /// Subscribe to a collection. You need to provide a unique subscription ID.
-    pub async fn subscribe(&mut self, id: String, name: String, params: Vec<Value>) -> Result<()> {
-        self.handle.subscribe(id, name, params).await
+    fn handlers(&self) -> std::sync::MutexGuard<'_, Handlers> {
+        self.handlers.lock().unwrap_or_else(|e| e.into_inner())
     }
 
-    /// Unsubscribe from a previously subscribed connection.
-    pub async fn unsubscribe(&mut self, id: String) -> Result<()> {
-        self.handle.unsubscribe(id).await
+    /// Call the handlers of a message. They are taken out of the lock
+    /// meanwhile, so that they may register other handlers, which are kept
+    /// for the next messages. Returns false if there are none.
+    fn run_handlers(&self, msg: &ServerMessage) -> bool {
+        let mut handlers = std::mem::take(&mut *self.handlers());
+        let handled = handlers.dispatch(msg);
+        let mut slot = self.handlers();
+        handlers.append(std::mem::take(&mut *slot));
+        *slot = handlers;
+        handled
     }
 
-}
+    fn report_subscriptions(&self) {
+        if let Some(metrics) = &self.config.metrics {
+            metrics.active_subscriptions(self.subscriptions.len());
+        }
+    }
 
-impl Handle {
+    /// Update the cache with a document message, and call its handlers. Then
+    /// pass it to the routed streams of its collection if there are any, or
+    /// to the main stream unless a handler took it.
+    async fn dispatch(&mut self, msg: ServerMessage) -> Result<()> {
 
-    /// Perform a DDP RPC Call. 
-    pub async fn call(&mut self, name: String, params: Vec<Value>) -> Result<MethodResult> {
-        let (tx, rx) = oneshot::channel();
-        let request = Request::Method { name, params, result: tx };
-        self.rpc.send(request).await?;
-        Ok(rx.await?)
+        if let Some(cache) = &self.cache {
+            cache.apply(&msg);
+        }
+
+        let handled = self.run_handlers(&msg);
+
+        let collection = match &msg {
+            ServerMessage::Added { collection, .. } |
+            ServerMessage::Changed { collection, .. } |
+            ServerMessage::Removed { collection, .. } |
+            ServerMessage::AddedBefore { collection, .. } => Some(collection),
+            _ => None,
+        };
+
+        if let Some(routes) = collection.and_then(|c| self.routes.get_mut(c.as_str())) {
+            routes.retain(|tx| !tx.is_closed());
+            // The last stream gets the message itself, the others a copy
+            if let Some((last, others)) = routes.split_last() {
+                // A stream dropped meanwhile will be cleaned up next time
+                for tx in others {
+                    let _ = tx.send(msg.clone()).await;
+                }
+                let _ = last.send(msg).await;
+                return Ok(())
+            }
+        }
+
+        match handled {
+            true => Ok(()),
+            false => self.pass_down(msg).await,
+        }
     }
 
-    pub async fn subscribe(&mut self, id: String, name: String, params: Vec<Value>) -> Result<()> {
-        let request = Request::Subscribe { name, id, params };
-        self.rpc.send(request).await?;
+    /// Pass a message down to the inbound stream, and a copy to each tap.
+    /// Taps whose stream was dropped, or overflowed under
+    /// [`crate::OverflowPolicy::Error`], are dropped, ending their stream.
+    /// So is the inbound stream once dropped, as long as taps are left: the
+    /// connection ends when no stream is left.
+    async fn pass_down(&mut self, msg: ServerMessage) -> Result<()> {
+        if !self.taps.is_empty() {
+            let mut taps = Vec::with_capacity(self.taps.len());
+            for tx in std::mem::take(&mut self.taps) {
+                if tx.send(msg.clone()).await.is_ok() {
+                    taps.push(tx);
+                }
+            }
+            self.taps = taps;
+        }
+        match &self.down_tx {
+            Some(tx) => match tx.send(msg).await {
+                Err(Error::Closed) if !self.taps.is_empty() => self.down_tx = None,
+                res => return res,
+            },
+            None if self.taps.is_empty() => return Err(Error::Closed),
+            None => (),
+        }
         Ok(())
     }
 
-    pub async fn unsubscribe(&mut self, id: String) -> Result<()> {
-        let request = Request::Unsubscribe { id };
-        self.rpc.send(request).await?;
-        Ok(())
+    /// Whether the inbound stream and all the taps were dropped.
+    fn unheard(&self) -> bool {
+        let down_closed = match &self.down_tx {
+            Some(tx) => tx.is_closed(),
+            None => true,
+        };
+        down_closed && self.taps.iter().all(|tx| tx.is_closed())
     }
 
-} 
\ No newline at end of file
+    /// Handle a message from the server.
+    async fn receive(&mut self, link: &mut Link, msg: ServerMessage) -> Result<()> {
+        match msg {
+            ServerMessage::Ping { id } => {
+                debug!("Answering ping request");
+                link.send(ClientMessage::Pong { id }).await?;
+            },
+
+            ServerMessage::Pong { .. } => {
+                trace!("Heartbeat answered");
+            },
+    
+            ServerMessage::Result(r) => {
+                if let Some(call) = self.pending.remove(&r.id) {
+                    if let Some(metrics) = &self.config.metrics {
+                        metrics.call_latency(&call.method, call.sent.elapsed());
+                    }
+                    self.report_pending();
+                    let result: MethodResult = r.into();
+                    #[cfg(feature = "otel")]
+                    call.span.end(&result);
+                    // Our caller dropped, what're we gonna do?
+                    let _ = call.result.send(Ok(result));
+                } else if !r.id.starts_with(NO_RESULT_ID) {
+                    // Can happen after a reconnection, for calls made on the previous link
+                    warn!("Unknown call response ID {}", r.id);
+                }
+                self.release(link).await?;
+
+            },
+
+            ServerMessage::Ready { subs } => {
+                for id in &subs {
+                    for chan in self.subscribing.remove(id).into_iter().flatten() {
+                        let _ = chan.send(Ok(()));
+                    }
+                }
+                let msg = ServerMessage::Ready { subs };
+                if !self.run_handlers(&msg) {
+                    self.pass_down(msg).await?;
+                }
+            },
+
+            ServerMessage::Updated { methods } => {
+                if let Some(cache) = &self.cache {
+                    cache.updated(&methods);
+                }
+                for id in &methods {
+                    if let Some(chan) = self.updating.remove(id) {
+                        let _ = chan.send(());
+                    }
+                }
+                self.pass_down(ServerMessage::Updated { methods }).await?;
+            },
+
+            ServerMessage::Nosub { id, error } => {
+                for chan in self.subscribing.remove(&id).into_iter().flatten() {
+                    let _ = chan.send(Err(RPCError(error.clone().unwrap_or(Value::Null))));
+                }
+                if self.subscriptions.remove(&id).is_some() {
+                    self.aliases.retain(|_, server_id| *server_id != id);
+                    self.report_subscriptions();
+                }
+                let msg = ServerMessage::Nosub { id, error };
+                if !self.run_handlers(&msg) {
+                    self.pass_down(msg).await?;
+                }
+            },
+
+            other => {
+                self.dispatch(other).await?;
+            }
+            
+        }
+        Ok(())
+    }
+
+    async fn serve(&mut self, link: &mut Link) -> Result<()> {
+
+        let mut last_seen = Instant::now();
+        let mut ping_sent: Option<Instant> = None;
+
+        // Calls parked when the link was lost, calls held back before, then
+        // requests taken while we were reconnecting
+        self.resend(link).await?;
+        self.release(link).await?;
+        while let Some(request) = self.offline.pop_front() {
+            self.request(link, request).await?;
+        }
+        link.flush().await?;
+
+        loop {
+
+            while let Some(msg) = link.backlog.pop_front() {
+                self.receive(link, msg).await?;
+            }
+
+            // pre1 servers do not answer pings
+            let heartbeat = self.config.heartbeat.filter(|_| self.version != "pre1");
+
+            let deadline = match (heartbeat, ping_sent) {
+                (None, _) => None,
+                (Some(hb), None) => Some(last_seen + hb.interval),
+                (Some(hb), Some(sent)) => Some(sent + hb.timeout),
+            };
+
+            let timer = match deadline {
+                Some(deadline) => runtime::sleep_until(deadline).fuse(),
+                None => Fuse::terminated(),
+            };
+            pin_mut!(timer);
+
+            select! {
+                () = timer => {
+                    if ping_sent.is_some() {
+                        return Err(Error::HeartbeatTimeout)
+                    }
+                    trace!("Sending heartbeat");
+                    link.send(ClientMessage::Ping { id: None }).await?;
+                    ping_sent = Some(Instant::now());
+                },
+
+                frame = link.transport.next().fuse() => {
+
+                    // Any traffic proves the server is alive
+                    last_seen = Instant::now();
+                    ping_sent = None;
+
+                    link.decode(frame)?;
+                },
+
+                msg = self.up_rx.next() => {
+                    let mut request = msg.ok_or(Error::Closed)?;
+                    let mut batch = 1;
+                    loop {
+                        if let Request::Close = request {
+                            debug!("Closing connection");
+                            // The link may already be broken, we're leaving anyway
+                            let _ = link.transport.close().await;
+                            return Ok(())
+                        }
+                        self.request(link, request).await?;
+
+                        // In batching mode, take the requests already waiting
+                        // and flush them together
+                        if !link.batching || batch >= self.config.outbound_capacity {
+                            break
+                        }
+                        match self.up_rx.next().now_or_never() {
+                            Some(Some(next)) => request = next,
+                            _ => break,
+                        }
+                        batch += 1;
+                    }
+                    if link.batching {
+                        link.flush().await?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handle a request from a handle, other than closing the connection.
+    async fn request(&mut self, link: &mut Link, request: Request) -> Result<()> {
+        match request {
+            request @ Request::Method { .. } => {
+                let wait = matches!(request, Request::Method { wait: true, .. });
+                if !self.held.is_empty() || self.blocked(wait) {
+                    self.held.push_back(request);
+                } else {
+                    self.call(link, request).await?;
+                }
+            },
+            Request::Subscribe { name, id, params, ready } => self.subscribe(link, name, id, params, ready).await?,
+            Request::Unsubscribe { id } => self.unsubscribe(link, id).await?,
+            Request::Resubscribe { old, name, id, params, ready } => {
+                // Subscribing first lets the documents of both stay in place
+                self.subscribe(link, name, id, params, ready).await?;
+                self.unsubscribe(link, old).await?
+            },
+            Request::Route { collection, stream } => self.route(collection, stream),
+            Request::Tap { stream } => self.tap(stream),
+            Request::Raw { payload } => {
+                link.submit_text(payload).await?
+            },
+            Request::Flush { done } => {
+                link.flush().await?;
+                let _ = done.send(());
+            },
+            Request::Purge => {
+                self.purge();
+                self.release(link).await?;
+            },
+            Request::AbortPending { reply } => {
+                let _ = reply.send(self.abort_pending());
+            },
+            Request::PendingCalls { reply } => self.pending_calls(reply),
+            Request::Subscriptions { reply } => self.subscriptions(reply),
+            Request::Close => unreachable!("close requests are handled by the caller"),
+        }
+        Ok(())
+    }
+
+    async fn subscribe(&mut self, link: &mut Link, name: String, id: String, params: Vec<Value>,
+                       ready: oneshot::Sender<SubscriptionResult>) -> Result<()> {
+        if self.config.share_subscriptions {
+            let shared = self.subscriptions.iter_mut().find(|(_, sub)| sub.name == name && sub.params == params);
+            if let Some((server_id, sub)) = shared {
+                debug!("Sharing subscription {} as {}", server_id, id);
+                sub.users += 1;
+                match self.subscribing.get_mut(server_id) {
+                    Some(waiting) => waiting.push(ready),
+                    None => { let _ = ready.send(Ok(())); },
+                }
+                self.aliases.insert(id, server_id.clone());
+                self.report_subscriptions();
+                return Ok(())
+            }
+        }
+        self.subscribing.insert(id.clone(), vec![ready]);
+        self.subscriptions.insert(id.clone(), ActiveSubscription {
+            name: name.clone(), params: params.clone(), started: Instant::now(), users: 1,
+        });
+        self.report_subscriptions();
+        let message = ClientMessage::Sub { id, name, params };
+        link.submit(message).await
+    }
+
+    /// Stop a local subscription, and the server one once no one shares it.
+    async fn unsubscribe(&mut self, link: &mut Link, id: String) -> Result<()> {
+        let id = self.aliases.remove(&id).unwrap_or(id);
+        if let Some(sub) = self.subscriptions.get_mut(&id) {
+            sub.users -= 1;
+            if sub.users > 0 {
+                self.report_subscriptions();
+                return Ok(())
+            }
+        }
+        self.subscribing.remove(&id);
+        if self.subscriptions.remove(&id).is_some() {
+            self.report_subscriptions();
+        }
+        let message = ClientMessage::Unsub { id };
+        link.submit(message).await
+    }
+
+    /// Send a method call.
+    async fn call(&mut self, link: &mut Link, request: Request) -> Result<()> {
+        #[cfg_attr(not(feature = "otel"), allow(unused_mut))]
+        if let Request::Method { name, mut params, result, updated, random_seed, permit, wait, #[cfg(feature = "otel")] context } = request {
+            #[cfg(feature = "otel")]
+            let span = crate::otel::start_call(&name, &mut params, &context,
+                                               self.config.trace_injection.as_ref());
+            if let Some(limit) = self.config.pending_call_limit {
+                if self.pending.len() >= limit {
+                    self.pending.retain(|call| !call.result.is_canceled());
+                    self.report_pending();
+                }
+                if self.pending.len() >= limit {
+                    #[cfg(feature = "otel")]
+                    span.fail(&Error::TooManyPendingCalls);
+                    let _ = result.send(Err(Error::TooManyPendingCalls));
+                    return Ok(())
+                }
+            }
+            let call = PendingCall {
+                method: name.clone(),
+                sent: Instant::now(),
+                result,
+                _permit: permit,
+                resend: self.config.resume_calls.map(|_| (params.clone(), random_seed.clone())),
+                #[cfg(feature = "otel")]
+                span,
+            };
+            let id = match self.config.ids.as_ref().map(|ids| ids.method_id()) {
+                Some(id) => match self.pending.insert_named(id.clone(), call) {
+                    Ok(()) => id,
+                    // The call in flight keeps its id
+                    Err(call) => {
+                        call.fail(Error::DuplicateId(id));
+                        return Ok(())
+                    },
+                },
+                None => self.pending.insert(call),
+            };
+            self.report_pending();
+            if let (Some(stub), Some(cache)) = (self.config.stubs.get(&name), &self.cache) {
+                stub(&mut StubContext::new(cache, &id, random_seed.as_deref()), &params);
+            }
+            if let Some(updated) = updated {
+                self.updating.insert(id.clone(), updated);
+            }
+            if wait {
+                self.waiting = Some(id.clone());
+            }
+            let message = ClientMessage::Method { id, method: name, params, random_seed };
+            link.submit(message).await?;
+        }
+        Ok(())
+    }
+
+    /// Whether a call made with `wait` holds back method calls: the last
+    /// one is not done, or this one is, and others are not done.
+    fn blocked(&self, wait: bool) -> bool {
+        matches!(&self.waiting, Some(id) if self.pending.get(id).is_some()) || (wait && !self.pending.is_empty())
+    }
+
+    /// Send the calls parked when the link was lost again, in the order they
+    /// were made.
+    async fn resend(&mut self, link: &mut Link) -> Result<()> {
+        if self.parked.is_none() {
+            return Ok(())
+        }
+        let mut calls: Vec<_> = self.pending.iter()
+            .filter(|(_, call)| !call.result.is_canceled())
+            .filter_map(|(id, call)| {
+                let (params, random_seed) = call.resend.clone()?;
+                Some((call.sent, ClientMessage::Method { id, method: call.method.clone(), params, random_seed }))
+            })
+            .collect();
+        calls.sort_by_key(|(sent, _)| *sent);
+        if !calls.is_empty() {
+            info!("Sending {} parked calls again", calls.len());
+        }
+        for (_, message) in calls {
+            link.submit(message).await?;
+        }
+        self.parked = None;
+        Ok(())
+    }
+
+    /// Send the calls held back, as far as calls made with `wait` allow.
+    async fn release(&mut self, link: &mut Link) -> Result<()> {
+        loop {
+            let wait = match self.held.front() {
+                Some(request) => matches!(request, Request::Method { wait: true, .. }),
+                None => return Ok(()),
+            };
+            if self.blocked(wait) {
+                return Ok(())
+            }
+            if let Some(request) = self.held.pop_front() {
+                self.call(link, request).await?;
+            }
+        }
+    }
+
+    fn route(&mut self, collection: String, stream: oneshot::Sender<queue::Receiver<ServerMessage>>) {
+        let (tx, rx) = queue::channel(self.config.inbound_capacity, self.config.overflow);
+        self.routes.entry(collection).or_default().push(tx);
+        let _ = stream.send(rx);
+    }
+
+    fn tap(&mut self, stream: oneshot::Sender<queue::Receiver<ServerMessage>>) {
+        let (tx, rx) = queue::channel(self.config.inbound_capacity, self.config.overflow);
+        self.taps.push(tx);
+        let _ = stream.send(rx);
+    }
+
+    fn pending_calls(&self, reply: oneshot::Sender<Vec<PendingCallInfo>>) {
+        let calls = self.pending.iter()
+            .map(|(id, call)| PendingCallInfo { id, method: call.method.clone(), age: call.sent.elapsed() });
+        let _ = reply.send(calls.collect());
+    }
+
+    fn subscriptions(&self, reply: oneshot::Sender<Vec<SubscriptionInfo>>) {
+        let subscriptions = self.subscriptions.iter().map(|(id, sub)| SubscriptionInfo {
+            id: id.clone(),
+            name: sub.name.clone(),
+            age: sub.started.elapsed(),
+            ready: !self.subscribing.contains_key(id),
+            users: sub.users,
+        });
+        let _ = reply.send(subscriptions.collect());
+    }
+
+    /// Forget the calls whose caller has given up.
+    fn purge(&mut self) {
+        self.pending.retain(|call| !call.result.is_canceled());
+        self.report_pending();
+        self.updating.retain(|_, chan| !chan.is_canceled());
+        let cancelled = |request: &Request| matches!(request, Request::Method { result, .. } if result.is_canceled());
+        self.offline.retain(|request| !cancelled(request));
+        self.held.retain(|request| !cancelled(request));
+    }
+
+    /// Fail all the calls in flight or queued with [`Error::Cancelled`].
+    /// Their results are ignored if they come later.
+    fn abort_pending(&mut self) -> usize {
+        self.fail_calls(|| Error::Cancelled)
+    }
+
+    /// Fail the parked calls, and the calls made while reconnecting, with
+    /// [`Error::Disconnected`] once the deadline to resume them is past.
+    fn expire_parked(&mut self) {
+        let expired = self.fail_calls(|| Error::Disconnected);
+        if expired > 0 {
+            warn!("Gave up on {} calls parked while reconnecting", expired);
+        }
+    }
+
+    /// Fail all the calls in flight or queued with `error`.
+    fn fail_calls(&mut self, error: impl Fn() -> Error) -> usize {
+        let mut aborted = 0;
+        for call in self.pending.drain() {
+            call.fail(error());
+            aborted += 1;
+        }
+        let (calls, others) = self.offline.drain(..).chain(self.held.drain(..))
+            .partition::<Vec<_>, _>(|request| matches!(request, Request::Method { .. }));
+        for request in calls {
+            if let Request::Method { result, .. } = request {
+                let _ = result.send(Err(error()));
+                aborted += 1;
+            }
+        }
+        self.offline.extend(others);
+        self.updating.clear();
+        self.waiting = None;
+        self.report_pending();
+        aborted
+    }
+
+}
+
+
+impl Connection {
+
+    /// Create a new connection to the given websocket endpoint.
+    /// the url parameter is passed as-is to [`async_tungstenite::tokio`];
+    /// both `ws://` and `wss://` urls are supported.
+    ///
+    /// If the websocket is lost, the connection is re-established in the background,
+    /// asking the server to resume the same DDP session.
+    pub async fn connect(url: &str) -> Result<Self> {
+        Self::connect_with_config(url, Config::default()).await
+    }
+
+    /// Configure a connection to the given websocket endpoint.
+    pub fn builder(url: &str) -> ConnectionBuilder {
+        ConnectionBuilder::new(url)
+    }
+
+    /// Connect with a complete websocket upgrade request, for instance to
+    /// pass authentication headers or cookies to a gateway.
+    pub async fn connect_with_request(request: HttpRequest) -> Result<Self> {
+        ConnectionBuilder::from_request(request).connect().await
+    }
+
+    /// Like [`Connection::connect`], with custom settings.
+    /// If the server refuses our preferred DDP version but suggests another
+    /// one we support, we dial again with that one.
+    pub async fn connect_with_config(url: &str, config: Config) -> Result<Self> {
+        let (link, info) = with_connect_timeout(&config, Self::establish(url, &config)).await?;
+        Self::start(link, info, Some(url.to_string()), config).await
+    }
+
+    /// Dial, and perform the DDP handshake.
+    async fn establish(url: &str, config: &Config) -> Result<(Link, ConnectInfo)> {
+        let mut version = preferred_version(config);
+        loop {
+            let (stream, response) = dial(url, config).await?;
+            let mut link = Link::new(websocket(stream, config), config);
+            match link.handshake(None, &version, &config.versions).await {
+                Ok(info) => {
+                    let (response, ()) = response.into_parts();
+                    let info = ConnectInfo {
+                        http_status: Some(response.status),
+                        http_headers: Some(response.headers),
+                        ..info
+                    };
+                    return Ok((link, info))
+                },
+                Err(Error::VersionMismatch { suggested }) if suggested != version && config.versions.contains(&suggested) => {
+                    debug!("Server suggested DDP version {}, dialing again", suggested);
+                    version = suggested;
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Create a new connection from an existing tungstenite websocket stream.
+    /// Such connections are not re-established if the websocket is lost.
+    pub async fn connect_with_websocket<S>(stream: WebSocketStream<S>) -> Result<Self>
+        where S: AsyncRead + AsyncWrite + Send + Unpin + 'static
+    {
+        Self::connect_with_transport(transport::websocket(stream)).await
+    }
+
+    /// Create a new connection over a stream established by the caller, such
+    /// as a TCP or TLS stream, or a tunnel: only the websocket handshake with
+    /// `request` and the DDP layer are done here.
+    /// Such connections are not re-established if the stream is lost.
+    pub async fn connect_over<S, R>(stream: S, request: R) -> Result<Self>
+        where S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin + 'static,
+              R: IntoClientRequest + Unpin
+    {
+        Self::connect_over_with_config(stream, request, Config::default()).await
+    }
+
+    /// Like [`Connection::connect_over`], with custom settings. See
+    /// [`ConnectionBuilder::connect_over`].
+    pub(crate) async fn connect_over_with_config<S, R>(stream: S, request: R, config: Config) -> Result<Self>
+        where S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin + 'static,
+              R: IntoClientRequest + Unpin
+    {
+        let mut request = request.into_client_request()?;
+        request.headers_mut().extend(config.headers.clone());
+        let establish = async {
+            let ws_config = Some(websocket_config(&config));
+            let (stream, response) = async_tungstenite::tokio::client_async_with_config(request, stream, ws_config).await?;
+            let mut link = Link::new(websocket(stream, &config), &config);
+            let info = link.handshake(None, &preferred_version(&config), &config.versions).await?;
+            let (response, ()) = response.into_parts();
+            let info = ConnectInfo {
+                http_status: Some(response.status),
+                http_headers: Some(response.headers),
+                ..info
+            };
+            Ok((link, info))
+        };
+        let (link, info) = with_connect_timeout(&config, establish).await?;
+        Self::start(link, info, None, config).await
+    }
+
+    /// Create a new connection over any [`Transport`].
+    /// Such connections are not re-established if the transport is lost.
+    pub async fn connect_with_transport(transport: impl Transport) -> Result<Self> {
+        Self::connect_with_transport_and_config(transport, Config::default()).await
+    }
+
+    /// Like [`Connection::connect_with_transport`], with custom settings. See
+    /// [`ConnectionBuilder::connect_with_transport`].
+    pub(crate) async fn connect_with_transport_and_config(transport: impl Transport, config: Config) -> Result<Self> {
+        let mut link = Link::new(transport, &config);
+        let info = with_connect_timeout(&config, link.handshake(None, &preferred_version(&config), &config.versions)).await?;
+        Self::start(link, info, None, config).await
+    }
+
+    async fn start(link: Link, info: ConnectInfo, url: Option<String>, config: Config) -> Result<Self> {
+        debug!("Connected with session {}", info.session);
+        let session = Some(info.session.clone());
+        let (connection, worker) = Self::assemble(info, session, ConnectionStatus::Connected, url, config);
+        Ok(connection.spawn(worker, |worker| worker.run(link)))
+    }
+
+    /// Return a connection right away, and dial it in the background.
+    /// Requests made in the meantime wait until the DDP session is
+    /// established, or are queued as configured with
+    /// [`ConnectionBuilder::offline_queue`].
+    ///
+    /// Failed dials are retried according to the [`ReconnectPolicy`]; with
+    /// [`ReconnectPolicy::Never`], the first failure closes the connection,
+    /// and is reported by [`Connection::take_error`].
+    ///
+    /// This must be called from within the runtime.
+    pub fn connect_lazy(url: &str) -> Self {
+        Self::connect_lazy_with_config(url, Config::default())
+    }
+
+    /// Like [`Connection::connect_lazy`], with custom settings.
+    pub fn connect_lazy_with_config(url: &str, config: Config) -> Self {
+        let info = ConnectInfo {
+            version: preferred_version(&config),
+            session: String::new(),
+            http_status: None,
+            http_headers: None,
+        };
+        let (connection, worker) = Self::assemble(info, None, ConnectionStatus::Connecting, Some(url.to_string()), config);
+        let url = url.to_string();
+        connection.spawn(worker, |worker| worker.run_lazy(url))
+    }
+
+    /// Build a connection and the worker that will serve it.
+    fn assemble(info: ConnectInfo, session: Option<String>, status: ConnectionStatus,
+                url: Option<String>, config: Config) -> (Self, Worker)
+    {
+        let (status_tx, status_rx) = watch::channel(status);
+        let (session_tx, session_rx) = watch::channel(session);
+        let (close_reason_tx, close_reason_rx) = watch::channel(None);
+        let (down_tx, down_rx) = queue::channel::<ServerMessage>(config.inbound_capacity, config.overflow);
+        let (up_tx, up_rx) = mpsc::channel::<Request>(config.outbound_capacity);
+        let call_timeout = config.call_timeout;
+        let cache = if config.cache { Some(Cache::new()) } else { None };
+        let ids = Ids(config.ids.clone());
+        let random_seeds = config.random_seeds;
+        let limiter = RateLimiter::new(&config).map(Arc::new);
+        let max_pending = config.max_pending_calls.map(|n| Arc::new(Semaphore::new(n)));
+        let handlers = Arc::new(Mutex::new(Handlers::default()));
+        let error = ErrorSlot::default();
+
+        let worker = Worker {
+            url,
+            config,
+            version: info.version.clone(),
+            session: session_tx,
+            close_reason: close_reason_tx,
+            status: status_tx,
+            pending: Slab::new(),
+            updating: HashMap::new(),
+            subscribing: HashMap::new(),
+            aliases: HashMap::new(),
+            subscriptions: HashMap::new(),
+            routes: HashMap::new(),
+            taps: Vec::new(),
+            cache: cache.clone(),
+            handlers: handlers.clone(),
+            offline: VecDeque::new(),
+            waiting: None,
+            held: VecDeque::new(),
+            parked: None,
+            down_tx: Some(down_tx),
+            up_rx,
+            error: error.clone(),
+        };
+
+        let connection = Self { stream: down_rx, worker: None, error, handle: Handle {
+            rpc: up_tx,
+            session: session_rx,
+            close_reason: close_reason_rx,
+            status: status_rx,
+            call_timeout,
+            cache,
+            ids,
+            random_seeds,
+            limiter,
+            max_pending,
+            handlers,
+        }, info };
+        (connection, worker)
+    }
+
+    /// Spawn the task running the worker.
+    fn spawn<F>(mut self, worker: Worker, run: impl FnOnce(Worker) -> F) -> Self
+        where F: Future<Output = ()> + Send + 'static
+    {
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!("ddp_connection",
+                                       url = worker.url.as_deref().unwrap_or("<websocket>"),
+                                       session = self.handle.session.borrow().as_deref().unwrap_or_default());
+
+        let task = run(worker);
+        #[cfg(feature = "tracing")]
+        let task = tracing::Instrument::instrument(task, span);
+        self.worker = Some(WorkerHandle { task: runtime::spawn(task), error: self.error.clone() });
+        self
+    }
+
+    /// What the connection was initially established with. After a
+    /// reconnection, the current session is given by [`Connection::session`].
+    /// Connections made with [`Connection::connect_lazy`] have no initial
+    /// session nor HTTP response here.
+    pub fn info(&self) -> &ConnectInfo {
+        &self.info
+    }
+
+    /// Access the inbound stream of messages. Pings are automatically answered,
+    /// all subscription-related messages will be passed down indiscriminatedly.
+    /// To move the stream elsewhere, see [`Connection::split`].
+    pub fn stream(&mut self) -> &mut impl Stream<Item = ServerMessage> {
+        &mut self.stream
+    }
+
+    /// Convenience method to consume a single message from the inbound stream.
+    /// It returns `None` once the connection has ended, see [`Connection::take_error`].
+    pub async fn recv(&mut self) -> Option<ServerMessage> {
+        self.stream.next().await
+    }
+
+    /// Acquire a handle that can be used to make RPC calls without borrowing
+    /// the main connection.
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
+    /// Why the connection ended, once the inbound stream is over: `None`
+    /// if it was closed cleanly, or the error is already taken.
+    pub fn take_error(&mut self) -> Option<Error> {
+        take_error(&self.error)
+    }
+
+    /// Take the handle to the background task running the connection, to
+    /// await its termination or abort it. Only the first call returns it.
+    pub fn take_worker(&mut self) -> Option<WorkerHandle> {
+        self.worker.take()
+    }
+
+    /// Split the connection into its inbound stream of messages and a handle,
+    /// so that each can be moved into a different task. The stream is owned,
+    /// and can be boxed.
+    #[doc(alias = "into_stream")]
+    pub fn split(self) -> (Messages, Handle) {
+        (Messages { stream: self.stream, error: self.error }, self.handle)
+    }
+
+    /// See [`Handle::message_stream`]
+    pub async fn message_stream(&mut self) -> Result<impl Stream<Item = ServerMessage>> {
+        self.handle.message_stream().await
+    }
+
+    /// See [`Handle::collection_stream`]
+    pub async fn collection_stream(&mut self, collection: &str) -> Result<impl Stream<Item = ServerMessage>> {
+        self.handle.collection_stream(collection).await
+    }
+
+    /// See [`Handle::collection`]
+    pub async fn collection<T: DeserializeOwned>(&mut self, name: &str) -> Result<Collection<T>> {
+        self.handle.collection(name).await
+    }
+
+    /// See [`Handle::cache`]
+    pub fn cache(&self) -> Option<&Cache> {
+        self.handle.cache()
+    }
+
+    /// See [`Handle::session`]
+    pub fn session(&self) -> Option<String> {
+        self.handle.session()
+    }
+
+    /// See [`Handle::status`]
+    pub fn status(&self) -> watch::Receiver<ConnectionStatus> {
+        self.handle.status()
+    }
+
+    /// See [`Handle::close_reason`]
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.handle.close_reason()
+    }
+
+    /// See [`Handle::on_added`]
+    pub fn on_added<F>(&self, collection: &str, handler: F)
+        where F: FnMut(&str, &Document) + Send + 'static
+    {
+        self.handle.on_added(collection, handler)
+    }
+
+    /// See [`Handle::on_changed`]
+    pub fn on_changed<F>(&self, collection: &str, handler: F)
+        where F: FnMut(&str, &Document, &[String]) + Send + 'static
+    {
+        self.handle.on_changed(collection, handler)
+    }
+
+    /// See [`Handle::on_removed`]
+    pub fn on_removed<F>(&self, collection: &str, handler: F)
+        where F: FnMut(&str) + Send + 'static
+    {
+        self.handle.on_removed(collection, handler)
+    }
+
+    /// See [`Handle::on_ready`]
+    pub fn on_ready<F>(&self, handler: F)
+        where F: FnMut(&[String]) + Send + 'static
+    {
+        self.handle.on_ready(handler)
+    }
+
+    /// See [`Handle::on_nosub`]
+    pub fn on_nosub<F>(&self, handler: F)
+        where F: FnMut(&str, Option<&Value>) + Send + 'static
+    {
+        self.handle.on_nosub(handler)
+    }
+
+    /// See [`Handle::flush`]
+    pub async fn flush(&mut self) -> Result<()> {
+        self.handle.flush().await
+    }
+
+    /// See [`Handle::call`]
+    pub async fn call<P: Serialize>(&mut self, name: String, params: P) -> Result<MethodResult> {
+        self.handle.call(name, params).await
+    }
+
+    /// See [`Handle::call_with_seed`]
+    pub async fn call_with_seed<P: Serialize>(&mut self, name: String, params: P, seed: String) -> Result<MethodResult> {
+        self.handle.call_with_seed(name, params, seed).await
+    }
+
+    /// See [`Handle::call_with_retry`]
+    pub async fn call_with_retry<P: Serialize>(&mut self, name: String, params: P, policy: &RetryPolicy) -> Result<MethodResult> {
+        self.handle.call_with_retry(name, params, policy).await
+    }
+
+    /// See [`Handle::call_wait`]
+    pub async fn call_wait<P: Serialize>(&mut self, name: String, params: P) -> Result<MethodResult> {
+        self.handle.call_wait(name, params).await
+    }
+
+    /// See [`Handle::method`]
+    pub async fn method<P: Serialize>(&mut self, name: String, params: P) -> Result<MethodCall> {
+        self.handle.method(name, params).await
+    }
+
+    /// See [`Handle::call_and_wait_updated`]
+    pub async fn call_and_wait_updated<P: Serialize>(&mut self, name: String, params: P) -> Result<MethodResult> {
+        self.handle.call_and_wait_updated(name, params).await
+    }
+
+    /// See [`Handle::send_message`]
+    pub async fn send_message(&mut self, msg: &ClientMessage) -> Result<()> {
+        self.handle.send_message(msg).await
+    }
+
+    /// See [`Handle::send_raw`]
+    pub async fn send_raw(&mut self, payload: String) -> Result<()> {
+        self.handle.send_raw(payload).await
+    }
+
+    /// See [`Handle::call_no_result`]
+    pub async fn call_no_result<P: Serialize>(&mut self, name: String, params: P) -> Result<()> {
+        self.handle.call_no_result(name, params).await
+    }
+
+    /// See [`Handle::call_typed`]
+    pub async fn call_typed<P, R>(&mut self, name: &str, params: P) -> std::result::Result<R, MethodError>
+        where P: Serialize, R: DeserializeOwned
+    {
+        self.handle.call_typed(name, params).await
+    }
+
+    /// Subscribe to a collection. You need to provide a unique subscription ID.
+    /// See [`Handle::subscribe`]
+    #[must_use = "dropping the guard unsubscribes"]
+    pub async fn subscribe<P: Serialize>(&mut self, id: String, name: String, params: P) -> Result<SubscriptionGuard> {
+        self.handle.subscribe(id, name, params).await
+    }
+
+    /// Unsubscribe from a previously subscribed connection.
+    pub async fn unsubscribe(&mut self, id: String) -> Result<()> {
+        self.handle.unsubscribe(id).await
+    }
+
+    /// See [`Handle::close`]
+    pub async fn close(mut self) -> Result<()> {
+        self.handle.close().await
+    }
+
+}
+
+impl Handle {
+
+    /// The DDP session id negotiated with the server. It is updated
+    /// whenever the connection is re-established.
+    pub fn session(&self) -> Option<String> {
+        self.session.borrow().clone()
+    }
+
+    /// The local copy of the published documents, if enabled with
+    /// [`ConnectionBuilder::cache`].
+    pub fn cache(&self) -> Option<&Cache> {
+        self.cache.as_ref()
+    }
+
+    /// Watch the state of the link to the server.
+    pub fn status(&self) -> watch::Receiver<ConnectionStatus> {
+        self.status.clone()
+    }
+
+    /// The method calls waiting for their result, to spot calls that are stuck.
+    /// Calls queued while reconnecting are not sent yet, and not included.
+    pub async fn pending_calls(&mut self) -> Result<Vec<PendingCallInfo>> {
+        let (reply, rx) = oneshot::channel();
+        self.rpc.send(Request::PendingCalls { reply }).await?;
+        Ok(rx.await?)
+    }
+
+    /// Fail all the method calls in flight, or queued while reconnecting,
+    /// with [`Error::Cancelled`], for instance to fail over to another
+    /// connection. Returns the number of calls aborted.
+    pub async fn abort_pending(&mut self) -> Result<usize> {
+        let (reply, rx) = oneshot::channel();
+        self.rpc.send(Request::AbortPending { reply }).await?;
+        Ok(rx.await?)
+    }
+
+    /// The subscriptions started and not stopped, to spot leaked ones.
+    pub async fn subscriptions(&mut self) -> Result<Vec<SubscriptionInfo>> {
+        let (reply, rx) = oneshot::channel();
+        self.rpc.send(Request::Subscriptions { reply }).await?;
+        Ok(rx.await?)
+    }
+
+    /// Why the server closed the websocket, if it did, until the connection
+    /// is re-established. Once the connection is closed, this tells whether
+    /// the server ended it.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.close_reason.borrow().clone()
+    }
+
+    fn handlers(&self) -> std::sync::MutexGuard<'_, Handlers> {
+        self.handlers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Call `handler` with the id and fields of the documents added to a
+    /// collection. Handlers are called by the connection worker, so they
+    /// should be quick. They may register other handlers, which apply from
+    /// the next message on. Messages handled by a callback are no longer
+    /// passed on the inbound stream of the connection, but still reach the
+    /// streams of their collection, see [`Handle::collection_stream`].
+    pub fn on_added<F>(&self, collection: &str, handler: F)
+        where F: FnMut(&str, &Document) + Send + 'static
+    {
+        self.handlers().added.entry(collection.to_string()).or_default().push(Box::new(handler));
+    }
+
+    /// Call `handler` with the id, the changed fields and the cleared fields
+    /// of the documents changed in a collection. See [`Handle::on_added`].
+    pub fn on_changed<F>(&self, collection: &str, handler: F)
+        where F: FnMut(&str, &Document, &[String]) + Send + 'static
+    {
+        self.handlers().changed.entry(collection.to_string()).or_default().push(Box::new(handler));
+    }
+
+    /// Call `handler` with the id of the documents removed from a collection.
+    /// See [`Handle::on_added`].
+    pub fn on_removed<F>(&self, collection: &str, handler: F)
+        where F: FnMut(&str) + Send + 'static
+    {
+        self.handlers().removed.entry(collection.to_string()).or_default().push(Box::new(handler));
+    }
+
+    /// Call `handler` with the ids of the subscriptions in `ready` messages.
+    /// See [`Handle::on_added`].
+    pub fn on_ready<F>(&self, handler: F)
+        where F: FnMut(&[String]) + Send + 'static
+    {
+        self.handlers().ready.push(Box::new(handler));
+    }
+
+    /// Call `handler` with the id and error of the subscriptions stopped by
+    /// the server, in `nosub` messages. See [`Handle::on_added`].
+    pub fn on_nosub<F>(&self, handler: F)
+        where F: FnMut(&str, Option<&Value>) + Send + 'static
+    {
+        self.handlers().nosub.push(Box::new(handler));
+    }
+
+    /// Write out the messages sent so far. Only needed in batching mode
+    /// (see [`crate::ConnectionBuilder::batching`]), where the worker
+    /// otherwise flushes once it has no more requests waiting.
+    /// While reconnecting, this waits for the queued messages to go out.
+    pub async fn flush(&mut self) -> Result<()> {
+        let (done, rx) = oneshot::channel();
+        self.rpc.send(Request::Flush { done }).await?;
+        Ok(rx.await?)
+    }
+
+    /// A fresh id for [`Handle::subscribe`], made by the configured
+    /// [`crate::ids::IdGenerator`] if any.
+    pub fn subscription_id(&self) -> String {
+        self.ids.subscription_id()
+    }
+
+    /// Perform a DDP RPC Call. 
+    /// The parameters can be anything serializing to an array, such as a tuple or
+    /// a `Vec<Value>`, or `()` for no parameters; any other value is passed as
+    /// a single parameter.
+    /// If the connection was configured with a call timeout, it applies here.
+    pub async fn call<P: Serialize>(&mut self, name: String, params: P) -> Result<MethodResult> {
+        match self.call_timeout {
+            Some(timeout) => self.call_with_timeout(name, params, timeout).await,
+            None => self.start_call(name, params).await?.await,
+        }
+    }
+
+    /// Send a method call without tracking it, for notification-style
+    /// methods: its result is discarded, and nothing is retried after a
+    /// reconnection. Returns once the call is handed to the connection.
+    pub async fn call_no_result<P: Serialize>(&mut self, name: String, params: P) -> Result<()> {
+        let params = to_params(params)?;
+        self.throttle(Some(&name)).await;
+        let id = format!("{}{}", NO_RESULT_ID, random_id());
+        let random_seed = self.random_seed();
+        self.send_message(&ClientMessage::Method { id, method: name, params, random_seed }).await
+    }
+
+    /// Perform a DDP RPC Call with typed parameters and result.
+    pub async fn call_typed<P, R>(&mut self, name: &str, params: P) -> std::result::Result<R, MethodError>
+        where P: Serialize, R: DeserializeOwned
+    {
+        let result = self.call(name.to_string(), params).await??;
+        serde_json::from_value(result).map_err(MethodError::Decode)
+    }
+
+    /// Perform a DDP RPC Call, giving up with an [`Error::Timeout`] if the
+    /// result does not arrive in time.
+    pub async fn call_with_timeout<P: Serialize>(&mut self, name: String, params: P, timeout: Duration) -> Result<MethodResult> {
+        let call = self.start_call(name, params).await?;
+        // The call is cancelled when dropped by the timeout
+        runtime::timeout(timeout, call).await.unwrap_or(Err(Error::Timeout))
+    }
+
+    /// Start a DDP RPC Call, returning as soon as it is sent. The returned
+    /// [`CallHandle`] resolves to the result; the call is cancelled if it is dropped.
+    pub async fn start_call<P: Serialize>(&mut self, name: String, params: P) -> Result<CallHandle> {
+        let random_seed = self.random_seed();
+        self.send_method(name, to_params(params)?, random_seed, None, false).await
+    }
+
+    /// Like [`Handle::start_call`], sending the given `randomSeed` with the
+    /// call, for instance the seed of an enclosing [`crate::ids::RandomStream`].
+    pub async fn start_call_with_seed<P: Serialize>(&mut self, name: String, params: P, seed: String) -> Result<CallHandle> {
+        self.send_method(name, to_params(params)?, Some(seed), None, false).await
+    }
+
+    /// Like [`Handle::call`], sending the given `randomSeed` with the call.
+    pub async fn call_with_seed<P: Serialize>(&mut self, name: String, params: P, seed: String) -> Result<MethodResult> {
+        let call = self.start_call_with_seed(name, params, seed).await?;
+        match self.call_timeout {
+            Some(timeout) => runtime::timeout(timeout, call).await.unwrap_or(Err(Error::Timeout)),
+            None => call.await,
+        }
+    }
+
+    /// Like [`Handle::call`], calling the method again with a backoff after
+    /// the failures allowed by `policy`. The outcome of the last attempt is
+    /// returned.
+    pub async fn call_with_retry<P: Serialize>(&mut self, name: String, params: P, policy: &RetryPolicy) -> Result<MethodResult> {
+        let params = to_params(params)?;
+        let mut delay = policy.delay;
+        let mut attempts = 1;
+        loop {
+            let outcome = self.call(name.clone(), params.clone()).await;
+            let wait = match policy.wait(&outcome, delay) {
+                Some(wait) if attempts < policy.max_attempts => wait,
+                _ => return outcome,
+            };
+            debug!("Calling {} again in {:?}, after attempt {}", name, wait, attempts);
+            runtime::sleep(wait).await;
+            delay = std::cmp::min(delay * 2, policy.max_delay);
+            attempts += 1;
+        }
+    }
+
+    /// Like [`Handle::call`], with the `wait` option of Meteor: the call is
+    /// sent once the calls made before it are done, and the calls made after
+    /// it are held back until it is done. This suits calls that change how the
+    /// server treats the next ones, such as `login`.
+    pub async fn call_wait<P: Serialize>(&mut self, name: String, params: P) -> Result<MethodResult> {
+        let random_seed = self.random_seed();
+        let call = self.send_method(name, to_params(params)?, random_seed, None, true).await?;
+        match self.call_timeout {
+            Some(timeout) => runtime::timeout(timeout, call).await.unwrap_or(Err(Error::Timeout)),
+            None => call.await,
+        }
+    }
+
+    /// Wait until the rate limit, if any, lets us call `method`, or subscribe.
+    async fn throttle(&self, method: Option<&str>) {
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire(method).await;
+        }
+    }
+
+    async fn send_method(&mut self, name: String, params: Vec<Value>, random_seed: Option<String>,
+                         updated: Option<oneshot::Sender<()>>, wait: bool) -> Result<CallHandle> {
+        let permit = match &self.max_pending {
+            Some(semaphore) => Some(semaphore.clone().acquire_owned().await.map_err(|_| Error::Closed)?),
+            None => None,
+        };
+        self.throttle(Some(&name)).await;
+        let (tx, rx) = oneshot::channel();
+        let call = CallHandle::new(rx, self.clone(), &name, random_seed.clone());
+        let request = Request::Method {
+            name, params, result: tx, updated, random_seed, permit, wait,
+            #[cfg(feature = "otel")]
+            context: opentelemetry::Context::current(),
+        };
+        self.rpc.send(request).await?;
+        Ok(call)
+    }
+
+    /// Start a DDP RPC Call, tracking both its result and its `updated`
+    /// message. Parameters are passed as in [`Handle::call`]; the call timeout
+    /// does not apply.
+    pub async fn method<P: Serialize>(&mut self, name: String, params: P) -> Result<MethodCall> {
+        let (updated_tx, updated_rx) = oneshot::channel();
+        let random_seed = self.random_seed();
+        let call = self.send_method(name, to_params(params)?, random_seed, Some(updated_tx), false).await?;
+        Ok(MethodCall::new(call, updated_rx))
+    }
+
+    /// Perform a DDP RPC Call, and return once its writes are reflected in
+    /// our subscriptions as well.
+    pub async fn call_and_wait_updated<P: Serialize>(&mut self, name: String, params: P) -> Result<MethodResult> {
+        self.method(name, params).await?.finished().await
+    }
+
+    /// Subscribe to a publication, under a unique subscription ID. The returned
+    /// [`Subscription`] tells when the server is done sending the initial documents;
+    /// it is stopped when dropped, unless detached.
+    /// Parameters are passed as in [`Handle::call`].
+    #[must_use = "dropping the guard unsubscribes"]
+    pub async fn subscribe<P: Serialize>(&mut self, id: String, name: String, params: P) -> Result<SubscriptionGuard> {
+        let params = to_params(params)?;
+        self.throttle(None).await;
+        let (tx, rx) = oneshot::channel();
+        let request = Request::Subscribe { name: name.clone(), id: id.clone(), params, ready: tx };
+        self.rpc.send(request).await?;
+        Ok(SubscriptionGuard::new(Subscription::new(id, name, self.clone(), rx)))
+    }
+
+    pub async fn unsubscribe(&mut self, id: String) -> Result<()> {
+        let request = Request::Unsubscribe { id };
+        self.rpc.send(request).await?;
+        Ok(())
+    }
+
+    /// Replace subscription `old` with a new one, see [`Subscription::update_params`].
+    pub(crate) async fn resubscribe(&mut self, old: String, id: String, name: String, params: Vec<Value>)
+                                    -> Result<oneshot::Receiver<SubscriptionResult>> {
+        self.throttle(None).await;
+        let (tx, rx) = oneshot::channel();
+        self.rpc.send(Request::Resubscribe { old, name, id, params, ready: tx }).await?;
+        Ok(rx)
+    }
+
+    /// Send any message to the server. Nothing is tracked: the replies to
+    /// messages such as `method` or `sub` sent this way arrive on the main
+    /// stream of the connection, if anywhere.
+    pub async fn send_message(&mut self, msg: &ClientMessage) -> Result<()> {
+        self.send_raw(serde_json::to_string(msg)?).await
+    }
+
+    /// Send a text frame verbatim, for messages beyond the DDP spec.
+    /// As with [`Handle::send_message`], nothing is tracked.
+    pub async fn send_raw(&mut self, payload: String) -> Result<()> {
+        self.rpc.send(Request::Raw { payload }).await?;
+        Ok(())
+    }
+
+    /// A fresh seed for a method call, if they are enabled.
+    fn random_seed(&self) -> Option<String> {
+        match self.random_seeds {
+            true => Some(RandomStream::make_seed()),
+            false => None,
+        }
+    }
+
+    /// Unsubscribe without waiting, for use in destructors.
+    pub(crate) fn unsubscribe_nowait(&mut self, id: String) {
+        self.send_nowait(Request::Unsubscribe { id });
+    }
+
+    /// Have the worker forget about abandoned calls.
+    pub(crate) async fn purge(&mut self) -> Result<()> {
+        self.rpc.send(Request::Purge).await?;
+        Ok(())
+    }
+
+    /// Like [`Handle::purge`], without waiting, for use in destructors.
+    pub(crate) fn purge_nowait(&mut self) {
+        self.send_nowait(Request::Purge);
+    }
+
+    fn send_nowait(&mut self, request: Request) {
+        match self.rpc.try_send(request) {
+            Err(e) if e.is_full() => {
+                // Finish the job in the background, if we're in a runtime at all
+                let mut rpc = self.rpc.clone();
+                let request = e.into_inner();
+                runtime::try_spawn(async move { rpc.send(request).await });
+            },
+            // If the connection is gone, there's nothing left to clean up
+            _ => (),
+        }
+    }
+
+    /// Receive the `added`, `changed` and `removed` messages of a collection on a
+    /// dedicated stream. As long as such a stream exists, these messages are no
+    /// longer passed on the main stream of the connection.
+    pub async fn collection_stream(&mut self, collection: &str) -> Result<impl Stream<Item = ServerMessage>> {
+        let (tx, rx) = oneshot::channel();
+        let request = Request::Route { collection: collection.to_string(), stream: tx };
+        self.rpc.send(request).await?;
+        Ok(rx.await?)
+    }
+
+    /// Another stream of the messages passed down to the inbound stream, for
+    /// consumers that each need the whole feed. It gets the messages from
+    /// now on, buffered and with the overflow policy of the inbound stream,
+    /// and keeps the connection going after the inbound stream is dropped.
+    pub async fn message_stream(&mut self) -> Result<impl Stream<Item = ServerMessage>> {
+        let (tx, rx) = oneshot::channel();
+        self.rpc.send(Request::Tap { stream: tx }).await?;
+        Ok(rx.await?)
+    }
+
+    /// Like [`Handle::collection_stream`], with the documents deserialized as `T`.
+    pub async fn collection<T: DeserializeOwned>(&mut self, name: &str) -> Result<Collection<T>> {
+        let (tx, rx) = oneshot::channel();
+        let request = Request::Route { collection: name.to_string(), stream: tx };
+        self.rpc.send(request).await?;
+        Ok(Collection::new(name, rx.await?))
+    }
+
+    /// Close the connection: the websocket is closed, pending calls fail with
+    /// [`Error::Closed`], and this returns once the worker has terminated.
+    /// This affects all the handles to the connection.
+    pub async fn close(&mut self) -> Result<()> {
+        // If the worker is already gone, there's nothing to close
+        let _ = self.rpc.send(Request::Close).await;
+        let mut status = self.status.clone();
+        while *status.borrow() != ConnectionStatus::Closed {
+            if status.changed().await.is_err() {
+                break
+            }
+        }
+        Ok(())
+    }
+
+}
+
+/// Messages sent through the sink are not tracked, as with [`Handle::send_message`].
+impl Sink<ClientMessage> for Handle {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.rpc.poll_ready_unpin(cx).map_err(Error::from)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, msg: ClientMessage) -> Result<()> {
+        let payload = serde_json::to_string(&msg)?;
+        self.rpc.start_send_unpin(Request::Raw { payload })?;
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.rpc.poll_flush_unpin(cx).map_err(Error::from)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        // Only this handle is done sending; use `Handle::close` to
+        // terminate the connection.
+        self.rpc.poll_flush_unpin(cx).map_err(Error::from)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::config::OverflowPolicy;
+    use crate::testing::{block_on, reconnect_every, MockServer};
+    use serde_json::json;
+
+    #[test]
+    fn test_params() {
+        assert_eq!(to_params(("room", 50, false)).unwrap(), vec![json!("room"), json!(50), json!(false)]);
+        assert_eq!(to_params(vec![json!(1), json!(2)]).unwrap(), vec![json!(1), json!(2)]);
+        assert_eq!(to_params(()).unwrap(), Vec::<Value>::new());
+        assert_eq!(to_params((Value::Null,)).unwrap(), vec![json!(null)]);
+        assert_eq!(to_params(json!({"a": 1})).unwrap(), vec![json!({"a": 1})]);
+    }
+
+    #[test]
+    fn test_connect_timeout() {
+        block_on(async {
+            // Accepts connections, and never answers
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}/websocket", listener.local_addr().unwrap());
+            let connect = Connection::builder(&url).connect_timeout(Duration::from_millis(50)).connect();
+            assert!(matches!(connect.await, Err(Error::ConnectTimeout)));
+        });
+    }
+
+    #[test]
+    fn test_connect_lazy() {
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            server.on_method("ping", |_| Ok(json!("pong")));
+
+            let mut connection = Connection::connect_lazy(&server.url());
+            assert_eq!(*connection.status().borrow(), ConnectionStatus::Connecting);
+            assert_eq!(connection.call("ping".to_string(), ()).await.unwrap(), Ok(json!("pong")));
+            assert_eq!(*connection.status().borrow(), ConnectionStatus::Connected);
+            assert!(connection.session().is_some());
+
+            // Nothing listens there anymore
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}/websocket", listener.local_addr().unwrap());
+            drop(listener);
+            let mut connection = Connection::builder(&url).reconnect(ReconnectPolicy::Never).connect_lazy();
+            assert!(matches!(connection.call("ping".to_string(), ()).await, Err(Error::Closed)));
+            assert!(matches!(connection.take_error(), Some(Error::WebSocket(_))));
+        });
+    }
+
+    #[test]
+    fn test_call_no_result() {
+        block_on(async {
+            let mut server = MockServer::start().await.unwrap();
+            server.on_method("log", |_| Ok(json!(true)));
+            let mut connection = Connection::connect(&server.url()).await.unwrap();
+
+            connection.call_no_result("log".to_string(), ("hello",)).await.unwrap();
+            loop {
+                if let Some(ClientMessage::Method { id, method, params, .. }) = server.next_message().await {
+                    assert!(id.starts_with(NO_RESULT_ID));
+                    assert_eq!((method.as_str(), params), ("log", vec![json!("hello")]));
+                    break
+                }
+            }
+            assert_eq!(connection.call("log".to_string(), ()).await.unwrap(), Ok(json!(true)));
+            assert!(connection.handle().pending_calls().await.unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_abort_pending() {
+        block_on(async {
+            // Unknown methods are never answered
+            let server = MockServer::start().await.unwrap();
+            let connection = Connection::connect(&server.url()).await.unwrap();
+            let mut handle = connection.handle();
+
+            let calls = [
+                handle.start_call("slow".to_string(), ()).await.unwrap(),
+                handle.start_call("slower".to_string(), ()).await.unwrap(),
+            ];
+            assert_eq!(handle.pending_calls().await.unwrap().len(), 2);
+            assert_eq!(handle.abort_pending().await.unwrap(), 2);
+            for call in calls {
+                assert!(matches!(call.await, Err(Error::Cancelled)));
+            }
+            assert!(handle.pending_calls().await.unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_resume_calls() {
+        block_on(async {
+            let mut server = MockServer::start().await.unwrap();
+            let connection = Connection::builder(&server.url())
+                .reconnect(reconnect_every(Duration::from_millis(10)))
+                .resume_calls(Duration::from_millis(200))
+                .connect().await.unwrap();
+            let mut handle = connection.handle();
+
+            // The call is in flight when the link is lost, and answered on the next one
+            let call = handle.start_call("echo".to_string(), ("hi",)).await.unwrap();
+            while !matches!(server.next_message().await, Some(ClientMessage::Method { .. })) {}
+            server.on_method("echo", |params| Ok(params[0].clone()));
+            server.disconnect();
+            assert_eq!(call.await.unwrap(), Ok(json!("hi")));
+
+            // Without a server to reconnect to, calls give up after the deadline
+            let call = handle.start_call("stuck".to_string(), ()).await.unwrap();
+            drop(server);
+            assert!(matches!(call.await, Err(Error::Disconnected)));
+            assert!(matches!(handle.call("echo".to_string(), ()).await, Err(Error::Disconnected)));
+        });
+    }
+
+    #[test]
+    fn test_resubscribe() {
+        block_on(async {
+            let mut server = MockServer::start().await.unwrap();
+            server.publish("rooms", vec![]);
+            // Each websocket gets a new session, which forgets the subscriptions
+            server.fresh_sessions();
+
+            let mut connection = Connection::builder(&server.url())
+                .reconnect(reconnect_every(Duration::from_millis(10)))
+                .connect().await.unwrap();
+            let mut sub = connection.subscribe("s1".to_string(), "rooms".to_string(), ()).await.unwrap();
+            assert_eq!(sub.ready().await.unwrap(), Ok(()));
+            assert!(matches!(connection.recv().await, Some(ServerMessage::Ready { .. })));
+            // The subscription is restored on the new session, and is ready again
+            server.disconnect();
+            assert!(matches!(connection.recv().await, Some(ServerMessage::Ready { .. })));
+
+            let mut subs = Vec::new();
+            while let Some(msg) = server.next_message().now_or_never().flatten() {
+                if let ClientMessage::Sub { id, name, .. } = msg {
+                    subs.push((id, name));
+                }
+            }
+            let expected = ("s1".to_string(), "rooms".to_string());
+            assert_eq!(subs, vec![expected.clone(), expected]);
+        });
+    }
+
+    #[test]
+    fn test_offline_queue() {
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            server.on_method("ping", |_| Ok(json!("pong")));
+            let connection = Connection::builder(&server.url())
+                .reconnect(reconnect_every(Duration::from_millis(100)))
+                .offline_queue(1)
+                .connect().await.unwrap();
+            let mut status = connection.status();
+            server.disconnect();
+            while *status.borrow() != ConnectionStatus::Reconnecting {
+                status.changed().await.unwrap();
+            }
+
+            let mut handle = connection.handle();
+            let queued = handle.start_call("ping".to_string(), ()).await.unwrap();
+            let refused = handle.start_call("ping".to_string(), ()).await.unwrap();
+            assert!(matches!(refused.await, Err(Error::Disconnected)));
+            assert_eq!(queued.await.unwrap(), Ok(json!("pong")));
+        });
+    }
+
+    #[test]
+    fn test_builder_connect_over() {
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            let stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+            let mut connection = Connection::builder(&server.url()).max_message_size(1024)
+                .connect_over(stream).await.unwrap();
+            assert_eq!(connection.info().http_status.map(|s| s.as_u16()), Some(101));
+
+            // The websocket settings of the builder apply
+            server.added("logs", "l1", json!({"text": "x".repeat(2048)}));
+            assert!(connection.recv().await.is_none());
+            assert!(matches!(connection.take_error(), Some(Error::MessageTooLarge { max_size: 1024, .. })));
+        });
+    }
+
+    #[test]
+    fn test_builder_connect_with_transport() {
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            let (ws, _) = async_tungstenite::tokio::connect_async(server.url()).await.unwrap();
+            let mut connection = Connection::builder(&server.url()).pending_call_limit(1)
+                .connect_with_transport(transport::websocket(ws)).await.unwrap();
+            let _stuck = connection.method("sleep".to_string(), ()).await.unwrap();
+            let refused = connection.method("sleep".to_string(), ()).await.unwrap();
+            assert!(matches!(refused.finished().await, Err(Error::TooManyPendingCalls)));
+        });
+    }
+
+
+    #[test]
+    fn test_unknown_frames() {
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            let mut connection = Connection::connect(&server.url()).await.unwrap();
+
+            // Frames without a message type are skipped, unknown messages passed on
+            server.send(ServerMessage::Unknown(json!({"server_id": "1"})));
+            server.send(ServerMessage::Unknown(json!({"msg": "teleport"})));
+            assert_eq!(connection.recv().await, Some(ServerMessage::Unknown(json!({"msg": "teleport"}))));
+        });
+    }
+
+    #[test]
+    fn test_batching() {
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            server.on_method("echo", |params| Ok(params[0].clone()));
+
+            let mut connection = Connection::builder(&server.url()).batching(true).connect().await.unwrap();
+            let mut calls = Vec::new();
+            for i in 0..10 {
+                calls.push(connection.method("echo".to_string(), (i,)).await.unwrap());
+            }
+            connection.flush().await.unwrap();
+            for (i, mut call) in calls.into_iter().enumerate() {
+                assert_eq!(call.result().await.unwrap(), Ok(json!(i)));
+            }
+        });
+    }
+
+    #[test]
+    fn test_max_pending_calls() {
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            server.on_method("echo", |params| Ok(params[0].clone()));
+
+            let mut connection = Connection::builder(&server.url()).max_pending_calls(1).connect().await.unwrap();
+            // Never answered, and holding the only slot
+            let stuck = connection.method("sleep".to_string(), ()).await.unwrap();
+            let mut handle = connection.handle();
+            let next = tokio::time::timeout(Duration::from_millis(100), handle.call("echo".to_string(), (1,)));
+            assert!(next.await.is_err());
+
+            drop(stuck);
+            assert_eq!(connection.call("echo".to_string(), (2,)).await.unwrap(), Ok(json!(2)));
+        });
+    }
+
+    #[test]
+    fn test_pending_call_limit() {
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            server.on_method("echo", |params| Ok(params[0].clone()));
+
+            let mut connection = Connection::builder(&server.url()).pending_call_limit(1).connect().await.unwrap();
+            let stuck = connection.method("sleep".to_string(), ()).await.unwrap();
+            assert!(matches!(connection.call("echo".to_string(), (1,)).await, Err(Error::TooManyPendingCalls)));
+
+            drop(stuck);
+            assert_eq!(connection.call("echo".to_string(), (2,)).await.unwrap(), Ok(json!(2)));
+        });
+    }
+
+    #[test]
+    fn test_heartbeat_timeout() {
+        block_on(async {
+            let mut server = MockServer::start().await.unwrap();
+            let connection = Connection::builder(&server.url())
+                .heartbeat(Duration::from_millis(50), Duration::from_millis(50))
+                .reconnect(reconnect_every(Duration::from_secs(10)))
+                .connect().await.unwrap();
+            let mut status = connection.status();
+
+            // Answered pings keep the connection up
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            assert_eq!(*status.borrow(), crate::ConnectionStatus::Connected);
+
+            server.ignore_pings();
+            while *status.borrow() != crate::ConnectionStatus::Reconnecting {
+                status.changed().await.unwrap();
+            }
+            let mut pings = 0;
+            while let Some(msg) = server.next_message().now_or_never().flatten() {
+                if let ClientMessage::Ping { .. } = msg {
+                    pings += 1;
+                }
+            }
+            assert!(pings >= 2);
+        });
+    }
+
+    #[test]
+    fn test_duplicate_id() {
+        struct SameId;
+        impl crate::IdGenerator for SameId {
+            fn method_id(&self) -> String {
+                "m1".to_string()
+            }
+        }
+
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            let mut connection = Connection::builder(&server.url()).ids(SameId).connect().await.unwrap();
+            let first = connection.handle().start_call("sleep".to_string(), ()).await.unwrap();
+            let second = connection.handle().start_call("sleep".to_string(), ()).await.unwrap();
+            assert!(matches!(second.await, Err(Error::DuplicateId(id)) if id == "m1"));
+
+            // The first call is still tracked, and gets its result
+            assert_eq!(connection.handle().pending_calls().await.unwrap().len(), 1);
+            server.reply("m1", Ok(json!(1)));
+            assert_eq!(first.await.unwrap(), Ok(json!(1)));
+        });
+    }
+
+    #[test]
+    fn test_document_filter() {
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            let mut connection = Connection::builder(&server.url())
+                .document_filter(|collection, id| collection == "rooms" && id != "r1")
+                .connect().await.unwrap();
+
+            server.added("users", "u1", json!({"name": "bob"}));
+            server.added("rooms", "r1", json!({"name": "general"}));
+            server.added("rooms", "r2", json!({"name": "random"}));
+            match connection.recv().await {
+                Some(ServerMessage::Added { id, .. }) => assert_eq!(id, "r2"),
+                other => panic!("unexpected {:?}", other),
+            }
+
+            server.changed("rooms", "r1", json!({"topic": "rust"}), vec![]);
+            server.changed("rooms", "r2", json!({"topic": "rust"}), vec![]);
+            match connection.recv().await {
+                Some(ServerMessage::Changed { id, fields: Some(fields), .. }) => {
+                    assert_eq!(id, "r2");
+                    assert_eq!(fields.parse::<Value>().unwrap(), json!({"topic": "rust"}));
+                },
+                other => panic!("unexpected {:?}", other),
+            }
+        });
+    }
+
+    #[test]
+    fn test_boxed_stream() {
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            server.on_method("echo", |params| Ok(params[0].clone()));
+            let connection = Connection::connect(&server.url()).await.unwrap();
+
+            let (messages, mut handle) = connection.split();
+            let mut messages: futures::stream::BoxStream<'static, ServerMessage> = Box::pin(messages);
+            let receiver = tokio::spawn(async move { messages.next().await });
+            server.added("rooms", "r1", json!({}));
+            assert!(matches!(receiver.await.unwrap(), Some(ServerMessage::Added { .. })));
+            assert_eq!(handle.call("echo".to_string(), (1,)).await.unwrap(), Ok(json!(1)));
+        });
+    }
+
+    #[test]
+    fn test_message_stream() {
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            let mut connection = Connection::connect(&server.url()).await.unwrap();
+            let mut handle = connection.handle();
+            let first = handle.message_stream().await.unwrap();
+            let second = handle.message_stream().await.unwrap();
+            futures::pin_mut!(first, second);
+
+            server.added("rooms", "r1", json!({}));
+            for msg in [connection.recv().await, first.next().await, second.next().await] {
+                assert!(matches!(msg, Some(ServerMessage::Added { id, .. }) if id == "r1"));
+            }
+
+            // The taps outlive the inbound stream
+            let (messages, _handle) = connection.split();
+            drop(messages);
+            server.added("rooms", "r2", json!({}));
+            server.added("rooms", "r3", json!({}));
+            for id in ["r2", "r3"] {
+                assert!(matches!(first.next().await, Some(ServerMessage::Added { id: got, .. }) if got == id));
+            }
+        });
+    }
+
+    #[test]
+    fn test_call_with_retry() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            let calls = AtomicUsize::new(0);
+            server.on_method("flaky", move |_| match calls.fetch_add(1, Ordering::Relaxed) {
+                0 | 1 => Err(RPCError(json!({"error": "too-many-requests", "details": {"timeToReset": 10}}))),
+                _ => Ok(json!("done")),
+            });
+            let mut connection = Connection::connect(&server.url()).await.unwrap();
+
+            let policy = crate::RetryPolicy { delay: Duration::from_millis(1), ..Default::default() };
+            assert_eq!(connection.call_with_retry("flaky".to_string(), (), &policy).await.unwrap(), Ok(json!("done")));
+
+            // Other errors are returned right away
+            server.on_method("check", |_| Err(RPCError(json!({"error": 400, "reason": "Match failed"}))));
+            assert!(connection.call_with_retry("check".to_string(), (), &policy).await.unwrap().is_err());
+        });
+    }
+
+    #[test]
+    fn test_max_message_size() {
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            let mut connection = Connection::builder(&server.url()).max_message_size(1024).connect().await.unwrap();
+
+            server.added("logs", "l1", json!({"text": "x".repeat(2048)}));
+            assert!(connection.recv().await.is_none());
+            assert!(matches!(connection.take_error(), Some(Error::MessageTooLarge { max_size: 1024, .. })));
+        });
+    }
+
+    #[test]
+    fn test_call_wait() {
+        async fn next_call(server: &mut MockServer) -> (String, String) {
+            loop {
+                if let Some(ClientMessage::Method { id, method, .. }) = server.next_message().await {
+                    return (id, method)
+                }
+            }
+        }
+        async fn quiet(server: &mut MockServer) -> bool {
+            tokio::time::timeout(Duration::from_millis(100), server.next_message()).await.is_err()
+        }
+
+        block_on(async {
+            let mut server = MockServer::start().await.unwrap();
+            server.on_method("echo", |params| Ok(params[0].clone()));
+            let connection = Connection::connect(&server.url()).await.unwrap();
+            let (mut a, mut b, mut c) = (connection.handle(), connection.handle(), connection.handle());
+
+            let driver = async {
+                let (slow, method) = next_call(&mut server).await;
+                assert_eq!(method, "slow");
+                // login waits for the calls before it...
+                assert!(quiet(&mut server).await);
+                server.reply(&slow, Ok(json!(0)));
+                let (login, method) = next_call(&mut server).await;
+                assert_eq!(method, "login");
+                // ...and holds back the calls after it
+                assert!(quiet(&mut server).await);
+                server.reply(&login, Ok(json!("user")));
+                assert_eq!(next_call(&mut server).await.1, "echo");
+            };
+            let (slow, login, echo, ()) = futures::join!(
+                a.call("slow".to_string(), ()),
+                b.call_wait("login".to_string(), ()),
+                c.call("echo".to_string(), (1,)),
+                driver,
+            );
+            assert_eq!(slow.unwrap(), Ok(json!(0)));
+            assert_eq!(login.unwrap(), Ok(json!("user")));
+            assert_eq!(echo.unwrap(), Ok(json!(1)));
+        });
+    }
+
+    #[test]
+    fn test_worker_handle() {
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            let mut connection = Connection::connect(&server.url()).await.unwrap();
+            let worker = connection.take_worker().unwrap();
+            assert!(connection.take_worker().is_none());
+            connection.handle().close().await.unwrap();
+            assert!(worker.await.is_ok());
+
+            let mut connection = Connection::connect(&server.url()).await.unwrap();
+            let worker = connection.take_worker().unwrap();
+            worker.abort();
+            assert!(matches!(worker.await, Err(Error::Closed)));
+            assert_eq!(connection.recv().await, None);
+        });
+    }
+
+    #[test]
+    fn test_take_error() {
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            let mut connection = Connection::builder(&server.url())
+                .channel_capacity(1, 16)
+                .overflow_policy(OverflowPolicy::Error)
+                .connect().await.unwrap();
+            for i in 0..3 {
+                server.added("tasks", &i.to_string(), json!({}));
+            }
+            while connection.recv().await.is_some() {}
+            assert!(matches!(connection.take_error(), Some(Error::Overflow)));
+            assert!(connection.take_error().is_none());
+        });
+    }
+
+    #[test]
+    fn test_connect_over() {
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            server.on_method("echo", |params| Ok(params[0].clone()));
+            let stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+            let mut connection = Connection::connect_over(stream, server.url().as_str()).await.unwrap();
+            assert_eq!(connection.info().http_status.map(|s| s.as_u16()), Some(101));
+            assert_eq!(connection.call("echo".to_string(), ("hi",)).await.unwrap(), Ok(json!("hi")));
+        });
+    }
+
+}
@@ -0,0 +1,157 @@
+//! Mapping structs to the `fields` and `cleared` of DDP document messages.
+//!
+//! With the `derive` feature, `#[derive(DdpDocument)]` implements
+//! [`DdpDocument`] field by field, using the helpers of this module.
+
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use crate::error::Result;
+use crate::protocol::{Fields, ServerMessage};
+
+pub use crate::cache::Document;
+
+/// A struct kept in sync with a document through `added` and `changed`
+/// messages. Fields absent from a document, or cleared, read as `null`,
+/// so they had better be `Option`s.
+pub trait DdpDocument: Sized {
+
+    /// Build the struct from the fields of an `added` message.
+    fn from_fields(fields: &Document) -> Result<Self>;
+
+    /// Apply a `changed` message, which only carries the modified fields.
+    fn apply_changes(&mut self, fields: &Document, cleared: &[String]) -> Result<()>;
+
+    /// The fields of the document, as in an `added` message. `null` fields are left out.
+    fn to_fields(&self) -> Result<Document>;
+
+    /// What changed since `old`, as in a `changed` message.
+    fn changes_from(&self, old: &Self) -> Result<Changes>;
+
+    /// Update the struct with a document message about it. Returns false if
+    /// the message is not an `added` or `changed` one.
+    fn apply(&mut self, msg: &ServerMessage) -> Result<bool> {
+        match msg {
+            ServerMessage::Added { fields, .. } | ServerMessage::AddedBefore { fields, .. } => {
+                *self = Self::from_fields(&object(fields))?;
+            },
+            ServerMessage::Changed { fields, cleared, .. } => {
+                self.apply_changes(&object(fields), cleared.as_deref().unwrap_or_default())?;
+            },
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+}
+
+fn object(fields: &Option<Fields>) -> Document {
+    fields.as_ref().map(Fields::to_object).unwrap_or_default()
+}
+
+/// The differences between two states of a document.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Changes {
+    /// The fields with a new value.
+    pub fields: Document,
+    /// The fields that became `null`.
+    pub cleared: Vec<String>,
+}
+
+impl Changes {
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty() && self.cleared.is_empty()
+    }
+
+    /// Record the change of a field, if its value differs.
+    pub fn diff_field<T: Serialize>(&mut self, name: &str, new: &T, old: &T) -> Result<()> {
+        let (new, old) = (serde_json::to_value(new)?, serde_json::to_value(old)?);
+        if new == old {
+            return Ok(())
+        }
+        match new {
+            Value::Null => self.cleared.push(name.to_string()),
+            new => { self.fields.insert(name.to_string(), new); },
+        }
+        Ok(())
+    }
+
+    /// The `changed` message describing these changes.
+    pub fn into_message(self, collection: &str, id: &str) -> ServerMessage {
+        ServerMessage::Changed {
+            collection: collection.into(),
+            id: id.into(),
+            fields: Some(self.fields.into()),
+            cleared: Some(self.cleared),
+        }
+    }
+
+}
+
+/// Read a field from EJSON. An absent field reads as `null`.
+pub fn read_field<T: DeserializeOwned>(fields: &Document, name: &str) -> Result<T> {
+    read_value(fields.get(name).unwrap_or(&Value::Null), name)
+}
+
+/// Read the value of a field from EJSON.
+pub fn read_value<T: DeserializeOwned>(value: &Value, name: &str) -> Result<T> {
+    crate::ejson::from_value(value.clone())
+        .map_err(|e| serde::de::Error::custom(format!("field `{}`: {}", name, e)))
+        .map_err(crate::error::Error::Json)
+}
+
+/// The value of a cleared field.
+pub fn cleared_field<T: DeserializeOwned>(name: &str) -> Result<T> {
+    read_value(&Value::Null, name)
+}
+
+/// Write a field, unless it is `null`.
+pub fn write_field<T: Serialize>(fields: &mut Document, name: &str, value: &T) -> Result<()> {
+    match serde_json::to_value(value)? {
+        Value::Null => (),
+        value => { fields.insert(name.to_string(), value); },
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "derive"))]
+mod tests {
+
+    use super::*;
+    use crate::DdpDocument;
+    use serde_json::json;
+
+    #[derive(Debug, Default, PartialEq, DdpDocument)]
+    struct Room {
+        name: String,
+        topic: Option<String>,
+        #[ddp(rename = "usersCount")]
+        users: u32,
+        #[ddp(skip)]
+        unread: bool,
+    }
+
+    fn fields(value: Value) -> Document {
+        match value {
+            Value::Object(fields) => fields,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_derive() {
+        let mut room = Room::from_fields(&fields(json!({"name": "general", "usersCount": 3}))).unwrap();
+        assert_eq!(room, Room { name: "general".to_string(), topic: None, users: 3, unread: false });
+        assert!(Room::from_fields(&fields(json!({"topic": "rust"}))).is_err());
+
+        let old = Room { name: "general".to_string(), topic: None, users: 3, unread: true };
+        room.apply_changes(&fields(json!({"topic": "rust"})), &[]).unwrap();
+        assert_eq!(room.topic.as_deref(), Some("rust"));
+        assert_eq!(room.to_fields().unwrap(), fields(json!({"name": "general", "topic": "rust", "usersCount": 3})));
+        assert_eq!(old.changes_from(&room).unwrap(), Changes { fields: Document::new(), cleared: vec!["topic".to_string()] });
+
+        room.apply_changes(&Document::new(), &["topic".to_string()]).unwrap();
+        assert_eq!(room.topic, None);
+    }
+
+}
@@ -0,0 +1,251 @@
+//! Meteor's [EJSON](https://docs.meteor.com/api/ejson.html) represents types
+//! that JSON lacks as objects with `$`-prefixed keys. The wrappers below
+//! (de)serialize to these representations, so they can be used as field types
+//! in documents and method parameters.
+
+use serde::{Serialize, Deserialize, Serializer, Deserializer, de::{self, DeserializeOwned}};
+use serde_json::{Map, Value};
+use crate::base64;
+
+/// Dates are `{"$date": millis}`.
+pub use crate::protocol::Timestamp as Date;
+
+/// Binary data, as `{"$binary": "<base64>"}`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Binary(pub Vec<u8>);
+
+#[derive(Serialize, Deserialize)]
+struct RawBinary {
+    #[serde(rename="$binary")]
+    data: String,
+}
+
+impl Serialize for Binary {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        RawBinary { data: base64::encode(&self.0) }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Binary {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = RawBinary::deserialize(deserializer)?;
+        base64::decode(&raw.data)
+            .map(Binary)
+            .ok_or_else(|| de::Error::custom("invalid base64 in $binary"))
+    }
+}
+
+/// A Mongo ObjectID, as `{"$type": "oid", "$value": "<hex>"}`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ObjectId(pub String);
+
+#[derive(Serialize, Deserialize)]
+struct RawCustom<'a> {
+    #[serde(rename="$type")]
+    kind: &'a str,
+    #[serde(rename="$value")]
+    value: Value,
+}
+
+impl Serialize for ObjectId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        RawCustom { kind: "oid", value: Value::String(self.0.clone()) }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ObjectId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Raw {
+            #[serde(rename="$type")]
+            kind: String,
+            #[serde(rename="$value")]
+            value: String,
+        }
+        let raw = Raw::deserialize(deserializer)?;
+        if raw.kind != "oid" {
+            return Err(de::Error::custom(format!("expected an oid, got {}", raw.kind)))
+        }
+        Ok(ObjectId(raw.value))
+    }
+}
+
+/// A decimal number from Meteor's `mongo-decimal` package, as
+/// `{"$type": "Decimal", "$value": "<digits>"}`. The digits are kept as
+/// sent, so that no precision is lost; with the `rust_decimal` feature,
+/// it converts to and from [`rust_decimal::Decimal`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Decimal(pub String);
+
+impl Serialize for Decimal {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        RawCustom { kind: "Decimal", value: Value::String(self.0.clone()) }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Decimal {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Raw {
+            #[serde(rename="$type")]
+            kind: String,
+            #[serde(rename="$value")]
+            value: String,
+        }
+        let raw = Raw::deserialize(deserializer)?;
+        if raw.kind != "Decimal" {
+            return Err(de::Error::custom(format!("expected a Decimal, got {}", raw.kind)))
+        }
+        Ok(Decimal(raw.value))
+    }
+}
+
+#[cfg(feature = "rust_decimal")]
+impl From<rust_decimal::Decimal> for Decimal {
+    fn from(decimal: rust_decimal::Decimal) -> Self {
+        Decimal(decimal.to_string())
+    }
+}
+
+#[cfg(feature = "rust_decimal")]
+impl std::convert::TryFrom<Decimal> for rust_decimal::Decimal {
+    type Error = rust_decimal::Error;
+
+    fn try_from(decimal: Decimal) -> Result<Self, Self::Error> {
+        decimal.0.parse()
+    }
+}
+
+/// A number that may be infinite or NaN, which EJSON sends as `{"$InfNaN": sign}`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub struct Float(pub f64);
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RawFloat {
+    Finite(f64),
+    Special {
+        #[serde(rename="$InfNaN")]
+        sign: i8,
+    },
+}
+
+impl Serialize for Float {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let raw = match self.0 {
+            f if f.is_nan() => RawFloat::Special { sign: 0 },
+            f if f.is_infinite() => RawFloat::Special { sign: f.signum() as i8 },
+            f => RawFloat::Finite(f),
+        };
+        raw.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Float {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Float(match RawFloat::deserialize(deserializer)? {
+            RawFloat::Finite(f) => f,
+            RawFloat::Special { sign } if sign > 0 => f64::INFINITY,
+            RawFloat::Special { sign } if sign < 0 => f64::NEG_INFINITY,
+            RawFloat::Special { .. } => f64::NAN,
+        }))
+    }
+}
+
+/// A regular expression, as `{"$regexp": pattern, "$flags": flags}`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Regex {
+    #[serde(rename="$regexp")]
+    pub pattern: String,
+    #[serde(rename="$flags")]
+    pub flags: String,
+}
+
+/// Whether an object has the shape of an EJSON special type.
+fn is_special(map: &Map<String, Value>) -> bool {
+    let has = |key| map.contains_key(key);
+    match map.len() {
+        1 => has("$date") || has("$binary") || has("$InfNaN") || has("$escape"),
+        2 => (has("$regexp") && has("$flags")) || (has("$type") && has("$value")),
+        _ => false,
+    }
+}
+
+/// Remove the `{"$escape": ...}` wrappers EJSON puts around plain objects
+/// that would otherwise be mistaken for special types.
+pub fn unescape(value: Value) -> Value {
+    match value {
+        Value::Object(mut map) => {
+            if map.len() == 1 {
+                if let Some(Value::Object(inner)) = map.remove("$escape") {
+                    return Value::Object(inner.into_iter().map(|(k, v)| (k, unescape(v))).collect())
+                }
+            }
+            Value::Object(map.into_iter().map(|(k, v)| (k, unescape(v))).collect())
+        },
+        Value::Array(values) => Value::Array(values.into_iter().map(unescape).collect()),
+        other => other,
+    }
+}
+
+/// Wrap plain objects that look like EJSON special types in `{"$escape": ...}`.
+/// This is meant for raw JSON data; values produced by the wrappers in this
+/// module are already proper EJSON, and must not be escaped.
+pub fn escape(value: Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let special = is_special(&map);
+            let map: Map<String, Value> = map.into_iter().map(|(k, v)| (k, escape(v))).collect();
+            if special {
+                let mut wrapper = Map::new();
+                wrapper.insert("$escape".to_string(), Value::Object(map));
+                Value::Object(wrapper)
+            } else {
+                Value::Object(map)
+            }
+        },
+        Value::Array(values) => Value::Array(values.into_iter().map(escape).collect()),
+        other => other,
+    }
+}
+
+/// Deserialize an EJSON document, such as the `fields` of an `added` message.
+pub fn from_value<T: DeserializeOwned>(value: Value) -> serde_json::Result<T> {
+    serde_json::from_value(unescape(value))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use serde_json::json;
+
+    fn roundtrip<T>(t: T, json: Value)
+        where T: Serialize + DeserializeOwned + PartialEq + std::fmt::Debug
+    {
+        assert_eq!(serde_json::to_value(&t).unwrap(), json);
+        assert_eq!(from_value::<T>(json).unwrap(), t);
+    }
+
+    #[test]
+    fn test_types() {
+        roundtrip(Binary(b"hello".to_vec()), json!({"$binary": "aGVsbG8="}));
+        roundtrip(ObjectId("5f1e2d".to_string()), json!({"$type": "oid", "$value": "5f1e2d"}));
+        roundtrip(Decimal("1.23".to_string()), json!({"$type": "Decimal", "$value": "1.23"}));
+        roundtrip(Float(1.5), json!(1.5));
+        roundtrip(Float(f64::NEG_INFINITY), json!({"$InfNaN": -1}));
+        roundtrip(Regex { pattern: "^a".to_string(), flags: "i".to_string() }, json!({"$regexp": "^a", "$flags": "i"}));
+        assert!(from_value::<Float>(json!({"$InfNaN": 0})).unwrap().0.is_nan());
+    }
+
+    #[test]
+    fn test_escape() {
+        let raw = json!({"a": {"$date": "not a date"}, "b": [{"$type": 1, "$value": 2}], "c": {"$x": 1}});
+        let escaped = json!({"a": {"$escape": {"$date": "not a date"}},
+                             "b": [{"$escape": {"$type": 1, "$value": 2}}],
+                             "c": {"$x": 1}});
+        assert_eq!(escape(raw.clone()), escaped);
+        assert_eq!(unescape(escaped), raw);
+    }
+
+}
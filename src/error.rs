@@ -0,0 +1,330 @@
+use async_tungstenite::tungstenite;
+use futures::channel::{mpsc, oneshot};
+use serde_json::Value;
+use std::convert::TryFrom;
+use std::time::Duration;
+use thiserror::Error;
+use crate::connection::RPCError;
+use crate::transport::CloseReason;
+
+/// Everything that can go wrong in siderite, apart from RPC calls
+/// failing on the server side (see [`crate::connection::RPCError`]).
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The TLS configuration could not be set up.
+    #[error("TLS setup failed: {0}")]
+    Tls(#[source] std::io::Error),
+
+    /// The tunnel through the configured proxy could not be established.
+    #[error("proxy error: {0}")]
+    Proxy(#[source] std::io::Error),
+
+    /// The websocket failed. Connections created from an url are
+    /// re-established when this happens.
+    #[error("websocket error: {0}")]
+    WebSocket(#[source] Box<tungstenite::Error>),
+
+    /// The server closed the websocket. Connections created from an url are
+    /// re-established when this happens, unless the server closed it
+    /// normally, see [`CloseReason::is_normal`].
+    #[error("websocket closed by the server ({0})")]
+    ClosedByServer(CloseReason),
+
+    /// The server did not complete the DDP handshake as expected.
+    #[error("DDP handshake failed: {0}")]
+    Handshake(String),
+
+    /// The server supports none of our DDP versions. It suggested the
+    /// one it would rather speak.
+    #[error("DDP version mismatch, server suggests version {suggested}")]
+    VersionMismatch { suggested: String },
+
+    /// A message could not be (de)serialized.
+    #[error("serialization error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// The server sent something that is not valid DDP.
+    #[error("protocol error: {0}")]
+    Protocol(String),
+
+    /// The server stopped answering our heartbeats.
+    #[error("server did not answer our ping")]
+    HeartbeatTimeout,
+
+    /// The server sent a message beyond the size limits, see
+    /// [`crate::ConnectionBuilder::max_message_size`].
+    #[error("message of {size} bytes exceeds the limit of {max_size} bytes")]
+    MessageTooLarge { size: usize, max_size: usize },
+
+    /// The consumer did not keep up with inbound messages, under [`crate::OverflowPolicy::Error`].
+    #[error("inbound message channel overflowed")]
+    Overflow,
+
+    /// The connection could not be established in time.
+    #[error("connection timed out")]
+    ConnectTimeout,
+
+    /// A method call did not complete in time.
+    #[error("RPC call timed out")]
+    Timeout,
+
+    /// The link was lost before the call could complete.
+    #[error("connection lost before the call completed")]
+    Disconnected,
+
+    /// The call was refused, as the connection already tracks as many calls
+    /// as allowed by [`crate::ConnectionBuilder::pending_call_limit`].
+    #[error("too many method calls waiting for their result")]
+    TooManyPendingCalls,
+
+    /// The id generator gave the call the id of another call still waiting
+    /// for its result, see [`crate::ConnectionBuilder::ids`].
+    #[error("method id {0} is already in use")]
+    DuplicateId(String),
+
+    /// The call was aborted with [`crate::Handle::abort_pending`].
+    #[error("method call cancelled")]
+    Cancelled,
+
+    /// The connection is closed, and its worker has terminated.
+    #[error("connection closed")]
+    Closed,
+}
+
+impl Error {
+
+    /// The HTTP response of the server, if it refused to upgrade to a
+    /// websocket, for instance a 403 from a gateway.
+    pub fn http_response(&self) -> Option<&tungstenite::http::Response<Option<String>>> {
+        match self {
+            Error::WebSocket(e) => match &**e {
+                tungstenite::Error::Http(response) => Some(response),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Whether the same request may succeed if tried again: the link failed
+    /// or timed out. Calls may have reached the server nonetheless, so only
+    /// idempotent ones should be retried.
+    pub fn is_retryable(&self) -> bool {
+        self.is_link_failure() || matches!(self, Error::ConnectTimeout | Error::Timeout | Error::Disconnected)
+    }
+
+    /// Errors after which the DDP session may be resumed on a new websocket.
+    pub(crate) fn is_link_failure(&self) -> bool {
+        match self {
+            Error::ClosedByServer(reason) => !reason.is_normal(),
+            _ => matches!(self, Error::WebSocket(_) | Error::HeartbeatTimeout),
+        }
+    }
+
+}
+
+impl From<tungstenite::Error> for Error {
+    fn from(e: tungstenite::Error) -> Self {
+        match e {
+            tungstenite::Error::Capacity(tungstenite::error::CapacityError::MessageTooLong { size, max_size }) => {
+                Error::MessageTooLarge { size, max_size }
+            },
+            e => Error::WebSocket(Box::new(e)),
+        }
+    }
+}
+
+impl From<mpsc::SendError> for Error {
+    fn from(_: mpsc::SendError) -> Self {
+        Error::Closed
+    }
+}
+
+impl From<oneshot::Canceled> for Error {
+    fn from(_: oneshot::Canceled) -> Self {
+        Error::Disconnected
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Everything that can go wrong with a typed method call,
+/// see [`crate::Handle::call_typed`].
+#[derive(Debug, Error)]
+pub enum MethodError {
+    /// The call could not be performed.
+    #[error(transparent)]
+    Call(#[from] Error),
+
+    /// The server answered with an error.
+    #[error(transparent)]
+    Rpc(#[from] RPCError),
+
+    /// The result did not have the expected type.
+    #[error("unexpected method result: {0}")]
+    Decode(#[source] serde_json::Error),
+}
+
+/// The code of a [`MeteorError`]: an HTTP-like status such as `403`, or a
+/// name such as `"too-many-requests"`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    Status(i64),
+    Name(String),
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorCode::Status(status) => write!(f, "{}", status),
+            ErrorCode::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+/// A `Meteor.Error` thrown by a method or publication, decoded from the
+/// [`RPCError`] carrying it:
+///
+/// ```ignore
+/// match handle.call("send".to_string(), params).await? {
+///     Err(e) => match MeteorError::try_from(e) {
+///         Ok(e) if e.error == ErrorCode::Name("too-many-requests".to_string()) => retry_later(),
+///         Ok(e) if e.error == ErrorCode::Status(403) => forbidden(e.reason),
+///         ...
+///     },
+///     ...
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MeteorError {
+    pub error: ErrorCode,
+    /// The message meant for the user.
+    pub reason: Option<String>,
+    /// Anything else the server gave.
+    pub details: Option<Value>,
+    /// Whether the error was thrown as is by the server code, rather than
+    /// replaced by a generic 500 error.
+    pub is_client_safe: bool,
+}
+
+impl TryFrom<RPCError> for MeteorError {
+    /// Errors without an `error` code are given back as they are.
+    type Error = RPCError;
+
+    fn try_from(e: RPCError) -> std::result::Result<Self, RPCError> {
+        let error = match e.0.get("error") {
+            Some(Value::Number(n)) if n.is_i64() => n.as_i64().map(ErrorCode::Status),
+            Some(Value::String(name)) => Some(ErrorCode::Name(name.clone())),
+            _ => None,
+        };
+        let error = match error {
+            Some(error) => error,
+            None => return Err(e),
+        };
+        let details = e.0.get("details").filter(|details| !details.is_null()).cloned();
+        Ok(MeteorError {
+            error,
+            reason: e.0.get("reason").and_then(Value::as_str).map(String::from),
+            details,
+            is_client_safe: matches!(e.0.get("isClientSafe"), Some(Value::Bool(true))),
+        })
+    }
+}
+
+/// Formatted as Meteor does, e.g. `Access denied [403]`.
+impl std::fmt::Display for MeteorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.reason {
+            Some(reason) => write!(f, "{} [{}]", reason, self.error),
+            None => write!(f, "[{}]", self.error),
+        }
+    }
+}
+
+impl std::error::Error for MeteorError {}
+
+impl MeteorError {
+
+    fn is(&self, status: i64, name: &str) -> bool {
+        match &self.error {
+            ErrorCode::Status(s) => *s == status,
+            ErrorCode::Name(n) => n == name,
+        }
+    }
+
+    /// A 401 or 403 error, or `not-authorized`, as when logged out.
+    pub fn is_not_authorized(&self) -> bool {
+        self.is(403, "not-authorized") || self.is(401, "not-authorized")
+    }
+
+    /// The `too-many-requests` error of Meteor's DDP rate limiter.
+    pub fn is_rate_limited(&self) -> bool {
+        self.is(429, "too-many-requests")
+    }
+
+    /// A 400 error, as thrown by `check()`, or `validation-error`.
+    pub fn is_validation_error(&self) -> bool {
+        self.is(400, "validation-error")
+    }
+
+    /// A 404 error, or `not-found`, as for unknown methods.
+    pub fn is_not_found(&self) -> bool {
+        self.is(404, "not-found")
+    }
+
+    /// Whether trying again later may succeed: rate limiting, and gateway
+    /// errors. Internal errors (500) are not, as they tend to happen again.
+    pub fn is_retryable(&self) -> bool {
+        self.is_rate_limited() || matches!(self.error, ErrorCode::Status(502..=504))
+    }
+
+    /// How long the rate limiter asks to wait, from `timeToReset`.
+    pub fn retry_after(&self) -> Option<Duration> {
+        let millis = self.details.as_ref()?.get("timeToReset")?.as_f64()?;
+        Some(Duration::from_millis(millis.max(0.0) as u64))
+    }
+
+}
+
+impl MethodError {
+
+    /// The Meteor error thrown by the server, if that is what happened.
+    pub fn meteor_error(&self) -> Option<MeteorError> {
+        match self {
+            MethodError::Rpc(e) => MeteorError::try_from(e.clone()).ok(),
+            _ => None,
+        }
+    }
+
+    /// Whether calling again may succeed, see [`Error::is_retryable`]
+    /// and [`MeteorError::is_retryable`].
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            MethodError::Call(e) => e.is_retryable(),
+            MethodError::Rpc(_) => matches!(self.meteor_error(), Some(e) if e.is_retryable()),
+            MethodError::Decode(_) => false,
+        }
+    }
+
+}
+
+#[test]
+fn test_meteor_error() {
+    use serde_json::json;
+
+    let e = MeteorError::try_from(RPCError(json!({
+        "isClientSafe": true, "error": 403, "reason": "Access denied", "message": "Access denied [403]", "errorType": "Meteor.Error"
+    }))).unwrap();
+    assert_eq!((&e.error, e.is_client_safe), (&ErrorCode::Status(403), true));
+    assert_eq!(e.to_string(), "Access denied [403]");
+
+    let e = MeteorError::try_from(RPCError(json!({"error": "too-many-requests", "details": {"timeToReset": 500}}))).unwrap();
+    assert_eq!(e.error, ErrorCode::Name("too-many-requests".to_string()));
+    assert_eq!(e.details, Some(json!({"timeToReset": 500})));
+
+    assert!(e.is_rate_limited() && e.is_retryable());
+    assert_eq!(e.retry_after(), Some(Duration::from_millis(500)));
+
+    assert!(MeteorError::try_from(RPCError(json!("boom"))).is_err());
+    assert!(!MethodError::Rpc(RPCError(json!({"error": 400, "reason": "Match failed"}))).is_retryable());
+    assert!(MethodError::Call(Error::Timeout).is_retryable());
+}
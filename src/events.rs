@@ -0,0 +1,148 @@
+//! Callbacks for server messages, an alternative to matching on the
+//! messages of the inbound stream. See [`crate::Handle::on_added`].
+
+use serde_json::Value;
+use std::collections::HashMap;
+use crate::cache::Document;
+use crate::protocol::{Fields, ServerMessage};
+
+type Added = Box<dyn FnMut(&str, &Document) + Send>;
+type Changed = Box<dyn FnMut(&str, &Document, &[String]) + Send>;
+type Removed = Box<dyn FnMut(&str) + Send>;
+type Ready = Box<dyn FnMut(&[String]) + Send>;
+type Nosub = Box<dyn FnMut(&str, Option<&Value>) + Send>;
+
+/// The fields of a document message, decoded once for all its handlers.
+fn document(fields: &Option<Fields>) -> Document {
+    fields.as_ref().map(Fields::to_object).unwrap_or_default()
+}
+
+/// The callbacks registered on a connection, called by its worker.
+#[derive(Default)]
+pub(crate) struct Handlers {
+    pub(crate) added: HashMap<String, Vec<Added>>,
+    pub(crate) changed: HashMap<String, Vec<Changed>>,
+    pub(crate) removed: HashMap<String, Vec<Removed>>,
+    pub(crate) ready: Vec<Ready>,
+    pub(crate) nosub: Vec<Nosub>,
+}
+
+impl Handlers {
+
+    /// Call the handlers of a message. Returns false if there are none.
+    pub(crate) fn dispatch(&mut self, msg: &ServerMessage) -> bool {
+        match msg {
+            ServerMessage::Added { collection, id, fields: f } |
+            ServerMessage::AddedBefore { collection, id, fields: f, .. } => {
+                let handlers = match self.added.get_mut(collection.as_str()) {
+                    Some(handlers) => handlers,
+                    None => return false,
+                };
+                let fields = document(f);
+                handlers.iter_mut().for_each(|h| h(id, &fields));
+            },
+            ServerMessage::Changed { collection, id, fields: f, cleared } => {
+                let handlers = match self.changed.get_mut(collection.as_str()) {
+                    Some(handlers) => handlers,
+                    None => return false,
+                };
+                let fields = document(f);
+                let cleared = cleared.as_deref().unwrap_or_default();
+                handlers.iter_mut().for_each(|h| h(id, &fields, cleared));
+            },
+            ServerMessage::Removed { collection, id } => match self.removed.get_mut(collection.as_str()) {
+                Some(handlers) => handlers.iter_mut().for_each(|h| h(id)),
+                None => return false,
+            },
+            ServerMessage::Ready { subs } if !self.ready.is_empty() => {
+                self.ready.iter_mut().for_each(|h| h(subs));
+            },
+            ServerMessage::Nosub { id, error } if !self.nosub.is_empty() => {
+                self.nosub.iter_mut().for_each(|h| h(id, error.as_ref()));
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    /// Add the handlers of `other` after ours.
+    pub(crate) fn append(&mut self, other: Handlers) {
+        for (collection, handlers) in other.added {
+            self.added.entry(collection).or_default().extend(handlers);
+        }
+        for (collection, handlers) in other.changed {
+            self.changed.entry(collection).or_default().extend(handlers);
+        }
+        for (collection, handlers) in other.removed {
+            self.removed.entry(collection).or_default().extend(handlers);
+        }
+        self.ready.extend(other.ready);
+        self.nosub.extend(other.nosub);
+    }
+
+}
+
+impl std::fmt::Debug for Handlers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Handlers")
+            .field("added", &self.added.keys().collect::<Vec<_>>())
+            .field("changed", &self.changed.keys().collect::<Vec<_>>())
+            .field("removed", &self.removed.keys().collect::<Vec<_>>())
+            .field("ready", &self.ready.len())
+            .field("nosub", &self.nosub.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::Connection;
+    use crate::testing::{block_on, MockServer};
+    use futures::{channel::mpsc, StreamExt};
+    use serde_json::json;
+
+    #[test]
+    fn test_handlers() {
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            let mut connection = Connection::connect(&server.url()).await.unwrap();
+            let tasks = connection.collection_stream("tasks").await.unwrap();
+            futures::pin_mut!(tasks);
+            let (tx, mut rx) = mpsc::unbounded();
+            let added = tx.clone();
+            connection.on_added("tasks", move |id, fields| {
+                let _ = added.unbounded_send(format!("added {} {}", id, fields["title"]));
+            });
+            let removed = tx.clone();
+            connection.on_removed("tasks", move |id| {
+                let _ = removed.unbounded_send(format!("removed {}", id));
+            });
+            // Handlers may register other handlers
+            let handle = connection.handle();
+            connection.on_ready(move |_| {
+                let tx = tx.clone();
+                handle.on_nosub(move |id, _| {
+                    let _ = tx.unbounded_send(format!("nosub {}", id));
+                });
+            });
+
+            server.added("tasks", "t1", json!({"title": "write tests"}));
+            server.removed("tasks", "t1");
+            server.added("notes", "n1", json!({}));
+            assert_eq!(rx.next().await.unwrap(), r#"added t1 "write tests""#);
+            assert_eq!(rx.next().await.unwrap(), "removed t1");
+            // The stream of the collection still gets them
+            assert!(matches!(tasks.next().await, Some(ServerMessage::Added { id, .. }) if id == "t1"));
+            assert!(matches!(tasks.next().await, Some(ServerMessage::Removed { id, .. }) if id == "t1"));
+            // Messages without a handler are still passed on
+            assert!(matches!(connection.recv().await, Some(ServerMessage::Added { id, .. }) if id == "n1"));
+
+            server.send(ServerMessage::Ready { subs: vec!["s1".to_string()] });
+            server.nosub("s1", None);
+            assert_eq!(rx.next().await.unwrap(), "nosub s1");
+        });
+    }
+
+}
@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::sync::{Arc, atomic::{AtomicU64, Ordering}};
+
+/// Produces the ids of method calls and subscriptions. Method ids must be
+/// unique among the calls in flight on a connection: a call given the id of
+/// another fails with [`crate::Error::DuplicateId`].
+pub trait IdGenerator: Send + Sync {
+    fn method_id(&self) -> String;
+
+    fn subscription_id(&self) -> String {
+        self.method_id()
+    }
+}
+
+/// Random ids in the style of Meteor's `Random.id()`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RandomIds;
+
+impl IdGenerator for RandomIds {
+    fn method_id(&self) -> String {
+        random_id()
+    }
+}
+
+/// A fixed prefix followed by a counter, such as `myapp-1`, `myapp-2`...
+#[derive(Debug)]
+pub struct PrefixedIds {
+    prefix: String,
+    counter: AtomicU64,
+}
+
+impl PrefixedIds {
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self { prefix: prefix.into(), counter: AtomicU64::new(0) }
+    }
+}
+
+impl IdGenerator for PrefixedIds {
+    fn method_id(&self) -> String {
+        format!("{}{}", self.prefix, self.counter.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+}
+
+/// The alphabet of Meteor's ids.
+const UNMISTAKABLE: &str = "23456789ABCDEFGHJKLMNPQRSTWXYZabcdefghijkmnopqrstuvwxyz";
+
+/// A random id of 17 characters, as made by Meteor's `Random.id()`.
+pub fn random_id() -> String {
+    let chars = UNMISTAKABLE.as_bytes();
+    (0..17).map(|_| chars[fastrand::usize(..chars.len())] as char).collect()
+}
+
+/// A deterministic generator, compatible with Meteor's `Random.createWithSeeds`:
+/// given the same seeds, both produce the same sequence of ids.
+#[derive(Clone, Debug)]
+pub struct SeededRandom {
+    s0: f64,
+    s1: f64,
+    s2: f64,
+    c: f64,
+}
+
+/// The hash used by Alea to turn seeds into its initial state.
+struct Mash(f64);
+
+impl Mash {
+    fn mash(&mut self, data: &str) -> f64 {
+        // JavaScript's `>>> 0`
+        fn uint32(x: f64) -> f64 {
+            x.trunc().rem_euclid(4_294_967_296.0)
+        }
+        for unit in data.encode_utf16() {
+            self.0 += f64::from(unit);
+            let mut h = 0.025_196_032_824_169_38 * self.0;
+            self.0 = uint32(h);
+            h -= self.0;
+            h *= self.0;
+            self.0 = uint32(h);
+            h -= self.0;
+            self.0 += h * 4_294_967_296.0;
+        }
+        uint32(self.0) * 2.328_306_436_538_696_3e-10
+    }
+}
+
+impl SeededRandom {
+
+    pub fn new<S: AsRef<str>>(seeds: &[S]) -> Self {
+        let mut mash = Mash(4_022_871_197.0);
+        let mut s0 = mash.mash(" ");
+        let mut s1 = mash.mash(" ");
+        let mut s2 = mash.mash(" ");
+        for seed in seeds {
+            for s in [&mut s0, &mut s1, &mut s2] {
+                *s -= mash.mash(seed.as_ref());
+                if *s < 0.0 {
+                    *s += 1.0;
+                }
+            }
+        }
+        Self { s0, s1, s2, c: 1.0 }
+    }
+
+    /// A number in `[0, 1)`.
+    pub fn fraction(&mut self) -> f64 {
+        let t = 2_091_639.0 * self.s0 + self.c * 2.328_306_436_538_696_3e-10;
+        self.s0 = self.s1;
+        self.s1 = self.s2;
+        self.c = t.trunc();
+        self.s2 = t - self.c;
+        self.s2
+    }
+
+    /// A string of `len` characters from `alphabet`.
+    pub fn string(&mut self, len: usize, alphabet: &str) -> String {
+        let chars: Vec<char> = alphabet.chars().collect();
+        (0..len).map(|_| chars[(self.fraction() * chars.len() as f64) as usize]).collect()
+    }
+
+    /// The next id, as `Random.id()`.
+    pub fn id(&mut self) -> String {
+        self.string(17, UNMISTAKABLE)
+    }
+
+}
+
+/// The random streams of a method call, as Meteor's `DDPCommon.RandomStream`.
+/// Given the `randomSeed` of a call, the client and the server derive the
+/// same ids, such as those of the documents inserted by the method.
+#[derive(Clone, Debug)]
+pub struct RandomStream {
+    seed: String,
+    sequences: HashMap<String, SeededRandom>,
+}
+
+impl RandomStream {
+
+    pub fn new(seed: impl Into<String>) -> Self {
+        Self { seed: seed.into(), sequences: HashMap::new() }
+    }
+
+    /// A fresh seed for a method call.
+    pub fn make_seed() -> String {
+        (0..20).map(|_| std::char::from_digit(fastrand::u32(..16), 16).unwrap()).collect()
+    }
+
+    pub fn seed(&self) -> &str {
+        &self.seed
+    }
+
+    /// The sequence with the given name, such as `/collection/rooms`.
+    pub fn sequence(&mut self, name: &str) -> &mut SeededRandom {
+        let seed = &self.seed;
+        self.sequences.entry(name.to_string())
+            .or_insert_with(|| SeededRandom::new(&[seed.as_str(), name]))
+    }
+
+    /// The id of the next document inserted into `collection`.
+    pub fn insert_id(&mut self, collection: &str) -> String {
+        self.sequence(&format!("/collection/{}", collection)).id()
+    }
+
+}
+
+/// The generator of a connection, if one was configured. Without one, method
+/// ids are made by the worker, and subscription ids are random.
+#[derive(Clone, Default)]
+pub(crate) struct Ids(pub Option<Arc<dyn IdGenerator>>);
+
+impl Ids {
+    pub fn subscription_id(&self) -> String {
+        match &self.0 {
+            Some(ids) => ids.subscription_id(),
+            None => random_id(),
+        }
+    }
+}
+
+impl std::fmt::Debug for Ids {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(if self.0.is_some() { "<custom>" } else { "<default>" })
+    }
+}
+
+#[test]
+fn test_prefixed_ids() {
+    let ids = PrefixedIds::new("app-");
+    assert_eq!(ids.method_id(), "app-1");
+    assert_eq!(ids.subscription_id(), "app-2");
+    assert_eq!(random_id().len(), 17);
+}
+
+#[test]
+fn test_seeded_random() {
+    // The values expected by Meteor's own tests
+    let mut random = SeededRandom::new(&["0"]);
+    assert_eq!(random.id(), "cp9hWvhg8GSvuZ9os");
+    assert_eq!(random.id(), "3f3k6Xo7rrHCifQhR");
+    assert_eq!(random.id(), "shxDnjWWmnKPEoLhM");
+    assert_eq!(random.id(), "6QTjB8C5SEqhmz4ni");
+}
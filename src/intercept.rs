@@ -0,0 +1,111 @@
+use serde_json::Value;
+use std::sync::Arc;
+
+/// What to do with an intercepted frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Carry on with the frame, possibly modified.
+    Pass,
+    /// Discard the frame.
+    Drop,
+}
+
+/// Hooks called with the JSON text of every frame exchanged with the server,
+/// for auditing, metrics, rewriting, or protocol extensions.
+/// Register them with [`crate::ConnectionBuilder::interceptor`]; they are called
+/// in order of registration, from the connection's background task, so they
+/// should not block.
+pub trait Interceptor: Send + Sync {
+
+    /// Called before a frame is sent.
+    fn outbound(&self, _frame: &mut String) -> Verdict {
+        Verdict::Pass
+    }
+
+    /// Called when a frame is received, before it is decoded.
+    fn inbound(&self, _frame: &mut String) -> Verdict {
+        Verdict::Pass
+    }
+
+}
+
+/// Rewrites frames before they are logged, to keep secrets out of the logs,
+/// see [`crate::ConnectionBuilder::redact`].
+pub type Redactor = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// A redactor masking the values of the given fields, at any depth of the
+/// JSON of a frame, such as `["password", "resume", "token"]` to hide the
+/// secrets of Meteor logins.
+pub fn mask_fields(fields: &[&str]) -> impl Fn(&str) -> String + Send + Sync + 'static {
+    let fields: Vec<String> = fields.iter().map(|field| field.to_string()).collect();
+    move |frame| {
+        fn mask(value: &mut Value, fields: &[String]) {
+            match value {
+                Value::Object(map) => for (key, value) in map.iter_mut() {
+                    if fields.contains(key) {
+                        *value = Value::String("<redacted>".to_string());
+                    } else {
+                        mask(value, fields);
+                    }
+                },
+                Value::Array(values) => values.iter_mut().for_each(|value| mask(value, fields)),
+                _ => (),
+            }
+        }
+        match serde_json::from_str::<Value>(frame) {
+            Ok(mut value) => {
+                mask(&mut value, &fields);
+                value.to_string()
+            },
+            // Not JSON, nothing we know how to mask
+            Err(_) => "<redacted>".to_string(),
+        }
+    }
+}
+
+/// The interceptors of a connection.
+pub(crate) type Interceptors = Vec<Arc<dyn Interceptor>>;
+
+/// Pass a frame through a chain of hooks. Returns whether it should be kept.
+pub(crate) fn run<F>(interceptors: &[Arc<dyn Interceptor>], frame: &mut String, hook: F) -> bool
+    where F: Fn(&dyn Interceptor, &mut String) -> Verdict
+{
+    interceptors.iter().all(|i| hook(i.as_ref(), frame) == Verdict::Pass)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    struct Tag;
+
+    impl Interceptor for Tag {
+        fn outbound(&self, frame: &mut String) -> Verdict {
+            frame.push('!');
+            Verdict::Pass
+        }
+        fn inbound(&self, frame: &mut String) -> Verdict {
+            if frame.contains("secret") { Verdict::Drop } else { Verdict::Pass }
+        }
+    }
+
+    #[test]
+    fn test_chain() {
+        let chain: Interceptors = vec![Arc::new(Tag), Arc::new(Tag)];
+        let mut frame = "hello".to_string();
+        assert!(run(&chain, &mut frame, |i, f| i.outbound(f)));
+        assert_eq!(frame, "hello!!");
+        assert!(!run(&chain, &mut "secret".to_string(), |i, f| i.inbound(f)));
+    }
+
+    #[test]
+    fn test_mask_fields() {
+        let redact = mask_fields(&["password", "resume"]);
+        let frame = r#"{"msg":"method","method":"login","params":[{"user":{"username":"bob"},"password":{"digest":"abc","algorithm":"sha-256"}}],"id":"1"}"#;
+        let masked: Value = serde_json::from_str(&redact(frame)).unwrap();
+        assert_eq!(masked["params"][0], serde_json::json!({"user": {"username": "bob"}, "password": "<redacted>"}));
+        assert!(!redact(r#"{"msg":"method","params":[{"resume":"t0k3n"}]}"#).contains("t0k3n"));
+    }
+
+}
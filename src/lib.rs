@@ -1,26 +1,97 @@
 //! A simple wrapper for the 
 //! [Meteor DDP](https://github.com/meteor/meteor/blob/devel/packages/ddp/DDP.md) protocol.
 //!
+//! For most applications, [`DdpClient`] bundles reconnection, login,
+//! subscriptions and a local cache of the documents:
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), siderite::MethodError> {
+//! use siderite::login::{Password, User};
+//!
+//! let mut client = siderite::DdpClient::connect("wss://example.com/websocket").await?;
+//! client.login_with_password(&User::username("bob"), &Password::hash("secret")).await?;
+//! let _rooms = client.subscribe("rooms", ()).await?;
+//! for (id, room) in client.cache().documents("rooms") {
+//!     println!("{}: {}", id, room["name"]);
+//! }
+//! # Ok(())
+//! # }
 //! ```
-//! let connection = siderite::Connection::connect("wss://example.com/websocket").await?;
-//! 
-//! // Make a RPC task in an independant task:
-//! let handle = connection.handle();
-//! tokio::spawn(async move {
-//!     let r = handle.call("login", vec!["username".into(), "my-secret-token".into()])
-//!                        .await?;  // this throws if the RPC call could not complete
-//!                        .map_err(|e| eprintln!("Login failed with reason: {}", e))?
 //!
-//!     ...
+//! The [`Connection`] underneath gives full control over the messages:
+//!
+//! ```no_run
+//! # async fn example() -> siderite::Result<()> {
+//! use siderite::ServerMessage;
+//!
+//! let mut connection = siderite::Connection::connect("wss://example.com/websocket").await?;
+//!
+//! // Make RPC calls from an independent task:
+//! let mut handle = connection.handle();
+//! tokio::spawn(async move {
+//!     match handle.call("login".to_string(), ("username", "my-secret-token")).await {
+//!         Ok(Ok(result)) => println!("Logged in: {}", result),
+//!         Ok(Err(e)) => eprintln!("Login failed with reason: {}", e),
+//!         Err(e) => eprintln!("The call could not complete: {}", e),
+//!     }
 //! });
 //!
 //! // Consume the stream
 //! while let Some(msg) = connection.recv().await {
-//!    match msg {
-//!       ServerMessage::Added{..} => { ... }
-//!    }
+//!     if let ServerMessage::Added { collection, id, .. } = msg {
+//!         println!("{} added to {}", id, collection);
+//!     }
 //! }
+//! # Ok(())
+//! # }
 //! ```
+//!
+//! With the `tracing` feature, connections, method calls and subscriptions
+//! get their own [`tracing`](https://docs.rs/tracing) spans. Log messages
+//! still go through the `log` crate.
+//!
+//! With the `prometheus` feature, [`prometheus::PrometheusMetrics`] reports
+//! connection metrics to a Prometheus registry.
+//!
+//! With the `derive` feature, `#[derive(DdpDocument)]` maps structs to
+//! the fields of DDP documents, see [`document`].
+//!
+//! With the `otel` feature, method calls are traced with OpenTelemetry,
+//! and their trace context can be passed to the server, see `otel::ParamSlot`.
+//!
+//! With the `rocketchat` feature, [`rocketchat`] wraps the methods and
+//! streams of the Rocket.Chat realtime API.
+//!
+//! With the `chrono` and `time` features, [`Timestamp`] converts to and
+//! from the date types of these crates.
+//!
+//! With the `rust_decimal` feature, [`ejson::Decimal`] converts to and
+//! from [`rust_decimal::Decimal`](https://docs.rs/rust_decimal).
+//!
+//! With the `simd-json` feature, [`ServerMessage::from_frame`] decodes
+//! method results and the other messages that are not about documents with
+//! [simd-json](https://docs.rs/simd-json), straight into the message. The
+//! fields of document messages are kept as raw text either way.
+//!
+//! With the `webpki-roots` feature, the Mozilla roots bundled with
+//! [webpki-roots](https://docs.rs/webpki-roots) are trusted when the platform
+//! has no certificate store, as in scratch containers; see
+//! [`config::default_tls_config`] and [`config::webpki_tls_config`].
+//!
+//! With the `native-tls` feature, `wss://` urls are dialed with the
+//! platform's TLS stack (SChannel, Secure Transport or OpenSSL) and its
+//! certificate store, instead of rustls. The rustls settings given with
+//! [`ConnectionBuilder::tls_config`] are then ignored.
+//!
+//! With the `server` feature, the `server` module accepts DDP sessions from
+//! clients.
+//!
+//! With the `testing` feature, `testing::MockServer` helps testing client
+//! code without a Meteor application.
+//!
+//! With the `async-std-runtime` feature, connections run on async-std (or
+//! smol) instead of tokio: tasks, timers and sockets are async-std's. The
+//! server side, the mock server and [`record`] still need tokio.
 
 
 /// This contains the message types defined in the DDP spec
@@ -29,7 +100,126 @@ pub mod protocol;
 /// This offers an async interface for connecting to a DDP endpoint and exchange messages.
 pub mod connection;
 
+/// A high-level client, bundling the common features.
+pub mod client;
+
+/// Spreading method calls over several connections.
+pub mod pool;
+
+/// Tracking the progress of method calls.
+pub mod call;
+
+/// The channels carrying DDP messages.
+pub mod transport;
+
+/// Connection settings, and a builder to apply them.
+pub mod config;
+
+/// Tracking the state of subscriptions.
+pub mod subscription;
+
+/// The error type shared by the whole crate.
+pub mod error;
+
+/// Tunneling connections through HTTP and SOCKS5 proxies.
+pub mod proxy;
+
+/// Resolving host names when dialing.
+pub mod resolve;
+
+/// A local copy of the documents published by the server.
+pub mod cache;
+
+/// Typed streams of document changes.
+pub mod collection;
+
+/// Combinators over streams of server messages.
+pub mod stream;
+
+/// Mapping structs to DDP documents.
+pub mod document;
+
+/// Simulating methods on the client.
+pub mod stub;
+
+/// Hooks to observe or rewrite raw frames.
+pub mod intercept;
+
+/// How the ids of method calls and subscriptions are made.
+pub mod ids;
+
+/// Reporting connection metrics.
+pub mod metrics;
+
+/// Exporting connection metrics to Prometheus.
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+
+/// OpenTelemetry spans for method calls.
+#[cfg(feature = "otel")]
+pub mod otel;
+
+/// Logging in with Meteor accounts.
+pub mod login;
+
+/// The event channels of the `meteor-streamer` package.
+pub mod streamer;
+
+/// Typed wrappers for the Rocket.Chat realtime API.
+#[cfg(feature = "rocketchat")]
+pub mod rocketchat;
+
+/// Typed wrappers for Meteor's extended JSON.
+pub mod ejson;
+
+/// The server side of the protocol.
+#[cfg(any(test, feature = "server"))]
+pub mod server;
+
+/// The SockJS framing used by many Meteor deployments.
+pub mod sockjs;
+
+/// Recording sessions, and replaying them.
+pub mod record;
+
+/// A mock DDP server for tests.
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+mod base64;
+mod events;
+mod queue;
 mod randomslab;
+mod ratelimit;
+mod runtime;
+
+pub use cache::Cache;
+pub use client::DdpClient;
+pub use call::{CallHandle, MethodCall};
+pub use collection::{Change, Collection};
+pub use document::DdpDocument;
+pub use config::{Config, ConnectionBuilder, DDP_VERSIONS, Heartbeat, OverflowPolicy, RateLimit, ReconnectPolicy, RetryPolicy};
+pub use connection::{ConnectInfo, Connection, ConnectionStatus, Handle, Messages, PendingCallInfo, SubscriptionInfo, WorkerHandle};
+pub use ids::IdGenerator;
+pub use intercept::{Interceptor, Verdict};
+pub use metrics::Metrics;
+pub use pool::Pool;
+pub use error::{Error, ErrorCode, MeteorError, MethodError, Result};
+pub use proxy::Proxy;
+pub use resolve::Resolver;
+pub use stream::DdpStreamExt;
+pub use streamer::Streamer;
+pub use transport::{CloseReason, Transport};
+pub use protocol::{ClientMessage, Fields, ServerMessage, Timestamp};
+pub use subscription::{Subscription, SubscriptionGuard};
+
+/// Derive [`DdpDocument`] for a struct.
+#[cfg(feature = "derive")]
+pub use siderite_derive::DdpDocument;
+
+// Lets the code generated by the derive macros name this crate from within
+#[cfg(feature = "derive")]
+extern crate self as siderite;
 
-pub use connection::{Connection, Handle};
-pub use protocol::{ClientMessage, ServerMessage, Timestamp};
+/// The TLS library in use, to build custom [`Config::tls`] settings.
+pub use tokio_rustls::rustls;
@@ -0,0 +1,226 @@
+//! Helpers for the `login` method of Meteor's accounts packages.
+//!
+//! ```ignore
+//! let password = Password::hash("correct horse battery staple");
+//! let login = login::login_with_password(&mut handle, &User::username("bob"), &password).await?;
+//! // later, on a new connection:
+//! login::resume(&mut handle, &login).await?;
+//! ```
+
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+use crate::connection::Handle;
+use crate::error::MethodError;
+use crate::protocol::Timestamp;
+
+/// How to identify the account logging in with a password.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum User {
+    Username(String),
+    Email(String),
+    Id(String),
+}
+
+impl User {
+
+    pub fn username(username: &str) -> Self {
+        User::Username(username.to_string())
+    }
+
+    pub fn email(email: &str) -> Self {
+        User::Email(email.to_string())
+    }
+
+    pub fn id(id: &str) -> Self {
+        User::Id(id.to_string())
+    }
+
+}
+
+/// A password as sent by the Meteor client: a hex digest, never the plaintext.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Password {
+    pub digest: String,
+    pub algorithm: String,
+}
+
+impl Password {
+
+    /// A password given by its hex-encoded SHA-256 digest.
+    pub fn digest(digest: &str) -> Self {
+        Self { digest: digest.to_lowercase(), algorithm: "sha-256".to_string() }
+    }
+
+    /// Hash a plaintext password, so that only its digest is sent.
+    pub fn hash(plaintext: &str) -> Self {
+        let digest = ring::digest::digest(&ring::digest::SHA256, plaintext.as_bytes());
+        let hex: String = digest.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+        Self { digest: hex, algorithm: "sha-256".to_string() }
+    }
+
+}
+
+/// The result of an OAuth authorization, as stored by the server at the end
+/// of the flow started with an [`OAuthRequest`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OAuthCredentials {
+    pub credential_token: String,
+    pub credential_secret: String,
+}
+
+/// The client side of Meteor's OAuth handshake. The user (or a headless
+/// browser) visits the provider's authorization page with [`OAuthRequest::state`]
+/// as the `state` parameter; once redirected back, the server hands out the
+/// credential secret, which is exchanged for a login with [`login_with_oauth`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OAuthRequest {
+    /// The login service, such as `github` or `google`.
+    pub service: String,
+    /// A random token identifying this authorization attempt.
+    pub credential_token: String,
+    /// Where the provider sends the user back to, for the `redirect` login style.
+    pub redirect_url: Option<String>,
+}
+
+impl OAuthRequest {
+
+    /// Start an authorization attempt for a login service, with a fresh credential token.
+    pub fn new(service: &str) -> Self {
+        Self {
+            service: service.to_string(),
+            credential_token: random_secret(),
+            redirect_url: None,
+        }
+    }
+
+    pub fn with_redirect_url(mut self, url: &str) -> Self {
+        self.redirect_url = Some(url.to_string());
+        self
+    }
+
+    /// The `state` parameter of the authorization request, which the server
+    /// uses to match the provider's callback with the credential token.
+    pub fn state(&self) -> String {
+        let mut state = json!({
+            "loginStyle": if self.redirect_url.is_some() { "redirect" } else { "popup" },
+            "credentialToken": self.credential_token,
+            "isCordova": false,
+        });
+        if let Some(url) = &self.redirect_url {
+            state["redirectUrl"] = json!(url);
+        }
+        crate::base64::encode(state.to_string().as_bytes())
+    }
+
+    /// Pair the credential token with the secret obtained at the end of the flow.
+    pub fn credentials(&self, credential_secret: &str) -> OAuthCredentials {
+        OAuthCredentials {
+            credential_token: self.credential_token.clone(),
+            credential_secret: credential_secret.to_string(),
+        }
+    }
+
+}
+
+/// A random string of 43 characters, like Meteor's `Random.secret()`.
+fn random_secret() -> String {
+    use ring::rand::SecureRandom;
+    const ALPHABET: &[u8; 64] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789-_";
+    let mut bytes = [0u8; 43];
+    ring::rand::SystemRandom::new().fill(&mut bytes)
+        .expect("system random generator failed");
+    bytes.iter().map(|b| ALPHABET[(b & 0x3f) as usize] as char).collect()
+}
+
+/// The outcome of a successful login.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoginResult {
+    /// The id of the logged in user.
+    pub id: String,
+    /// A token to log in again with [`login_with_token`] or [`resume`].
+    pub token: String,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub token_expires: Option<Timestamp>,
+    /// The login service that was used, such as `password` or `resume`.
+    #[serde(default, rename="type", skip_serializing_if="Option::is_none")]
+    pub kind: Option<String>,
+}
+
+/// The parameter of a `login` call with a password.
+pub fn password_params(user: &User, password: &Password) -> Value {
+    json!({ "user": user, "password": password })
+}
+
+/// The parameter of a `login` call with a resume token.
+pub fn token_params(token: &str) -> Value {
+    json!({ "resume": token })
+}
+
+/// The parameter of a `login` call concluding an OAuth flow.
+pub fn oauth_params(credentials: &OAuthCredentials) -> Value {
+    json!({ "oauth": credentials })
+}
+
+/// Call `login`, holding back the calls after it until the server has
+/// switched to the new user, as the Meteor client does.
+async fn login(handle: &mut Handle, params: Value) -> Result<LoginResult, MethodError> {
+    let result = handle.call_wait("login".to_string(), params).await??;
+    serde_json::from_value(result).map_err(MethodError::Decode)
+}
+
+/// Log in with a username, email or user id, and a password.
+/// Use [`Password::hash`] to build the password from its plaintext.
+pub async fn login_with_password(handle: &mut Handle, user: &User, password: &Password) -> Result<LoginResult, MethodError> {
+    login(handle, password_params(user, password)).await
+}
+
+/// Log in with a resume token, as obtained from a previous login or from
+/// the `Meteor.loginToken` of a browser session.
+pub async fn login_with_token(handle: &mut Handle, token: &str) -> Result<LoginResult, MethodError> {
+    login(handle, token_params(token)).await
+}
+
+/// Log in with the credentials obtained at the end of an OAuth flow,
+/// for services such as `accounts-github` or `accounts-google`.
+pub async fn login_with_oauth(handle: &mut Handle, credentials: &OAuthCredentials) -> Result<LoginResult, MethodError> {
+    login(handle, oauth_params(credentials)).await
+}
+
+/// Log in again as the user of a previous login, e.g. after a new
+/// session had to be established.
+pub async fn resume(handle: &mut Handle, previous: &LoginResult) -> Result<LoginResult, MethodError> {
+    login_with_token(handle, &previous.token).await
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_params() {
+        let password = Password::digest("ABCDEF");
+        assert_eq!(password_params(&User::username("bob"), &password),
+                   json!({"user": {"username": "bob"}, "password": {"digest": "abcdef", "algorithm": "sha-256"}}));
+        assert_eq!(Password::hash("abc").digest,
+                   "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(token_params("t0k3n"), json!({"resume": "t0k3n"}));
+
+        let request = OAuthRequest::new("github");
+        assert_eq!(request.credential_token.len(), 43);
+        let state: Value = serde_json::from_slice(&crate::base64::decode(&request.state()).unwrap()).unwrap();
+        assert_eq!(state["credentialToken"], json!(request.credential_token));
+        assert_eq!(oauth_params(&request.credentials("s3cr3t")),
+                   json!({"oauth": {"credentialToken": request.credential_token, "credentialSecret": "s3cr3t"}}));
+
+        let result: LoginResult = serde_json::from_value(json!({
+            "id": "u1", "token": "t0k3n", "tokenExpires": {"$date": 1000}, "type": "password"
+        })).unwrap();
+        assert_eq!(result.kind.as_deref(), Some("password"));
+        assert_eq!(result.token_expires, serde_json::from_value(json!({"$date": 1000})).unwrap());
+    }
+
+}
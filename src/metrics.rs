@@ -0,0 +1,27 @@
+use std::time::Duration;
+
+/// Receives measurements from a connection, to be forwarded to a metrics
+/// system. All methods do nothing by default. They are called from the
+/// connection's background task, so they should not block.
+/// Register an implementation with [`crate::ConnectionBuilder::metrics`].
+pub trait Metrics: Send + Sync {
+
+    /// A frame was sent to the server.
+    fn message_sent(&self) {}
+
+    /// A frame was received from the server.
+    fn message_received(&self) {}
+
+    /// The number of method calls waiting for their result has changed.
+    fn pending_calls(&self, _count: usize) {}
+
+    /// The number of subscriptions started and not stopped has changed.
+    fn active_subscriptions(&self, _count: usize) {}
+
+    /// A method call got its result after this long.
+    fn call_latency(&self, _method: &str, _latency: Duration) {}
+
+    /// The link to the server was re-established.
+    fn reconnected(&self) {}
+
+}
@@ -0,0 +1,157 @@
+//! OpenTelemetry spans for method calls. Each call gets a `ddp.method` span,
+//! child of the context current when the call was started, and ended when
+//! its result arrives, or with the error the call fails with. The span's context can be passed to the server inside
+//! the call parameters, see [`ParamSlot`].
+
+use opentelemetry::{Context, KeyValue, global};
+use opentelemetry::trace::{StatusCode, TraceContextExt, Tracer};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use crate::connection::MethodResult;
+use crate::error::Error;
+
+/// Where to put the trace context of a method call, as the fields of the
+/// global text map propagator (e.g. `traceparent` for W3C trace context).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParamSlot {
+    /// Append an object holding the fields as an extra parameter.
+    Append,
+    /// Set the fields under `key` in the object parameter at `index`.
+    /// Calls without such a parameter are left untouched.
+    Field { index: usize, key: String },
+}
+
+/// The span of a method call. A call forgotten before its outcome is known,
+/// for instance after its caller gave up, ends its span as abandoned.
+pub(crate) struct CallSpan(Context);
+
+impl CallSpan {
+
+    /// End the span with the result from the server.
+    pub(crate) fn end(&self, result: &MethodResult) {
+        match result {
+            Ok(_) => self.0.span().set_status(StatusCode::Ok, String::new()),
+            Err(e) => self.0.span().set_status(StatusCode::Error, e.to_string()),
+        }
+        self.0.span().end();
+    }
+
+    /// End the span with the error the call failed with on our side.
+    pub(crate) fn fail(&self, error: &Error) {
+        self.0.span().set_status(StatusCode::Error, error.to_string());
+        self.0.span().end();
+    }
+
+}
+
+impl Drop for CallSpan {
+    fn drop(&mut self) {
+        if self.0.span().is_recording() {
+            self.0.span().set_status(StatusCode::Error, "call abandoned".to_string());
+            self.0.span().end();
+        }
+    }
+}
+
+/// Start the span of a method call, and inject its context into the parameters.
+pub(crate) fn start_call(method: &str, params: &mut Vec<Value>, parent: &Context, slot: Option<&ParamSlot>) -> CallSpan {
+    let tracer = global::tracer("siderite");
+    let span = tracer.start_with_context(format!("ddp.method {}", method), parent.clone());
+    let cx = parent.with_span(span);
+    cx.span().set_attribute(KeyValue::new("rpc.system", "ddp"));
+    cx.span().set_attribute(KeyValue::new("rpc.method", method.to_string()));
+
+    let span_context = cx.span().span_context().clone();
+    log::debug!("Calling {} (trace {}, span {})", method,
+                span_context.trace_id().to_hex(), span_context.span_id().to_hex());
+
+    if let Some(slot) = slot {
+        let mut fields = HashMap::new();
+        global::get_text_map_propagator(|propagator| propagator.inject_context(&cx, &mut fields));
+        let fields: Map<String, Value> = fields.into_iter().map(|(k, v)| (k, Value::String(v))).collect();
+        match slot {
+            ParamSlot::Append => params.push(Value::Object(fields)),
+            ParamSlot::Field { index, key } => {
+                if let Some(Value::Object(param)) = params.get_mut(*index) {
+                    param.insert(key.clone(), Value::Object(fields));
+                }
+            },
+        }
+    }
+
+    CallSpan(cx)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::Connection;
+    use crate::testing::{MockServer, block_on};
+    use opentelemetry::sdk::export::trace::SpanData;
+    use opentelemetry::sdk::trace::{Span, SpanProcessor, TracerProvider};
+    use opentelemetry::trace::TraceResult;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    /// Collects the name and status of the ended spans.
+    #[derive(Debug)]
+    struct Recorder(Arc<Mutex<Vec<(String, StatusCode, String)>>>);
+
+    impl SpanProcessor for Recorder {
+        fn on_start(&self, _: &mut Span, _: &Context) {}
+        fn on_end(&self, span: SpanData) {
+            let status = (span.name.into_owned(), span.status_code, span.status_message.into_owned());
+            self.0.lock().unwrap().push(status);
+        }
+        fn force_flush(&self) -> TraceResult<()> {
+            Ok(())
+        }
+        fn shutdown(&mut self) -> TraceResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_slots() {
+        // Without a propagator or tracer, the injected fields are empty
+        let parent = Context::new();
+        let mut params = vec![json!({"a": 1})];
+        start_call("test", &mut params, &parent, Some(&ParamSlot::Field { index: 0, key: "trace".to_string() }));
+        assert_eq!(params, vec![json!({"a": 1, "trace": {}})]);
+        start_call("test", &mut params, &parent, Some(&ParamSlot::Append));
+        assert_eq!(params.len(), 2);
+    }
+
+    #[test]
+    fn test_failed_calls() {
+        let ended = Arc::new(Mutex::new(Vec::new()));
+        global::set_tracer_provider(TracerProvider::builder().with_span_processor(Recorder(ended.clone())).build());
+
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            let connection = Connection::builder(&server.url()).pending_call_limit(1).connect().await.unwrap();
+            let mut handle = connection.handle();
+            let stuck = handle.start_call("traced".to_string(), ()).await.unwrap();
+            let refused = handle.start_call("traced".to_string(), ()).await.unwrap();
+            assert!(matches!(refused.await, Err(Error::TooManyPendingCalls)));
+            handle.abort_pending().await.unwrap();
+            assert!(matches!(stuck.await, Err(Error::Cancelled)));
+            drop(handle.start_call("traced".to_string(), ()).await.unwrap());
+            handle.purge().await.unwrap();
+            assert!(handle.pending_calls().await.unwrap().is_empty());
+        });
+
+        // Other tests may be tracing their calls meanwhile
+        let ended: Vec<_> = ended.lock().unwrap().iter()
+            .filter(|(name, _, _)| name == "ddp.method traced")
+            .map(|(_, code, message)| (*code, message.clone()))
+            .collect();
+        assert_eq!(ended, vec![
+            (StatusCode::Error, Error::TooManyPendingCalls.to_string()),
+            (StatusCode::Error, Error::Cancelled.to_string()),
+            (StatusCode::Error, "call abandoned".to_string()),
+        ]);
+    }
+
+}
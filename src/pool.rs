@@ -0,0 +1,147 @@
+//! Spreading method calls over several connections, to one or several
+//! servers, for services that outgrow a single DDP session.
+
+use futures::{StreamExt, future};
+use serde::Serialize;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::config::ConnectionBuilder;
+use crate::connection::{Connection, ConnectionStatus, Handle, MethodResult, to_params};
+use crate::error::{Error, Result};
+use crate::runtime;
+use log::warn;
+
+/// A set of connections, used in turn for method calls.
+///
+/// Calls skip the connections that are reconnecting, and connections that
+/// are closed for good are evicted from the pool. The inbound messages of
+/// the connections are discarded: pools are meant for method calls.
+#[derive(Debug)]
+pub struct Pool {
+    handles: Mutex<Vec<Handle>>,
+    next: AtomicUsize,
+}
+
+impl Pool {
+
+    /// Open `size` connections, spread over the urls in turn.
+    pub async fn connect(urls: &[&str], size: usize) -> Result<Self> {
+        let builders = urls.iter().cycle().take(size).map(|url| Connection::builder(url));
+        Self::connect_with(builders).await
+    }
+
+    /// Open a connection with each of the builders.
+    pub async fn connect_with(builders: impl IntoIterator<Item = ConnectionBuilder>) -> Result<Self> {
+        let mut connections = Vec::new();
+        for builder in builders {
+            connections.push(builder.connect().await?);
+        }
+        Ok(Self::from_connections(connections))
+    }
+
+    /// Pool connections established beforehand.
+    pub fn from_connections(connections: impl IntoIterator<Item = Connection>) -> Self {
+        let handles = connections.into_iter().map(|connection| {
+            let (messages, handle) = connection.split();
+            runtime::spawn(messages.for_each(|_| future::ready(())));
+            handle
+        }).collect();
+        Self { handles: Mutex::new(handles), next: AtomicUsize::new(0) }
+    }
+
+    /// The number of connections not evicted yet.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// Handles to the connections of the pool.
+    pub fn handles(&self) -> Vec<Handle> {
+        self.lock().clone()
+    }
+
+    /// The next connected handle, evicting the closed ones on the way.
+    /// Fails with [`Error::Closed`] if none is left, and with
+    /// [`Error::Disconnected`] if they are all reconnecting.
+    pub fn handle(&self) -> Result<Handle> {
+        let mut handles = self.lock();
+        handles.retain(|handle| {
+            let closed = *handle.status().borrow() == ConnectionStatus::Closed;
+            if closed {
+                warn!("Evicting closed connection from the pool");
+            }
+            !closed
+        });
+        if handles.is_empty() {
+            return Err(Error::Closed)
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..handles.len())
+            .map(|i| &handles[(start + i) % handles.len()])
+            .find(|handle| *handle.status().borrow() == ConnectionStatus::Connected)
+            .cloned()
+            .ok_or(Error::Disconnected)
+    }
+
+    /// Perform a method call on the next connection, see [`Handle::call`].
+    pub async fn call<P: Serialize>(&self, name: String, params: P) -> Result<MethodResult> {
+        let params = to_params(params)?;
+        self.handle()?.call(name, params).await
+    }
+
+    /// Close all the connections.
+    pub async fn close(&self) -> Result<()> {
+        let handles = std::mem::take(&mut *self.lock());
+        for mut handle in handles {
+            handle.close().await?;
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Handle>> {
+        self.handles.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::{block_on, MockServer};
+    use serde_json::json;
+
+    #[test]
+    fn test_pool() {
+        block_on(async {
+            let servers = [MockServer::start().await.unwrap(), MockServer::start().await.unwrap()];
+            for (i, server) in servers.iter().enumerate() {
+                server.on_method("whoami", move |_| Ok(json!(i)));
+            }
+            let urls: Vec<String> = servers.iter().map(MockServer::url).collect();
+            let urls: Vec<&str> = urls.iter().map(String::as_str).collect();
+
+            // The mock server serves one client at a time
+            let pool = Pool::connect(&urls, 2).await.unwrap();
+            assert_eq!(pool.len(), 2);
+            let mut answers = Vec::new();
+            for _ in 0..4 {
+                answers.push(pool.call("whoami".to_string(), ()).await.unwrap().unwrap());
+            }
+            assert_eq!(answers, vec![json!(0), json!(1), json!(0), json!(1)]);
+
+            pool.handles()[0].close().await.unwrap();
+            for _ in 0..2 {
+                assert_eq!(pool.call("whoami".to_string(), ()).await.unwrap(), Ok(json!(1)));
+            }
+            assert_eq!(pool.len(), 1);
+
+            pool.close().await.unwrap();
+            assert!(matches!(pool.call("whoami".to_string(), ()).await, Err(Error::Closed)));
+        });
+    }
+
+}
@@ -0,0 +1,111 @@
+//! A [`Metrics`] recorder for the [`prometheus`](https://docs.rs/prometheus) crate.
+//!
+//! ```ignore
+//! let registry = prometheus::Registry::new();
+//! let metrics = siderite::prometheus::PrometheusMetrics::new(&registry)?;
+//! let connection = Connection::builder(url).metrics(metrics).connect().await?;
+//! ```
+
+use ::prometheus::{HistogramOpts, HistogramVec, IntCounter, IntGauge, Opts, Registry};
+use std::collections::HashMap;
+use std::time::Duration;
+use crate::metrics::Metrics;
+
+/// Connection metrics registered with a [`Registry`], under the `siderite_` prefix.
+/// Gauges describe a single connection: when several connections report to the
+/// same registry, give each recorder distinct labels with [`PrometheusMetrics::with_labels`].
+#[derive(Clone, Debug)]
+pub struct PrometheusMetrics {
+    sent: IntCounter,
+    received: IntCounter,
+    pending: IntGauge,
+    subscriptions: IntGauge,
+    reconnects: IntCounter,
+    latency: HistogramVec,
+}
+
+impl PrometheusMetrics {
+
+    pub fn new(registry: &Registry) -> ::prometheus::Result<Self> {
+        Self::with_labels(registry, HashMap::new())
+    }
+
+    /// Register the metrics with constant labels, such as the name of the server.
+    pub fn with_labels(registry: &Registry, labels: HashMap<String, String>) -> ::prometheus::Result<Self> {
+        let opts = |name: &str, help: &str| {
+            Opts::new(name, help).namespace("siderite").const_labels(labels.clone())
+        };
+
+        let metrics = Self {
+            sent: IntCounter::with_opts(opts("messages_sent_total", "Frames sent to the server"))?,
+            received: IntCounter::with_opts(opts("messages_received_total", "Frames received from the server"))?,
+            pending: IntGauge::with_opts(opts("pending_calls", "Method calls waiting for their result"))?,
+            subscriptions: IntGauge::with_opts(opts("active_subscriptions", "Subscriptions started and not stopped"))?,
+            reconnects: IntCounter::with_opts(opts("reconnects_total", "Links re-established after a failure"))?,
+            latency: HistogramVec::new(
+                HistogramOpts::from(opts("call_latency_seconds", "Time until a method call gets its result")),
+                &["method"],
+            )?,
+        };
+
+        registry.register(Box::new(metrics.sent.clone()))?;
+        registry.register(Box::new(metrics.received.clone()))?;
+        registry.register(Box::new(metrics.pending.clone()))?;
+        registry.register(Box::new(metrics.subscriptions.clone()))?;
+        registry.register(Box::new(metrics.reconnects.clone()))?;
+        registry.register(Box::new(metrics.latency.clone()))?;
+
+        Ok(metrics)
+    }
+
+}
+
+impl Metrics for PrometheusMetrics {
+
+    fn message_sent(&self) {
+        self.sent.inc()
+    }
+
+    fn message_received(&self) {
+        self.received.inc()
+    }
+
+    fn pending_calls(&self, count: usize) {
+        self.pending.set(count as i64)
+    }
+
+    fn active_subscriptions(&self, count: usize) {
+        self.subscriptions.set(count as i64)
+    }
+
+    fn call_latency(&self, method: &str, latency: Duration) {
+        self.latency.with_label_values(&[method]).observe(latency.as_secs_f64())
+    }
+
+    fn reconnected(&self) {
+        self.reconnects.inc()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_register() {
+        let registry = Registry::new();
+        let metrics = PrometheusMetrics::new(&registry).unwrap();
+        metrics.message_sent();
+        metrics.call_latency("login", Duration::from_millis(20));
+
+        let families = registry.gather();
+        let sent = families.iter().find(|f| f.get_name() == "siderite_messages_sent_total").unwrap();
+        assert_eq!(sent.get_metric()[0].get_counter().get_value(), 1.0);
+
+        // Same names, same registry
+        assert!(PrometheusMetrics::new(&registry).is_err());
+    }
+
+}
@@ -1,14 +1,89 @@
 //! This module contains the `serde` datastructures for DDP
 
-use serde::{Serialize, Deserialize};
-use serde_json::{self, Value};
+use serde::{Serialize, Deserialize, de::{DeserializeOwned, IgnoredAny}};
+use serde_json::{self, Map, Value, value::RawValue};
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::error::Error;
 
 /// A date represented by the JSON object `{ "$date": ts }`, with `ts` in millisecs since the epoch.
 /// This type is not [`Ord`] because the timestamp can be null
 #[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct Timestamp {
     #[serde(rename="$date")]
-    millis: Option<u64>,
+    millis: Option<i64>,
+}
+
+impl Timestamp {
+
+    /// The current time.
+    pub fn now() -> Self {
+        Self::from(SystemTime::now())
+    }
+
+    /// A date `millis` milliseconds after the epoch, or before it if negative.
+    pub fn from_millis(millis: i64) -> Self {
+        Self { millis: Some(millis) }
+    }
+
+    /// Milliseconds since the epoch, unless the date is null.
+    pub fn millis(&self) -> Option<i64> {
+        self.millis
+    }
+
+    /// The date as a [`SystemTime`], unless it is null.
+    pub fn to_system_time(&self) -> Option<SystemTime> {
+        let millis = self.millis?;
+        let offset = Duration::from_millis(millis.unsigned_abs());
+        if millis >= 0 {
+            UNIX_EPOCH.checked_add(offset)
+        } else {
+            UNIX_EPOCH.checked_sub(offset)
+        }
+    }
+
+    /// The date as a [`chrono::DateTime`], unless it is null or out of range.
+    #[cfg(feature = "chrono")]
+    pub fn to_chrono(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        use chrono::TimeZone;
+        chrono::Utc.timestamp_millis_opt(self.millis?).single()
+    }
+
+    /// The date as a [`time::OffsetDateTime`], unless it is null or out of range.
+    #[cfg(feature = "time")]
+    pub fn to_offset_date_time(&self) -> Option<time::OffsetDateTime> {
+        let nanos = i128::from(self.millis?) * 1_000_000;
+        time::OffsetDateTime::from_unix_timestamp_nanos(nanos).ok()
+    }
+
+}
+
+/// Dates out of the range of `i64` milliseconds are clamped.
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        let millis = match time.duration_since(UNIX_EPOCH) {
+            Ok(after) => i64::try_from(after.as_millis()).unwrap_or(i64::MAX),
+            Err(before) => i64::try_from(before.duration().as_millis()).map_or(i64::MIN, |millis| -millis),
+        };
+        Self::from_millis(millis)
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl<Tz: chrono::TimeZone> From<chrono::DateTime<Tz>> for Timestamp {
+    fn from(date: chrono::DateTime<Tz>) -> Self {
+        Self::from_millis(date.timestamp_millis())
+    }
+}
+
+/// Dates out of the range of `i64` milliseconds are clamped.
+#[cfg(feature = "time")]
+impl From<time::OffsetDateTime> for Timestamp {
+    fn from(date: time::OffsetDateTime) -> Self {
+        let millis = date.unix_timestamp_nanos() / 1_000_000;
+        Self::from_millis(i64::try_from(millis).unwrap_or(if millis < 0 { i64::MIN } else { i64::MAX }))
+    }
 }
 
 impl PartialOrd for Timestamp {
@@ -22,6 +97,7 @@ impl PartialOrd for Timestamp {
 
 /// DDP messages from client to server
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
 #[serde(tag = "msg")]
 #[serde(rename_all = "camelCase")]
 pub enum ClientMessage {
@@ -46,7 +122,9 @@ pub enum ClientMessage {
     Method { 
         id: String, 
         method: String, 
-        params: Vec<Value>
+        params: Vec<Value>,
+        #[serde(rename="randomSeed", default, skip_serializing_if="Option::is_none")]
+        random_seed: Option<String>,
     },
 
 
@@ -59,8 +137,14 @@ pub enum ClientMessage {
 
 }
 
+/// A string stored inline when short, as ids and collection names usually
+/// are: document messages carry them without allocating.
+pub type SmartString = smartstring::alias::String;
+
 /// DDP messages from server to client
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+#[serde(remote = "Self")]
 #[serde(tag = "msg")]
 #[serde(rename_all = "camelCase")]
 pub enum ServerMessage {
@@ -95,49 +179,253 @@ pub enum ServerMessage {
     },
     
     Added {
-        collection: String,
-        id: String,
-        fields: Option<Value>,
+        collection: SmartString,
+        id: SmartString,
+        fields: Option<Fields>,
     },
     Changed {
-        collection: String,
-        id: String,
+        collection: SmartString,
+        id: SmartString,
         #[serde(default, skip_serializing_if="Option::is_none")]
-        fields: Option<Value>,
+        fields: Option<Fields>,
         #[serde(default, skip_serializing_if="Option::is_none")]
         cleared: Option<Vec<String>>,
     },
     Removed {
-        collection: String,
-        id: String,
+        collection: SmartString,
+        id: SmartString,
     },
     Ready {
         subs: Vec<String>,
     },
     AddedBefore {
-        collection: String,
-        id: String,
+        collection: SmartString,
+        id: SmartString,
         #[serde(default, skip_serializing_if="Option::is_none")]
-        fields: Option<Value>,
+        fields: Option<Fields>,
         before: Option<String>,
     },
     MovedBefore {
         before: Option<String>,
+    },
+
+    /// A message of a type we do not know, passed on as-is.
+    #[serde(skip)]
+    Unknown(Value),
+
+}
+
+/// The types of [`ServerMessage`] we can decode.
+const SERVER_MESSAGES: &[&str] = &[
+    "connected", "failed", "ping", "pong", "result", "nosub", "updated",
+    "added", "changed", "removed", "ready", "addedBefore", "movedBefore",
+];
+
+impl Serialize for ServerMessage {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            ServerMessage::Unknown(value) => value.serialize(serializer),
+            known => ServerMessage::serialize(known, serializer),
+        }
+    }
+}
+
+/// The type of a message, peeked before decoding it.
+#[derive(Deserialize)]
+struct Tag<'a> {
+    #[serde(borrow, default)]
+    msg: Option<Cow<'a, str>>,
+}
+
+/// The type of the message in a frame, if it has one. Meteor servers write
+/// it first, which spares scanning the whole frame.
+fn message_type(frame: &str) -> serde_json::Result<Option<Cow<'_, str>>> {
+    if let Some(rest) = frame.strip_prefix(r#"{"msg":""#) {
+        if let Some(msg) = rest.find('"').map(|end| &rest[..end]).filter(|msg| !msg.contains('\\')) {
+            return Ok(Some(Cow::Borrowed(msg)))
+        }
+    }
+    match serde_json::from_str::<Tag<'_>>(frame) {
+        Ok(tag) => Ok(tag.msg),
+        // Valid JSON, but not an object
+        Err(_) => serde_json::from_str::<IgnoredAny>(frame).map(|_| None),
     }
+}
 
+/// Messages of an unknown type become [`ServerMessage::Unknown`], while
+/// malformed messages of a known type are still errors. This goes through a
+/// [`Value`]: frames are better decoded with [`ServerMessage::from_frame`].
+impl<'de> Deserialize<'de> for ServerMessage {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        match value.get("msg").and_then(Value::as_str) {
+            Some(msg) if SERVER_MESSAGES.contains(&msg) => {
+                ServerMessage::deserialize(value).map_err(serde::de::Error::custom)
+            },
+            _ => Ok(ServerMessage::Unknown(value)),
+        }
+    }
 }
 
 impl ServerMessage {
 
+    /// Decode a frame in a single pass, straight from the string. Frames
+    /// without a message type, such as the `server_id` greeting of Meteor
+    /// servers, decode as `None`.
+    pub fn from_frame(frame: &str) -> serde_json::Result<Option<Self>> {
+        Self::from_frame_filtered(frame, |_, _| true)
+    }
+
+    /// Like [`ServerMessage::from_frame`], but the document messages for
+    /// which `keep(collection, id)` is false decode as `None`, without
+    /// copying their fields.
+    pub(crate) fn from_frame_filtered(frame: &str, keep: impl Fn(&str, &str) -> bool) -> serde_json::Result<Option<Self>> {
+        match message_type(frame)?.as_deref() {
+            None => Ok(None),
+            Some(msg @ ("added" | "changed" | "removed" | "addedBefore")) => {
+                let doc: DocumentFrame<'_> = serde_json::from_str(frame)?;
+                match keep(&doc.collection, &doc.id) {
+                    true => Ok(Some(doc.into_message(msg))),
+                    false => Ok(None),
+                }
+            },
+            Some(msg) if SERVER_MESSAGES.contains(&msg) => decode_known(frame).map(Some),
+            Some(_) => Ok(Some(ServerMessage::Unknown(serde_json::from_str(frame)?))),
+        }
+    }
+
+    /// Decode a message, rejecting unknown message types, and fields we would
+    /// not send back when serializing it. Fields set to `null` count as absent.
+    pub fn from_value_strict(value: Value) -> crate::error::Result<Self> {
+        let msg: ServerMessage = serde_json::from_value(value.clone())?;
+        if let ServerMessage::Unknown(_) = msg {
+            return Err(Error::Protocol(format!("unknown message: {}", value)))
+        }
+        let known = serde_json::to_value(&msg)?;
+        if let (Value::Object(fields), Value::Object(known)) = (&value, &known) {
+            for (field, v) in fields {
+                if !v.is_null() && !known.contains_key(field) {
+                    return Err(Error::Protocol(format!("unknown field {} in {}", field, value)))
+                }
+            }
+        }
+        Ok(msg)
+    }
+
     pub fn pretty(&self) -> String {
-        serde_json::to_value(&self)
+        serde_json::to_value(self)
             .and_then(|v| serde_json::to_string_pretty(&v))
             .unwrap_or_else(|_| "<<serialization error>>".to_string())
     }
 
 }
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// Decode a message of a known type, other than a document message.
+#[cfg(not(feature = "simd-json"))]
+fn decode_known(frame: &str) -> serde_json::Result<ServerMessage> {
+    let mut deserializer = serde_json::Deserializer::from_str(frame);
+    let msg = ServerMessage::deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    Ok(msg)
+}
+
+/// Decode a message of a known type, other than a document message, with
+/// simd-json parsing a copy of the frame in place.
+#[cfg(feature = "simd-json")]
+fn decode_known(frame: &str) -> serde_json::Result<ServerMessage> {
+    let mut bytes = frame.as_bytes().to_vec();
+    let mut deserializer = simd_json::Deserializer::from_slice(&mut bytes).map_err(serde::de::Error::custom)?;
+    ServerMessage::deserialize(&mut deserializer).map_err(serde::de::Error::custom)
+}
+
+/// The fields of a document message, kept as the JSON text received: they
+/// are only decoded when asked for.
+#[derive(Clone, Debug, Serialize)]
+#[serde(transparent)]
+pub struct Fields(Box<RawValue>);
+
+impl Fields {
+
+    /// The fields as JSON text.
+    pub fn get(&self) -> &str {
+        self.0.get()
+    }
+
+    /// Decode the fields.
+    pub fn parse<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_str(self.0.get())
+    }
+
+    /// Decode the fields as an object. Fields that are not an object are
+    /// taken as no fields.
+    pub fn to_object(&self) -> Map<String, Value> {
+        self.parse().unwrap_or_default()
+    }
+
+}
+
+impl From<Value> for Fields {
+    fn from(value: Value) -> Self {
+        Fields(serde_json::value::to_raw_value(&value).expect("a Value is valid JSON"))
+    }
+}
+
+impl From<Map<String, Value>> for Fields {
+    fn from(object: Map<String, Value>) -> Self {
+        Fields::from(Value::Object(object))
+    }
+}
+
+/// Fields are equal if they decode to the same JSON, whatever their layout.
+impl PartialEq for Fields {
+    fn eq(&self, other: &Self) -> bool {
+        self.get() == other.get() || self.parse::<Value>().ok() == other.parse::<Value>().ok()
+    }
+}
+
+impl Eq for Fields {}
+
+/// This goes through a [`Value`]: [`ServerMessage::from_frame`] copies the
+/// text of the fields instead.
+impl<'de> Deserialize<'de> for Fields {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Value::deserialize(deserializer).map(Fields::from)
+    }
+}
+
+/// A document message (`added`, `changed`, `removed` or `addedBefore`),
+/// borrowed from its frame so that it can be filtered before anything is
+/// copied.
+#[derive(Deserialize)]
+struct DocumentFrame<'a> {
+    #[serde(borrow)]
+    collection: Cow<'a, str>,
+    #[serde(borrow)]
+    id: Cow<'a, str>,
+    #[serde(borrow, default)]
+    fields: Option<&'a RawValue>,
+    #[serde(default)]
+    cleared: Option<Vec<String>>,
+    #[serde(default)]
+    before: Option<String>,
+}
+
+impl DocumentFrame<'_> {
+
+    fn into_message(self, msg: &str) -> ServerMessage {
+        let (collection, id) = (SmartString::from(self.collection), SmartString::from(self.id));
+        let fields = self.fields.map(|fields| Fields(fields.to_owned()));
+        match msg {
+            "added" => ServerMessage::Added { collection, id, fields },
+            "changed" => ServerMessage::Changed { collection, id, fields, cleared: self.cleared },
+            "removed" => ServerMessage::Removed { collection, id },
+            _ => ServerMessage::AddedBefore { collection, id, fields, before: self.before },
+        }
+    }
+
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct MethodResponse {
     pub id: String,
     #[serde(default, skip_serializing_if="Option::is_none")]
@@ -160,7 +448,7 @@ mod tests {
     {
         let serialized = serde_json::to_string(msg).unwrap();
         assert_eq!(serialized, string);
-        let deserialized: M = serde_json::from_str(&string).unwrap();
+        let deserialized: M = serde_json::from_str(string).unwrap();
         assert_eq!(msg, &deserialized);
         
     }
@@ -195,8 +483,62 @@ mod tests {
         check_message(&ServerMessage::Ping { id: Some("pingpong".to_string()) }, r#"{"msg":"ping","id":"pingpong"}"#);
     }
 
+    #[test]
+    fn test_random_seed() {
+        check_message(&ClientMessage::Method {
+            id: "1".to_string(), method: "insert".to_string(), params: vec![], random_seed: Some("abc".to_string())
+        }, r#"{"msg":"method","id":"1","method":"insert","params":[],"randomSeed":"abc"}"#);
+        check_message(&ClientMessage::Method {
+            id: "1".to_string(), method: "insert".to_string(), params: vec![], random_seed: None
+        }, r#"{"msg":"method","id":"1","method":"insert","params":[]}"#);
+    }
+
+    #[test]
+    fn test_unknown() {
+        check_message(&ServerMessage::Unknown(serde_json::json!({"msg": "teleport", "to": "mars"})),
+                      r#"{"msg":"teleport","to":"mars"}"#);
+        assert!(serde_json::from_str::<ServerMessage>(r#"{"msg":"added"}"#).is_err());
+    }
+
+    #[test]
+    fn test_from_frame() {
+        let added = ServerMessage::Added { collection: "rooms".into(), id: "r1".into(), fields: None };
+        assert_eq!(ServerMessage::from_frame(r#"{"msg":"added","collection":"rooms","id":"r1"}"#).unwrap(), Some(added.clone()));
+        assert_eq!(ServerMessage::from_frame(r#"{"id":"r1","collection":"rooms", "msg":"added"}"#).unwrap(), Some(added));
+        assert_eq!(ServerMessage::from_frame(r#"{"server_id":"0"}"#).unwrap(), None);
+        assert_eq!(ServerMessage::from_frame(r#"{"msg":"teleport","to":"mars"}"#).unwrap(),
+                   Some(ServerMessage::Unknown(serde_json::json!({"msg": "teleport", "to": "mars"}))));
+        assert!(ServerMessage::from_frame(r#"{"msg":"added"}"#).is_err());
+        assert!(ServerMessage::from_frame(r#"{"msg":"ready","subs":[]} trailing"#).is_err());
+
+        let frame = r#"{"msg":"changed","collection":"rooms","id":"r2","fields":{"topic": "rust"}}"#;
+        match ServerMessage::from_frame(frame).unwrap() {
+            Some(ServerMessage::Changed { fields: Some(fields), .. }) => {
+                assert_eq!(fields.get(), r#"{"topic": "rust"}"#);
+                assert_eq!(fields, Fields::from(serde_json::json!({"topic": "rust"})));
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(ServerMessage::from_frame_filtered(frame, |_, id| id != "r2").unwrap(), None);
+    }
+
+    #[test]
+    fn test_strict() {
+        let extra = serde_json::json!({"msg": "ready", "subs": ["s1"], "extra": 1});
+        assert!(ServerMessage::from_value_strict(extra).is_err());
+        let null = serde_json::json!({"msg": "nosub", "id": "s1", "error": null});
+        assert!(ServerMessage::from_value_strict(null).is_ok());
+        let unknown = serde_json::json!({"msg": "teleport"});
+        assert!(ServerMessage::from_value_strict(unknown).is_err());
+    }
+
     #[test]
     fn test_timestamp() {
         check_message(&Timestamp{ millis: Some(129348109238) }, r#"{"$date":129348109238}"#);
+        check_message(&Timestamp{ millis: Some(-1000) }, r#"{"$date":-1000}"#);
+        let date = UNIX_EPOCH + Duration::from_millis(129348109238);
+        assert_eq!(Timestamp::from(date).to_system_time(), Some(date));
+        assert_eq!(Timestamp::from(UNIX_EPOCH - Duration::from_secs(1)), Timestamp::from_millis(-1000));
+        assert_eq!(Timestamp::from_millis(-1000).to_system_time(), Some(UNIX_EPOCH - Duration::from_secs(1)));
     }
 }
\ No newline at end of file
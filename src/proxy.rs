@@ -0,0 +1,298 @@
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use std::io;
+use std::net::IpAddr;
+use crate::base64;
+use crate::runtime::{self, TcpStream};
+
+/// Username and password for proxy authentication.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// A proxy through which the websocket connection is tunneled.
+/// TLS and the websocket handshake happen end-to-end, inside the tunnel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Proxy {
+    /// An HTTP proxy supporting the `CONNECT` method.
+    Http {
+        addr: String,
+        credentials: Option<Credentials>,
+    },
+    /// A SOCKS5 proxy. Host names are resolved by the proxy.
+    Socks5 {
+        addr: String,
+        credentials: Option<Credentials>,
+    },
+}
+
+fn failure(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg.into())
+}
+
+impl Proxy {
+
+    /// An HTTP proxy, at `host:port`.
+    pub fn http(addr: &str) -> Self {
+        Proxy::Http { addr: addr.to_string(), credentials: None }
+    }
+
+    /// A SOCKS5 proxy, at `host:port`.
+    pub fn socks5(addr: &str) -> Self {
+        Proxy::Socks5 { addr: addr.to_string(), credentials: None }
+    }
+
+    /// Authenticate to the proxy.
+    pub fn with_credentials(mut self, username: &str, password: &str) -> Self {
+        let creds = Some(Credentials { username: username.to_string(), password: password.to_string() });
+        match &mut self {
+            Proxy::Http { credentials, .. } | Proxy::Socks5 { credentials, .. } => *credentials = creds,
+        }
+        self
+    }
+
+    /// Open a TCP stream to `host:port` through the proxy.
+    pub(crate) async fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        match self {
+            Proxy::Http { addr, credentials } => {
+                let stream = runtime::connect(addr.as_str()).await?;
+                http_connect(stream, host, port, credentials.as_ref()).await
+            },
+            Proxy::Socks5 { addr, credentials } => {
+                let stream = runtime::connect(addr.as_str()).await?;
+                socks5_connect(stream, host, port, credentials.as_ref()).await
+            },
+        }
+    }
+
+}
+
+async fn http_connect(stream: TcpStream, host: &str, port: u16, credentials: Option<&Credentials>) -> io::Result<TcpStream> {
+
+    let mut stream = BufReader::new(stream);
+
+    // IPv6 literals are bracketed in the authority
+    let authority = match host.parse() {
+        Ok(IpAddr::V6(_)) => format!("[{}]:{}", host, port),
+        _ => format!("{}:{}", host, port),
+    };
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+    if let Some(Credentials { username, password }) = credentials {
+        let token = base64::encode(format!("{}:{}", username, password).as_bytes());
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut status = String::new();
+    stream.read_line(&mut status).await?;
+    let code = status.split_whitespace().nth(1);
+    if code != Some("200") {
+        return Err(failure(format!("proxy refused CONNECT: {}", status.trim_end())))
+    }
+
+    // Skip the response headers
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 {
+            return Err(failure("proxy closed the connection"))
+        }
+        if line == "\r\n" || line == "\n" {
+            break
+        }
+    }
+
+    if !stream.buffer().is_empty() {
+        return Err(failure("proxy sent unexpected data after CONNECT"))
+    }
+
+    Ok(stream.into_inner())
+}
+
+async fn socks5_connect(mut stream: TcpStream, host: &str, port: u16, credentials: Option<&Credentials>) -> io::Result<TcpStream> {
+
+    // Greeting: offer "no authentication", or "username/password"
+    let method = if credentials.is_some() { 0x02 } else { 0x00 };
+    stream.write_all(&[0x05, 0x01, method]).await?;
+
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 0x05 || reply[1] != method {
+        return Err(failure("SOCKS5 proxy rejected our authentication method"))
+    }
+
+    if let Some(Credentials { username, password }) = credentials {
+        if username.len() > 255 || password.len() > 255 {
+            return Err(failure("SOCKS5 credentials too long"))
+        }
+        let mut auth = vec![0x01, username.len() as u8];
+        auth.extend_from_slice(username.as_bytes());
+        auth.push(password.len() as u8);
+        auth.extend_from_slice(password.as_bytes());
+        stream.write_all(&auth).await?;
+
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            return Err(failure("SOCKS5 authentication failed"))
+        }
+    }
+
+    let mut request = vec![0x05, 0x01, 0x00];
+    match host.parse() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(0x01);
+            request.extend_from_slice(&ip.octets());
+        },
+        Ok(IpAddr::V6(ip)) => {
+            request.push(0x04);
+            request.extend_from_slice(&ip.octets());
+        },
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(failure("host name too long for SOCKS5"))
+            }
+            request.extend_from_slice(&[0x03, host.len() as u8]);
+            request.extend_from_slice(host.as_bytes());
+        },
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).await?;
+    if header[1] != 0x00 {
+        return Err(failure(format!("SOCKS5 proxy refused to connect, code {}", header[1])))
+    }
+
+    // Skip the bound address
+    let len = match header[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => stream.read_u8().await? as usize,
+        other => return Err(failure(format!("unknown SOCKS5 address type {}", other))),
+    };
+    let mut bound = vec![0; len + 2];
+    stream.read_exact(&mut bound).await?;
+
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::block_on;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    /// Serve one client: for each step, expect some bytes, then answer.
+    async fn script(steps: Vec<(&'static [u8], &'static [u8])>) -> (String, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let task = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            for (expected, reply) in steps {
+                let mut received = vec![0; expected.len()];
+                stream.read_exact(&mut received).await.unwrap();
+                assert_eq!(String::from_utf8_lossy(&received), String::from_utf8_lossy(expected));
+                stream.write_all(reply).await.unwrap();
+            }
+            // Wait for the client to hang up
+            let _ = stream.read(&mut [0; 1]).await;
+        });
+        (addr, task)
+    }
+
+    /// Check that the tunnel carries data both ways, then hang up.
+    async fn ping(mut stream: TcpStream) {
+        stream.write_all(b"ping").await.unwrap();
+        let mut pong = [0; 4];
+        stream.read_exact(&mut pong).await.unwrap();
+        assert_eq!(&pong, b"pong");
+    }
+
+    #[test]
+    fn test_http_connect() {
+        block_on(async {
+            let (addr, server) = script(vec![
+                (b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\nProxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n",
+                 b"HTTP/1.1 200 Connection established\r\nVia: test\r\n\r\n"),
+                (b"ping", b"pong"),
+            ]).await;
+            let proxy = Proxy::http(&addr).with_credentials("user", "pass");
+            ping(proxy.connect("example.com", 443).await.unwrap()).await;
+            server.await.unwrap();
+
+            let (addr, server) = script(vec![
+                (b"CONNECT [::1]:443 HTTP/1.1\r\nHost: [::1]:443\r\n\r\n", b"HTTP/1.1 200 OK\r\n\r\n"),
+                (b"ping", b"pong"),
+            ]).await;
+            ping(Proxy::http(&addr).connect("::1", 443).await.unwrap()).await;
+            server.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn test_http_connect_refused() {
+        block_on(async {
+            let (addr, server) = script(vec![
+                (b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n",
+                 b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n"),
+            ]).await;
+            let err = Proxy::http(&addr).connect("example.com", 443).await.unwrap_err();
+            assert_eq!(err.to_string(), "proxy refused CONNECT: HTTP/1.1 407 Proxy Authentication Required");
+            server.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn test_socks5() {
+        block_on(async {
+            // A host name, with an IPv4 bound address
+            let (addr, server) = script(vec![
+                (b"\x05\x01\x00", b"\x05\x00"),
+                (b"\x05\x01\x00\x03\x0bexample.com\x01\xbb", b"\x05\x00\x00\x01\x7f\x00\x00\x01\x30\x39"),
+                (b"ping", b"pong"),
+            ]).await;
+            ping(Proxy::socks5(&addr).connect("example.com", 443).await.unwrap()).await;
+            server.await.unwrap();
+
+            // An IPv4 address, with an IPv6 bound address
+            let (addr, server) = script(vec![
+                (b"\x05\x01\x02", b"\x05\x02"),
+                (b"\x01\x04user\x04pass", b"\x01\x00"),
+                (b"\x05\x01\x00\x01\x0a\x00\x00\x01\x01\xbb",
+                 b"\x05\x00\x00\x04\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x30\x39"),
+                (b"ping", b"pong"),
+            ]).await;
+            let proxy = Proxy::socks5(&addr).with_credentials("user", "pass");
+            ping(proxy.connect("10.0.0.1", 443).await.unwrap()).await;
+            server.await.unwrap();
+
+            // An IPv6 address, with a bound host name
+            let (addr, server) = script(vec![
+                (b"\x05\x01\x00", b"\x05\x00"),
+                (b"\x05\x01\x00\x04\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x01\xbb",
+                 b"\x05\x00\x00\x03\x05proxy\x30\x39"),
+                (b"ping", b"pong"),
+            ]).await;
+            ping(Proxy::socks5(&addr).connect("::1", 443).await.unwrap()).await;
+            server.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn test_socks5_auth_failure() {
+        block_on(async {
+            let (addr, server) = script(vec![
+                (b"\x05\x01\x02", b"\x05\x02"),
+                (b"\x01\x04user\x05wrong", b"\x01\x01"),
+            ]).await;
+            let proxy = Proxy::socks5(&addr).with_credentials("user", "wrong");
+            let err = proxy.connect("example.com", 443).await.unwrap_err();
+            assert_eq!(err.to_string(), "SOCKS5 authentication failed");
+            server.await.unwrap();
+        })
+    }
+
+}
@@ -0,0 +1,166 @@
+//! A bounded queue carrying server messages from the worker to the consumer,
+//! with a configurable behaviour when the consumer falls behind.
+
+use futures::{Stream, task::AtomicWaker};
+use std::{collections::VecDeque, pin::Pin, sync::{Arc, Mutex}, task::{Context, Poll}};
+use tokio::sync::Notify;
+use crate::config::OverflowPolicy;
+use crate::error::{Error, Result};
+
+struct State<T> {
+    items: VecDeque<T>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    sender_gone: bool,
+    receiver_gone: bool,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    receiver: AtomicWaker,
+    space: Notify,
+}
+
+pub(crate) struct Sender<T>(Arc<Shared<T>>);
+
+pub(crate) struct Receiver<T>(Arc<Shared<T>>);
+
+pub(crate) fn channel<T>(capacity: usize, policy: OverflowPolicy) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            items: VecDeque::new(),
+            capacity: capacity.max(1),
+            policy,
+            sender_gone: false,
+            receiver_gone: false,
+        }),
+        receiver: AtomicWaker::new(),
+        space: Notify::new(),
+    });
+    (Sender(shared.clone()), Receiver(shared))
+}
+
+impl<T> Sender<T> {
+
+    /// Queue an item. Depending on the policy, a full queue makes this wait,
+    /// drop the oldest item, or fail with [`Error::Overflow`].
+    pub async fn send(&self, item: T) -> Result<()> {
+        loop {
+            {
+                let mut state = self.0.state.lock().unwrap();
+
+                if state.receiver_gone {
+                    return Err(Error::Closed)
+                }
+
+                let full = state.items.len() >= state.capacity;
+
+                if !full || state.policy == OverflowPolicy::DropOldest {
+                    if full {
+                        state.items.pop_front();
+                    }
+                    state.items.push_back(item);
+                    drop(state);
+                    self.0.receiver.wake();
+                    return Ok(())
+                }
+
+                if state.policy == OverflowPolicy::Error {
+                    return Err(Error::Overflow)
+                }
+            }
+
+            // The receiver leaves a permit when it makes room, so this can't miss a wakeup.
+            self.0.space.notified().await;
+        }
+    }
+
+    /// Whether the receiving end was dropped.
+    pub fn is_closed(&self) -> bool {
+        self.0.state.lock().unwrap().receiver_gone
+    }
+
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().sender_gone = true;
+        self.0.receiver.wake();
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.0.receiver.register(cx.waker());
+        let mut state = self.0.state.lock().unwrap();
+        match state.items.pop_front() {
+            Some(item) => {
+                drop(state);
+                self.0.space.notify_one();
+                Poll::Ready(Some(item))
+            },
+            None if state.sender_gone => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().receiver_gone = true;
+        self.0.space.notify_one();
+    }
+}
+
+impl<T> std::fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.0.state.lock().unwrap();
+        f.debug_struct("Receiver")
+            .field("queued", &state.items.len())
+            .field("capacity", &state.capacity)
+            .field("policy", &state.policy)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use futures::{executor::block_on, stream::StreamExt};
+
+    #[test]
+    fn test_drop_oldest() {
+        let (tx, mut rx) = channel(2, OverflowPolicy::DropOldest);
+        block_on(async {
+            for i in 0..5 {
+                tx.send(i).await.unwrap();
+            }
+            drop(tx);
+            assert_eq!(rx.by_ref().collect::<Vec<_>>().await, vec![3, 4]);
+        });
+    }
+
+    #[test]
+    fn test_overflow_error() {
+        let (tx, mut rx) = channel(1, OverflowPolicy::Error);
+        block_on(async {
+            tx.send(1).await.unwrap();
+            assert!(matches!(tx.send(2).await, Err(Error::Overflow)));
+            assert_eq!(rx.next().await, Some(1));
+            tx.send(3).await.unwrap();
+            assert_eq!(rx.next().await, Some(3));
+        });
+    }
+
+    #[test]
+    fn test_receiver_gone() {
+        let (tx, rx) = channel(1, OverflowPolicy::Block);
+        drop(rx);
+        assert!(tx.is_closed());
+        assert!(matches!(block_on(tx.send(1)), Err(Error::Closed)));
+    }
+
+}
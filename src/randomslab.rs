@@ -1,9 +1,13 @@
-use slab;
-use fastrand;
+use std::collections::{HashMap, hash_map::Entry};
 
 type Label = [u8; 8];
 
-pub struct Slab<T>(slab::Slab<(Label, T)>);
+/// Entries are found by an id made of their index and a random label, or
+/// by the id they were given when inserted with [`Slab::insert_named`].
+pub struct Slab<T> {
+    entries: slab::Slab<(Label, T)>,
+    named: HashMap<String, usize>,
+}
 
 fn random_label() -> Label {
     let mut r = [0; 8];
@@ -27,32 +31,88 @@ fn split2(s: &str) -> Option<(usize, &str)> {
 impl<T> Slab<T> {
 
     pub fn new() -> Self {
-        Self(slab::Slab::new())
+        Self { entries: slab::Slab::new(), named: HashMap::new() }
     }
 
     pub fn insert(&mut self, t: T) -> String {
         let label = random_label();
-        let idx = self.0.insert((label, t));
+        let idx = self.entries.insert((label, t));
         format!("{}:{}", idx, std::str::from_utf8(&label).unwrap())
     }
 
-    /*
+    /// Insert an entry under an id of our choosing. If the id is already in
+    /// use, the entry is handed back.
+    pub fn insert_named(&mut self, id: String, t: T) -> Result<(), T> {
+        match self.named.entry(id) {
+            Entry::Occupied(_) => Err(t),
+            Entry::Vacant(slot) => {
+                slot.insert(self.entries.insert((random_label(), t)));
+                Ok(())
+            },
+        }
+    }
+
     pub fn get(&self, key: &str) -> Option<&T> {
+        if let Some(n) = self.named.get(key) {
+            return self.entries.get(*n).map(|entry| &entry.1)
+        }
         let (n, label) = split2(key)?;
-        let entry = self.0.get(n)?;
+        let entry = self.entries.get(n)?;
         if entry.0 == label.as_bytes() {
             Some(&entry.1)
         } else {
             None
         }
     }
-    */
+
+    /// The entries, with their ids.
+    pub fn iter(&self) -> impl Iterator<Item = (String, &T)> {
+        let names: HashMap<usize, &String> = self.named.iter().map(|(id, n)| (*n, id)).collect();
+        self.entries.iter().map(move |(n, (label, t))| {
+            let id = match names.get(&n) {
+                Some(id) => id.to_string(),
+                None => format!("{}:{}", n, std::str::from_utf8(label).unwrap()),
+            };
+            (id, t)
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Drop all the entries.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.named.clear();
+    }
+
+    /// Remove all the entries, returning them.
+    pub fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+        self.named.clear();
+        self.entries.drain().map(|(_, t)| t)
+    }
+
+    /// Keep only the entries matching the predicate.
+    pub fn retain<F: FnMut(&mut T) -> bool>(&mut self, mut f: F) {
+        self.entries.retain(|_, (_, t)| f(t));
+        let entries = &self.entries;
+        self.named.retain(|_, n| entries.contains(*n));
+    }
 
     pub fn remove(&mut self, key: &str) -> Option<T> {
+        if let Some(n) = self.named.remove(key) {
+            return Some(self.entries.remove(n).1)
+        }
+
         let (n, label) = split2(key)?;
 
-        if &self.0.get(n)?.0 == label.as_bytes() {
-            Some(self.0.remove(n).1)
+        if self.entries.get(n)?.0 == label.as_bytes() {
+            Some(self.entries.remove(n).1)
         } else {
             None
         }
@@ -83,4 +143,27 @@ fn test_random_slab() {
     assert_eq!(slab.remove(&l2), Some("def"));
     assert_eq!(slab.remove("nonsense"), None);
 
-}
\ No newline at end of file
+}
+#[test]
+fn test_retain() {
+
+    let mut slab = Slab::new();
+
+    let l1 = slab.insert(1);
+    let l2 = slab.insert(2);
+    let l3 = slab.insert(3);
+
+    slab.insert_named("four".to_string(), 4).unwrap();
+    slab.insert_named("five".to_string(), 5).unwrap();
+    assert_eq!(slab.insert_named("five".to_string(), 6), Err(6));
+
+    slab.retain(|n| *n != 2 && *n != 5);
+
+    assert_eq!(slab.remove(&l2), None);
+    assert_eq!(slab.remove("five"), None);
+    assert_eq!(slab.remove(&l1), Some(1));
+    assert_eq!(slab.remove(&l3), Some(3));
+    assert_eq!(slab.remove("four"), Some(4));
+    assert_eq!(slab.remove("four"), None);
+
+}
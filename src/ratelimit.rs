@@ -0,0 +1,96 @@
+//! Client-side throttling of method calls and subscriptions, to stay under
+//! the limits enforced by Meteor's `DDPRateLimiter`.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use crate::config::{Config, RateLimit};
+use crate::runtime::{self, Instant};
+
+/// A token bucket, holding up to `limit.requests` tokens and refilled at
+/// `limit.requests` per `limit.interval`. It may go into debt: the requests
+/// then wait in line for their token.
+#[derive(Debug)]
+struct Bucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+
+    fn new(limit: RateLimit) -> Self {
+        // A limit of zero would block forever
+        let limit = RateLimit { requests: limit.requests.max(1), ..limit };
+        Self { limit, tokens: limit.requests as f64, updated: Instant::now() }
+    }
+
+    /// Take a token, returning how long to wait before it is actually there.
+    fn take(&mut self, now: Instant) -> Duration {
+        let rate = self.limit.requests as f64 / self.limit.interval.as_secs_f64();
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(self.limit.requests as f64) - 1.0;
+        self.updated = now;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+
+}
+
+/// The buckets of a connection, shared by its handles.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    default: Option<Mutex<Bucket>>,
+    methods: HashMap<String, Mutex<Bucket>>,
+}
+
+impl RateLimiter {
+
+    /// The limiter described by the config, if it sets any limit.
+    pub(crate) fn new(config: &Config) -> Option<Self> {
+        if config.rate_limit.is_none() && config.method_rate_limits.is_empty() {
+            return None
+        }
+        Some(Self {
+            default: config.rate_limit.map(|limit| Mutex::new(Bucket::new(limit))),
+            methods: config.method_rate_limits.iter()
+                .map(|(method, limit)| (method.clone(), Mutex::new(Bucket::new(*limit))))
+                .collect(),
+        })
+    }
+
+    /// Wait for our turn to call `method`, or to subscribe if `None`.
+    pub(crate) async fn acquire(&self, method: Option<&str>) {
+        let bucket = match method.and_then(|m| self.methods.get(m)).or(self.default.as_ref()) {
+            Some(bucket) => bucket,
+            None => return,
+        };
+        let delay = bucket.lock().unwrap_or_else(|e| e.into_inner()).take(Instant::now());
+        if delay > Duration::from_secs(0) {
+            runtime::sleep(delay).await;
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket { limit: RateLimit { requests: 2, interval: Duration::from_secs(1) }, tokens: 2.0, updated: start };
+        assert_eq!(bucket.take(start), Duration::from_secs(0));
+        assert_eq!(bucket.take(start), Duration::from_secs(0));
+        assert_eq!(bucket.take(start), Duration::from_millis(500));
+        assert_eq!(bucket.take(start), Duration::from_secs(1));
+        // Refilled, but still in debt
+        assert_eq!(bucket.take(start + Duration::from_secs(1)), Duration::from_millis(500));
+    }
+
+}
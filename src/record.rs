@@ -0,0 +1,272 @@
+//! Recording sessions to JSON lines files, and replaying them.
+//!
+//! ```ignore
+//! let connection = Connection::builder(url)
+//!     .interceptor(Recorder::create("session.jsonl")?)
+//!     .connect().await?;
+//! // later, in a test:
+//! let replayer = Replayer::open("session.jsonl", false).await?;
+//! let connection = Connection::connect(&replayer.url()).await?;
+//! ```
+
+use futures::{sink::SinkExt, stream::StreamExt};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use async_tungstenite::tungstenite::Message;
+use tokio::net::{TcpListener, TcpStream};
+use crate::intercept::{Interceptor, Verdict};
+use log::{debug, warn};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// From the server.
+    In,
+    /// To the server.
+    Out,
+}
+
+/// A frame of a recorded session.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    /// Milliseconds since the start of the recording.
+    pub millis: u64,
+    pub direction: Direction,
+    pub frame: String,
+}
+
+/// An [`Interceptor`] writing every frame to a JSON lines file, one [`Record`] per line.
+pub struct Recorder {
+    start: Instant,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Recorder {
+
+    /// Record to a new file, replacing any existing one.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(io::BufWriter::new(std::fs::File::create(path)?)))
+    }
+
+    pub fn new<W: Write + Send + 'static>(out: W) -> Self {
+        Self { start: Instant::now(), out: Mutex::new(Box::new(out)) }
+    }
+
+    fn record(&self, direction: Direction, frame: &str) {
+        let record = Record {
+            millis: self.start.elapsed().as_millis() as u64,
+            direction,
+            frame: frame.to_string(),
+        };
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        // Flushing each line keeps the file usable if the process dies
+        let written = serde_json::to_writer(&mut *out, &record)
+            .map_err(io::Error::from)
+            .and_then(|()| out.write_all(b"\n"))
+            .and_then(|()| out.flush());
+        if let Err(e) = written {
+            warn!("Could not record frame: {}", e);
+        }
+    }
+
+}
+
+impl Interceptor for Recorder {
+
+    fn outbound(&self, frame: &mut String) -> Verdict {
+        self.record(Direction::Out, frame);
+        Verdict::Pass
+    }
+
+    fn inbound(&self, frame: &mut String) -> Verdict {
+        self.record(Direction::In, frame);
+        Verdict::Pass
+    }
+
+}
+
+impl std::fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Recorder").field("start", &self.start).finish()
+    }
+}
+
+/// Read the records of a file written by a [`Recorder`].
+pub fn read_records<P: AsRef<Path>>(path: P) -> io::Result<Vec<Record>> {
+    io::BufReader::new(std::fs::File::open(path)?).lines()
+        .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+/// The `msg` and `id` fields of a frame.
+fn message_id(frame: &Value) -> (Option<&str>, Option<&str>) {
+    (frame.get("msg").and_then(Value::as_str), frame.get("id").and_then(Value::as_str))
+}
+
+/// Replace recorded method ids with the ones of the new session.
+fn rewrite(frame: &str, ids: &HashMap<String, String>) -> String {
+    let mut value: Value = match serde_json::from_str(frame) {
+        Ok(value) => value,
+        Err(_) => return frame.to_string(),
+    };
+    let swap = |id: &mut Value| {
+        if let Some(new) = id.as_str().and_then(|id| ids.get(id)) {
+            *id = Value::String(new.clone());
+        }
+    };
+    match value.get("msg").and_then(Value::as_str) {
+        Some("result") => value.get_mut("id").map(swap).unwrap_or_default(),
+        Some("updated") => {
+            if let Some(Value::Array(methods)) = value.get_mut("methods") {
+                methods.iter_mut().for_each(swap);
+            }
+        },
+        _ => return frame.to_string(),
+    }
+    value.to_string()
+}
+
+/// A local websocket server playing back the server side of a recorded session
+/// to the first client connecting. The client is expected to send the same
+/// messages as in the recording: the server's frames following a client frame
+/// are only sent once the client has sent its counterpart. Method ids are
+/// translated, so that results reach the new calls.
+#[derive(Debug)]
+pub struct Replayer {
+    addr: SocketAddr,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Replayer {
+
+    /// Replay a recording. With `realtime`, the server's frames are spaced as
+    /// they were originally; otherwise they are sent as soon as possible.
+    pub async fn start(records: Vec<Record>, realtime: bool) -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let task = tokio::spawn(async move {
+            let result = match listener.accept().await {
+                Ok((stream, _)) => replay(stream, records, realtime).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                warn!("Replay failed: {}", e);
+            }
+        });
+        Ok(Self { addr, task })
+    }
+
+    /// Replay a file written by a [`Recorder`].
+    pub async fn open<P: AsRef<Path>>(path: P, realtime: bool) -> io::Result<Self> {
+        Self::start(read_records(path)?, realtime).await
+    }
+
+    /// The url to connect to.
+    pub fn url(&self) -> String {
+        format!("ws://{}/websocket", self.addr)
+    }
+
+}
+
+impl Drop for Replayer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn ws_error(e: async_tungstenite::tungstenite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+async fn replay(stream: TcpStream, records: Vec<Record>, realtime: bool) -> io::Result<()> {
+
+    debug!("Replaying {} frames to {:?}", records.len(), stream.peer_addr());
+    let mut ws = async_tungstenite::tokio::accept_async(stream).await.map_err(ws_error)?;
+
+    // In case the recording lacks the greeting; the client ignores duplicates
+    ws.send(Message::Text(r#"{"server_id":"0"}"#.to_string())).await.map_err(ws_error)?;
+
+    let mut ids = HashMap::new();
+    let mut clock = 0;
+
+    for record in records {
+        match record.direction {
+            Direction::Out => {
+                let recorded: Value = serde_json::from_str(&record.frame).unwrap_or(Value::Null);
+                let actual: Value = loop {
+                    match ws.next().await {
+                        Some(Ok(Message::Text(txt))) => {
+                            let actual: Value = serde_json::from_str(&txt)?;
+                            let (msg, _) = message_id(&actual);
+                            // Heartbeats depend on timing, not on the session
+                            if matches!(msg, Some("ping") | Some("pong")) && message_id(&recorded).0 != msg {
+                                continue
+                            }
+                            break actual
+                        },
+                        Some(Ok(_)) => continue,
+                        _ => return Ok(()),
+                    }
+                };
+                if let ((Some("method"), Some(old)), (Some("method"), Some(new))) = (message_id(&recorded), message_id(&actual)) {
+                    ids.insert(old.to_string(), new.to_string());
+                }
+            },
+            Direction::In => {
+                if realtime {
+                    tokio::time::sleep(Duration::from_millis(record.millis.saturating_sub(clock))).await;
+                }
+                clock = record.millis;
+                ws.send(Message::Text(rewrite(&record.frame, &ids))).await.map_err(ws_error)?;
+            },
+        }
+    }
+
+    // Let the client leave on its own terms
+    while let Some(Ok(_)) = ws.next().await {}
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::Connection;
+    use crate::testing::{block_on, MockServer};
+    use serde_json::json;
+
+    #[test]
+    fn test_rewrite() {
+        let ids = [("0:old".to_string(), "3:new".to_string())].iter().cloned().collect();
+        assert_eq!(rewrite(r#"{"msg":"result","id":"0:old","result":1}"#, &ids), r#"{"id":"3:new","msg":"result","result":1}"#);
+        assert_eq!(rewrite(r#"{"msg":"updated","methods":["0:old"]}"#, &ids), r#"{"methods":["3:new"],"msg":"updated"}"#);
+    }
+
+    #[test]
+    fn test_record_replay() {
+        let path = std::env::temp_dir().join(format!("siderite-record-{}.jsonl", std::process::id()));
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            server.on_method("answer", |_| Ok(json!(42)));
+            let mut connection = Connection::builder(&server.url())
+                .interceptor(Recorder::create(&path).unwrap())
+                .connect().await.unwrap();
+            assert_eq!(connection.call("answer".to_string(), ()).await.unwrap(), Ok(json!(42)));
+            connection.close().await.unwrap();
+            drop(server);
+
+            let replayer = Replayer::open(&path, false).await.unwrap();
+            let mut connection = Connection::connect(&replayer.url()).await.unwrap();
+            assert_eq!(connection.call("answer".to_string(), ()).await.unwrap(), Ok(json!(42)));
+        });
+        let _ = std::fs::remove_file(path);
+    }
+
+}
@@ -0,0 +1,114 @@
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use crate::runtime;
+
+/// Resolves host names to addresses when dialing, in place of the system
+/// resolver. Set it with [`crate::ConnectionBuilder::resolver`]; it is not
+/// used through a proxy, which resolves names itself.
+///
+/// Adapting an async resolver such as trust-dns only takes boxing its lookup:
+///
+/// ```ignore
+/// impl Resolver for MyDns {
+///     fn resolve<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+///         Box::pin(async move {
+///             let ips = self.0.lookup_ip(host).await?;
+///             Ok(ips.iter().map(|ip| SocketAddr::new(ip, port)).collect())
+///         })
+///     }
+/// }
+/// ```
+pub trait Resolver: Send + Sync {
+    /// The addresses to try, in order, to reach `host` on `port`.
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>>;
+}
+
+/// The system resolver, as used by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        Box::pin(crate::runtime::lookup_host(host, port))
+    }
+}
+
+/// Fixed addresses for some hosts, like an `/etc/hosts` of its own. Other
+/// hosts go to the system resolver, unless [`StaticResolver::strict`].
+#[derive(Clone, Debug, Default)]
+pub struct StaticResolver {
+    hosts: HashMap<String, Vec<IpAddr>>,
+    strict: bool,
+}
+
+impl StaticResolver {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolve `host` to `addr`. Calling this again for the same host adds
+    /// another address to try.
+    pub fn host(mut self, host: &str, addr: IpAddr) -> Self {
+        self.hosts.entry(host.to_ascii_lowercase()).or_default().push(addr);
+        self
+    }
+
+    /// Fail to resolve the hosts without a fixed address.
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+}
+
+impl Resolver for StaticResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        match self.hosts.get(&host.to_ascii_lowercase()) {
+            Some(addrs) => {
+                let addrs = addrs.iter().map(|ip| SocketAddr::new(*ip, port)).collect();
+                Box::pin(async move { Ok(addrs) })
+            },
+            None if self.strict => Box::pin(async move {
+                Err(io::Error::new(io::ErrorKind::NotFound, format!("no address for {}", host)))
+            }),
+            None => SystemResolver.resolve(host, port),
+        }
+    }
+}
+
+/// Open a TCP stream to the first address of `host` that accepts it.
+pub(crate) async fn connect(resolver: &dyn Resolver, host: &str, port: u16) -> io::Result<runtime::TcpStream> {
+    let mut last = io::Error::new(io::ErrorKind::NotFound, format!("no address for {}", host));
+    for addr in resolver.resolve(host, port).await? {
+        match runtime::connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last = e,
+        }
+    }
+    Err(last)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::{block_on, MockServer};
+    use crate::Connection;
+
+    #[test]
+    fn test_static_resolver() {
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            let url = server.url().replace("127.0.0.1", "ddp.test");
+            let resolver = StaticResolver::new().host("ddp.test", "127.0.0.1".parse().unwrap()).strict();
+            assert!(resolver.resolve("elsewhere.test", 80).await.is_err());
+
+            let connection = Connection::builder(&url).resolver(resolver).connect().await.unwrap();
+            assert!(connection.session().is_some());
+        });
+    }
+
+}
@@ -0,0 +1,153 @@
+//! Typed wrappers for the realtime API of [Rocket.Chat](https://developer.rocket.chat/reference/api/realtime-api).
+//!
+//! ```ignore
+//! let me = rocketchat::login(&mut handle, &User::username("bot"), &Password::hash("secret")).await?;
+//! let mut messages = rocketchat::room_messages(&mut handle, "GENERAL").await?;
+//! while let Some(message) = messages.next().await {
+//!     let message = message?;
+//!     rocketchat::send_message(&mut handle, &message.room_id, "pong").await?;
+//! }
+//! ```
+
+use futures::{Stream, StreamExt};
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use crate::connection::Handle;
+use crate::error::{MethodError, Result};
+use crate::ids;
+use crate::login::{self, LoginResult, Password, User};
+use crate::protocol::Timestamp;
+use crate::streamer::Streamer;
+
+/// The author of a message.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserRef {
+    #[serde(rename="_id")]
+    pub id: String,
+    pub username: String,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// A chat message, as sent by `sendMessage` and `stream-room-messages`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    #[serde(rename="_id")]
+    pub id: String,
+    #[serde(rename="rid")]
+    pub room_id: String,
+    pub msg: String,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub ts: Option<Timestamp>,
+    #[serde(default, rename="u", skip_serializing_if="Option::is_none")]
+    pub user: Option<UserRef>,
+    /// The type of system messages, such as `uj` when a user joins.
+    #[serde(default, rename="t", skip_serializing_if="Option::is_none")]
+    pub kind: Option<String>,
+    /// The message starting the thread this one belongs to.
+    #[serde(default, rename="tmid", skip_serializing_if="Option::is_none")]
+    pub thread_id: Option<String>,
+}
+
+/// Log in with a username, email or user id, and a password.
+pub async fn login(handle: &mut Handle, user: &User, password: &Password) -> std::result::Result<LoginResult, MethodError> {
+    login::login_with_password(handle, user, password).await
+}
+
+/// Log in with a personal access token, or the token of a previous login.
+pub async fn login_with_token(handle: &mut Handle, token: &str) -> std::result::Result<LoginResult, MethodError> {
+    login::login_with_token(handle, token).await
+}
+
+/// Post a message to a room, and get it back as stored by the server.
+pub async fn send_message(handle: &mut Handle, room_id: &str, text: &str) -> std::result::Result<Message, MethodError> {
+    let message = json!({ "_id": ids::random_id(), "rid": room_id, "msg": text });
+    handle.call_typed("sendMessage", (message,)).await
+}
+
+/// The events of a Rocket.Chat stream, such as `stream-room-messages`.
+///
+/// Streams are [`Streamer`]s; only the events of the subscribed name are
+/// yielded, as their arguments. The subscription is stopped when this is
+/// dropped.
+#[derive(Debug)]
+pub struct Events {
+    streamer: Streamer,
+}
+
+impl Events {
+
+    /// Subscribe to the events named `event` of a stream.
+    pub async fn subscribe(handle: &mut Handle, stream: &str, event: &str) -> Result<Self> {
+        Ok(Self { streamer: Streamer::subscribe(handle, stream, &[event]).await? })
+    }
+
+    pub fn event(&self) -> &str {
+        &self.streamer.events()[0]
+    }
+
+}
+
+impl Stream for Events {
+    type Item = Vec<Value>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Vec<Value>>> {
+        self.streamer.poll_next_unpin(cx).map(|event| event.map(|(_, args)| args))
+    }
+}
+
+/// The new messages of a room, from `stream-room-messages`. Messages that
+/// cannot be decoded yield an error, and the stream goes on.
+pub async fn room_messages(handle: &mut Handle, room_id: &str) -> Result<impl Stream<Item = Result<Message>>> {
+    let events = Events::subscribe(handle, "stream-room-messages", room_id).await?;
+    Ok(events.filter_map(|mut args| async move {
+        if args.is_empty() {
+            return None
+        }
+        Some(crate::ejson::from_value(args.swap_remove(0)).map_err(Into::into))
+    }))
+}
+
+/// The events of a user, from `stream-notify-user`, such as `notification`,
+/// `message` or `rooms-changed`. Only the logged in user can be listened to.
+pub async fn notify_user(handle: &mut Handle, user_id: &str, event: &str) -> Result<Events> {
+    Events::subscribe(handle, "stream-notify-user", &format!("{}/{}", user_id, event)).await
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::{block_on, MockServer};
+    use crate::Connection;
+
+    #[test]
+    fn test_room_messages() {
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            server.on_method("sendMessage", |params| Ok(params[0].clone()));
+            server.publish("stream-room-messages", vec![]);
+            let connection = Connection::connect(&server.url()).await.unwrap();
+            let mut handle = connection.handle();
+
+            let sent = send_message(&mut handle, "GENERAL", "hello").await.unwrap();
+            assert_eq!((sent.room_id.as_str(), sent.msg.as_str()), ("GENERAL", "hello"));
+
+            let messages = room_messages(&mut handle, "GENERAL").await.unwrap();
+            futures::pin_mut!(messages);
+            for (room, text) in &[("random", "elsewhere"), ("GENERAL", "hi")] {
+                server.changed("stream-room-messages", "id", json!({
+                    "eventName": room,
+                    "args": [{"_id": "m1", "rid": room, "msg": text, "ts": {"$date": 1000}, "u": {"_id": "u1", "username": "bob"}}],
+                }), vec![]);
+            }
+            let message = messages.next().await.unwrap().unwrap();
+            assert_eq!(message.msg, "hi");
+            assert_eq!(message.user.unwrap().username, "bob");
+            assert_eq!(message.ts, Some(Timestamp::from_millis(1000)));
+        });
+    }
+
+}
@@ -0,0 +1,212 @@
+//! The async runtime services used by connections: spawning tasks, timers,
+//! and opening TCP streams. Besides these, connections only need `futures`
+//! and the runtime-agnostic `tokio::sync` channels.
+//!
+//! Connections run on tokio by default, and on async-std with the
+//! `async-std-runtime` feature, which also serves smol. TCP streams are then
+//! seen through tokio's IO traits, so that TLS and proxies work the same.
+
+use futures::future::{Future, FutureExt};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+#[cfg(not(feature = "async-std-runtime"))]
+pub(crate) use tokio::time::Instant;
+#[cfg(feature = "async-std-runtime")]
+pub(crate) use std::time::Instant;
+
+/// A TCP stream of the runtime, with tokio's IO traits.
+#[cfg(not(feature = "async-std-runtime"))]
+pub(crate) type TcpStream = tokio::net::TcpStream;
+#[cfg(feature = "async-std-runtime")]
+pub(crate) type TcpStream = tokio_util::compat::Compat<async_std::net::TcpStream>;
+
+/// A task running in the background. It resolves to the task's output, or to
+/// `None` if the task panicked. Dropping it lets the task run on its own.
+#[cfg(not(feature = "async-std-runtime"))]
+#[derive(Debug)]
+pub(crate) struct Task<T>(tokio::task::JoinHandle<T>);
+
+#[cfg(not(feature = "async-std-runtime"))]
+impl<T> Future for Task<T> {
+    type Output = Option<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.0.poll_unpin(cx).map(Result::ok)
+    }
+}
+
+#[cfg(not(feature = "async-std-runtime"))]
+impl<T> Task<T> {
+
+    /// Stop the task at its next suspension point.
+    pub(crate) fn abort(&self) {
+        self.0.abort()
+    }
+
+}
+
+/// A task running in the background. It resolves to the task's output, or to
+/// `None` if the task panicked or was aborted. Dropping it lets the task run
+/// on its own.
+#[cfg(feature = "async-std-runtime")]
+#[derive(Debug)]
+pub(crate) struct Task<T> {
+    handle: async_std::task::JoinHandle<Option<T>>,
+    abort: futures::future::AbortHandle,
+}
+
+#[cfg(feature = "async-std-runtime")]
+impl<T> Future for Task<T> {
+    type Output = Option<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.handle.poll_unpin(cx)
+    }
+}
+
+#[cfg(feature = "async-std-runtime")]
+impl<T> Task<T> {
+
+    /// Stop the task at its next suspension point.
+    pub(crate) fn abort(&self) {
+        self.abort.abort()
+    }
+
+}
+
+#[cfg(not(feature = "async-std-runtime"))]
+pub(crate) fn spawn<F>(task: F) -> Task<F::Output>
+    where F: Future + Send + 'static, F::Output: Send + 'static
+{
+    Task(tokio::spawn(task))
+}
+
+#[cfg(feature = "async-std-runtime")]
+pub(crate) fn spawn<F>(task: F) -> Task<F::Output>
+    where F: Future + Send + 'static, F::Output: Send + 'static
+{
+    let (abort, registration) = futures::future::AbortHandle::new_pair();
+    // Boxed first, so that the wrappers do not each copy a large future
+    let task = futures::future::Abortable::new(std::panic::AssertUnwindSafe(Box::pin(task)).catch_unwind(), registration)
+        .map(|res| res.ok().and_then(Result::ok));
+    Task { handle: async_std::task::spawn(task), abort }
+}
+
+/// Like [`spawn`], from a context that may not be within the runtime, such as
+/// a destructor. Returns false if the task could not be started.
+#[cfg(not(feature = "async-std-runtime"))]
+pub(crate) fn try_spawn<F>(task: F) -> bool
+    where F: Future + Send + 'static, F::Output: Send + 'static
+{
+    match tokio::runtime::Handle::try_current() {
+        Ok(runtime) => {
+            runtime.spawn(task);
+            true
+        },
+        Err(_) => false,
+    }
+}
+
+/// Like [`spawn`], from a context that may not be within the runtime, such as
+/// a destructor. The async-std executor is global, so this always succeeds.
+#[cfg(feature = "async-std-runtime")]
+pub(crate) fn try_spawn<F>(task: F) -> bool
+    where F: Future + Send + 'static, F::Output: Send + 'static
+{
+    async_std::task::spawn(task);
+    true
+}
+
+#[cfg(not(feature = "async-std-runtime"))]
+pub(crate) async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await
+}
+
+#[cfg(feature = "async-std-runtime")]
+pub(crate) async fn sleep(duration: Duration) {
+    async_std::task::sleep(duration).await
+}
+
+#[cfg(not(feature = "async-std-runtime"))]
+pub(crate) async fn sleep_until(deadline: Instant) {
+    tokio::time::sleep_until(deadline).await
+}
+
+#[cfg(feature = "async-std-runtime")]
+pub(crate) async fn sleep_until(deadline: Instant) {
+    async_std::task::sleep(deadline.saturating_duration_since(Instant::now())).await
+}
+
+/// Run a future for at most `duration`. Returns `None` if it did not complete in time.
+#[cfg(not(feature = "async-std-runtime"))]
+pub(crate) async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    tokio::time::timeout(duration, future).await.ok()
+}
+
+/// Run a future for at most `duration`. Returns `None` if it did not complete in time.
+#[cfg(feature = "async-std-runtime")]
+pub(crate) async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    async_std::future::timeout(duration, future).await.ok()
+}
+
+/// Open a TCP stream to `addr`, such as `"host:port"` or a socket address.
+#[cfg(not(feature = "async-std-runtime"))]
+pub(crate) async fn connect(addr: impl tokio::net::ToSocketAddrs) -> io::Result<TcpStream> {
+    TcpStream::connect(addr).await
+}
+
+/// Open a TCP stream to `addr`, such as `"host:port"` or a socket address.
+#[cfg(feature = "async-std-runtime")]
+pub(crate) async fn connect(addr: impl async_std::net::ToSocketAddrs) -> io::Result<TcpStream> {
+    use tokio_util::compat::FuturesAsyncReadCompatExt;
+    Ok(async_std::net::TcpStream::connect(addr).await?.compat())
+}
+
+/// Resolve `host` with the system resolver.
+#[cfg(not(feature = "async-std-runtime"))]
+pub(crate) async fn lookup_host(host: &str, port: u16) -> io::Result<Vec<std::net::SocketAddr>> {
+    Ok(tokio::net::lookup_host((host, port)).await?.collect())
+}
+
+/// Resolve `host` with the system resolver.
+#[cfg(feature = "async-std-runtime")]
+pub(crate) async fn lookup_host(host: &str, port: u16) -> io::Result<Vec<std::net::SocketAddr>> {
+    use async_std::net::ToSocketAddrs;
+    Ok((host, port).to_socket_addrs().await?.collect())
+}
+
+#[cfg(all(test, feature = "async-std-runtime"))]
+mod tests {
+
+    use super::*;
+    use crate::Connection;
+    use crate::testing::{block_on, MockServer};
+    use serde_json::json;
+
+    #[test]
+    fn test_async_std_runtime() {
+        // The mock server needs tokio, on a thread of its own
+        let (url_tx, url_rx) = std::sync::mpsc::channel();
+        let (stop_tx, stop_rx) = futures::channel::oneshot::channel::<()>();
+        let server = std::thread::spawn(move || {
+            block_on(async {
+                let server = MockServer::start().await.unwrap();
+                server.on_method("echo", |params| Ok(params[0].clone()));
+                url_tx.send(server.url()).unwrap();
+                let _ = stop_rx.await;
+            });
+        });
+
+        let url = url_rx.recv().unwrap();
+        async_std::task::block_on(async {
+            let mut connection = Connection::builder(&url).call_timeout(Duration::from_secs(1)).connect().await.unwrap();
+            assert_eq!(connection.call("echo".to_string(), ("hi",)).await.unwrap(), Ok(json!("hi")));
+        });
+        stop_tx.send(()).unwrap();
+        server.join().unwrap();
+    }
+
+}
@@ -0,0 +1,297 @@
+//! The server side of DDP: the handshake, heartbeats and session ids, over
+//! an accepted websocket. What to do with methods and subscriptions is up
+//! to the application.
+//!
+//! ```ignore
+//! let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
+//! let (tcp, _) = listener.accept().await?;
+//! let mut session = server::accept(tcp).await?;
+//! while let Some(msg) = session.recv().await? {
+//!     if let ClientMessage::Method { id, .. } = msg {
+//!         session.send(&ServerMessage::Result(MethodResponse { id, result: None, error: None })).await?;
+//!     }
+//! }
+//! ```
+
+use futures::{AsyncRead, AsyncWrite, sink::SinkExt, stream::StreamExt};
+use async_tungstenite::{WebSocketStream, tungstenite::Message};
+use async_tungstenite::tungstenite::protocol::{CloseFrame, frame::coding::CloseCode};
+use async_tungstenite::tokio::TokioAdapter;
+use tokio::net::TcpStream;
+use crate::error::{Error, Result};
+use crate::ids::random_id;
+use crate::protocol::{ClientMessage, ServerMessage};
+use log::{debug, trace};
+
+/// The DDP versions we speak, by order of preference.
+pub const SUPPORTED_VERSIONS: &[&str] = &["1"];
+
+/// A random session id, in the style of Meteor's `Random.id()`.
+fn session_id() -> String {
+    random_id()
+}
+
+/// The version to use for a `connect` message, or the one to propose instead.
+fn negotiate(version: &str, support: &[String]) -> std::result::Result<String, String> {
+    if SUPPORTED_VERSIONS.contains(&version) {
+        return Ok(version.to_string())
+    }
+    let proposal = support.iter()
+        .find(|v| SUPPORTED_VERSIONS.contains(&v.as_str()))
+        .cloned()
+        .unwrap_or_else(|| SUPPORTED_VERSIONS[0].to_string());
+    Err(proposal)
+}
+
+/// An established DDP session with a client.
+#[derive(Debug)]
+pub struct Session<S> {
+    id: String,
+    version: String,
+    ws: WebSocketStream<S>,
+}
+
+/// Accept a websocket on a TCP connection, and perform the DDP handshake.
+pub async fn accept(stream: TcpStream) -> Result<Session<TokioAdapter<TcpStream>>> {
+    let ws = async_tungstenite::tokio::accept_async(stream).await?;
+    Session::handshake(ws).await
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Session<S> {
+
+    /// Perform the DDP handshake on an accepted websocket. If the client does
+    /// not speak a version we support, it is told so with a `failed` message,
+    /// and the websocket is closed.
+    pub async fn handshake(mut ws: WebSocketStream<S>) -> Result<Self> {
+
+        ws.send(Message::Text(r#"{"server_id":"0"}"#.to_string())).await?;
+
+        let (version, support) = match Self::read(&mut ws).await? {
+            Some(ClientMessage::Connect { version, support, .. }) => (version, support),
+            Some(other) => return Err(Error::Handshake(format!("expected connect, got {:?}", other))),
+            None => return Err(Error::Handshake("client left".to_string())),
+        };
+
+        match negotiate(&version, &support) {
+            Ok(version) => {
+                // Sessions are never resumed, as we keep no state across websockets
+                let id = session_id();
+                let mut session = Self { id: id.clone(), version, ws };
+                session.send(&ServerMessage::Connected { session: id }).await?;
+                debug!("New session {}", session.id);
+                Ok(session)
+            },
+            Err(proposal) => {
+                let msg = serde_json::to_string(&ServerMessage::Failed { version: proposal })?;
+                ws.send(Message::Text(msg)).await?;
+                let _ = ws.close(None).await;
+                Err(Error::Handshake(format!("unsupported DDP version {}", version)))
+            },
+        }
+    }
+
+    /// Read the next DDP message, skipping control frames. Binary frames are
+    /// read as UTF-8 text, as by the client.
+    async fn read(ws: &mut WebSocketStream<S>) -> Result<Option<ClientMessage>> {
+        while let Some(frame) = ws.next().await {
+            let txt = match frame? {
+                Message::Text(txt) => txt,
+                Message::Binary(data) => String::from_utf8(data)
+                    .map_err(|e| Error::Protocol(format!("binary frame is not UTF-8: {}", e)))?,
+                Message::Close(_) => break,
+                _ => continue,
+            };
+            trace!("<= {}", txt);
+            return Ok(Some(serde_json::from_str(&txt)?))
+        }
+        Ok(None)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The negotiated DDP version.
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// The next message from the client, or `None` once it has left.
+    /// Pings are answered, and pongs swallowed.
+    pub async fn recv(&mut self) -> Result<Option<ClientMessage>> {
+        loop {
+            match Self::read(&mut self.ws).await? {
+                Some(ClientMessage::Ping { id }) => self.send(&ServerMessage::Pong { id }).await?,
+                Some(ClientMessage::Pong { .. }) => (),
+                other => return Ok(other),
+            }
+        }
+    }
+
+    pub async fn send(&mut self, msg: &ServerMessage) -> Result<()> {
+        let payload = serde_json::to_string(msg)?;
+        trace!("=> {}", payload);
+        self.ws.send(Message::Text(payload)).await?;
+        Ok(())
+    }
+
+    /// Check that the client is alive. Its pong is swallowed by [`Session::recv`].
+    pub async fn ping(&mut self) -> Result<()> {
+        self.send(&ServerMessage::Ping { id: None }).await
+    }
+
+    /// Close the websocket normally, which ends the client's connection.
+    pub async fn close(self) -> Result<()> {
+        self.close_with(1000, "").await
+    }
+
+    /// Close the websocket with a status code. Clients reconnect unless the
+    /// code is 1000, or they are not set to reconnect.
+    pub async fn close_with(mut self, code: u16, reason: &str) -> Result<()> {
+        let frame = CloseFrame { code: CloseCode::from(code), reason: reason.to_string().into() };
+        self.ws.close(Some(frame)).await?;
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::Connection;
+    use crate::protocol::MethodResponse;
+    use crate::testing::{block_on, reconnect_every};
+    use serde_json::json;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate("1", &[]), Ok("1".to_string()));
+        assert_eq!(negotiate("pre2", &["pre2".to_string(), "1".to_string()]), Err("1".to_string()));
+        assert_eq!(session_id().len(), 17);
+    }
+
+    #[test]
+    fn test_session() {
+        block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}/websocket", listener.local_addr().unwrap());
+
+            tokio::spawn(async move {
+                let mut session = accept(listener.accept().await.unwrap().0).await.unwrap();
+                while let Some(msg) = session.recv().await.unwrap() {
+                    if let ClientMessage::Method { id, .. } = msg {
+                        let response = MethodResponse { id, result: Some(json!("pong")), error: None };
+                        session.send(&ServerMessage::Result(response)).await.unwrap();
+                    }
+                }
+            });
+
+            let mut connection = Connection::connect(&url).await.unwrap();
+            assert_eq!(connection.session().unwrap().len(), 17);
+            assert_eq!(connection.call("ping".to_string(), ()).await.unwrap(), Ok(json!("pong")));
+        });
+    }
+
+    #[test]
+    fn test_closed_by_server() {
+        block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}/websocket", listener.local_addr().unwrap());
+
+            tokio::spawn(async move {
+                let session = accept(listener.accept().await.unwrap().0).await.unwrap();
+                session.close().await.unwrap();
+            });
+
+            // A normal close ends the connection, whatever the reconnection policy
+            let mut connection = Connection::connect(&url).await.unwrap();
+            assert_eq!(connection.recv().await, None);
+            assert_eq!(connection.close_reason().map(|r| r.code), Some(1000));
+            assert!(connection.take_error().is_none());
+        });
+    }
+
+    #[test]
+    fn test_closed_for_restart() {
+        block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}/websocket", listener.local_addr().unwrap());
+
+            tokio::spawn(async move {
+                let session = accept(listener.accept().await.unwrap().0).await.unwrap();
+                session.close_with(1012, "service restart").await.unwrap();
+                let mut session = accept(listener.accept().await.unwrap().0).await.unwrap();
+                while session.recv().await.unwrap().is_some() {}
+            });
+
+            let connection = Connection::builder(&url).reconnect(reconnect_every(std::time::Duration::from_millis(100))).connect().await.unwrap();
+            let mut status = connection.status();
+            while *status.borrow() != crate::ConnectionStatus::Reconnecting {
+                status.changed().await.unwrap();
+            }
+            let reason = connection.close_reason().unwrap();
+            assert_eq!((reason.code, reason.reason.as_str()), (1012, "service restart"));
+
+            // Forgotten once reconnected
+            while *status.borrow() != crate::ConnectionStatus::Connected {
+                status.changed().await.unwrap();
+            }
+            assert_eq!(connection.close_reason(), None);
+        });
+    }
+
+    #[test]
+    fn test_closed_going_away() {
+        block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}/websocket", listener.local_addr().unwrap());
+
+            tokio::spawn(async move {
+                // Go away from the first two connections, then stay up
+                for _ in 0..2 {
+                    let session = accept(listener.accept().await.unwrap().0).await.unwrap();
+                    session.close_with(1001, "shutting down").await.unwrap();
+                }
+                let mut session = accept(listener.accept().await.unwrap().0).await.unwrap();
+                while session.recv().await.unwrap().is_some() {}
+            });
+
+            // Without a reconnection policy, a server going away ends the connection
+            let mut connection = Connection::builder(&url).reconnect(crate::ReconnectPolicy::Never).connect().await.unwrap();
+            assert_eq!(connection.recv().await, None);
+            assert_eq!(connection.close_reason().map(|r| r.code), Some(1001));
+            assert!(connection.take_error().is_none());
+
+            // Otherwise, it is reconnected to
+            let connection = Connection::builder(&url).reconnect(reconnect_every(std::time::Duration::from_millis(100))).connect().await.unwrap();
+            let mut status = connection.status();
+            while *status.borrow() != crate::ConnectionStatus::Reconnecting {
+                status.changed().await.unwrap();
+            }
+            assert_eq!(connection.close_reason().map(|r| r.code), Some(1001));
+            while *status.borrow() != crate::ConnectionStatus::Connected {
+                status.changed().await.unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn test_version_fallback() {
+        block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}/websocket", listener.local_addr().unwrap());
+
+            tokio::spawn(async move {
+                // The client first proposes pre2, and comes back with the suggested version
+                assert!(accept(listener.accept().await.unwrap().0).await.is_err());
+                let mut session = accept(listener.accept().await.unwrap().0).await.unwrap();
+                while session.recv().await.unwrap().is_some() {}
+            });
+
+            let connection = Connection::builder(&url).versions(vec!["pre2", "1"]).connect().await.unwrap();
+            assert_eq!(connection.info().version, "1");
+        });
+    }
+
+}
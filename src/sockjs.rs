@@ -0,0 +1,66 @@
+//! The websocket transport of [SockJS](https://github.com/sockjs/sockjs-protocol),
+//! which Meteor serves at `/sockjs/<server>/<session>/websocket`. The server
+//! wraps messages in frames: `o` opens the session, `h` is a heartbeat,
+//! `a[...]` carries an array of messages, and `c[code, reason]` closes the session.
+//! The client sends arrays of messages.
+
+use serde_json::Value;
+use crate::error::{Error, Result};
+
+/// A frame from a SockJS server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Frame {
+    Open,
+    Heartbeat,
+    Messages(Vec<String>),
+    Close { code: u64, reason: String },
+}
+
+/// The websocket url of a fresh SockJS session on a Meteor server, given its
+/// base url, e.g. `wss://example.com/` gives `wss://example.com/sockjs/123/abcdefgh/websocket`.
+pub fn websocket_url(base: &str) -> String {
+    const CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789_";
+    let session: String = (0..8).map(|_| CHARS[fastrand::usize(..CHARS.len())] as char).collect();
+    format!("{}/sockjs/{:03}/{}/websocket", base.trim_end_matches('/'), fastrand::u32(..1000), session)
+}
+
+/// Wrap a message for the server.
+pub fn encode(payload: &str) -> String {
+    Value::Array(vec![Value::String(payload.to_string())]).to_string()
+}
+
+pub fn decode(frame: &str) -> Result<Frame> {
+    let malformed = || Error::Protocol(format!("malformed SockJS frame: {}", frame));
+    let (kind, body) = frame.split_at(frame.char_indices().nth(1).map_or(frame.len(), |(i, _)| i));
+    match kind {
+        "o" => Ok(Frame::Open),
+        "h" => Ok(Frame::Heartbeat),
+        "a" => Ok(Frame::Messages(serde_json::from_str(body).map_err(|_| malformed())?)),
+        "c" => {
+            let (code, reason): (u64, String) = serde_json::from_str(body).map_err(|_| malformed())?;
+            Ok(Frame::Close { code, reason })
+        },
+        _ => Err(malformed()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_frames() {
+        assert_eq!(decode("o").unwrap(), Frame::Open);
+        assert_eq!(decode("h").unwrap(), Frame::Heartbeat);
+        assert_eq!(decode(r#"a["{\"msg\":\"ping\"}","x"]"#).unwrap(),
+                   Frame::Messages(vec![r#"{"msg":"ping"}"#.to_string(), "x".to_string()]));
+        assert_eq!(decode(r#"c[3000,"Go away!"]"#).unwrap(), Frame::Close { code: 3000, reason: "Go away!".to_string() });
+        assert!(decode("z").is_err());
+        assert_eq!(encode(r#"{"msg":"pong"}"#), r#"["{\"msg\":\"pong\"}"]"#);
+
+        let url = websocket_url("wss://example.com/");
+        assert!(url.starts_with("wss://example.com/sockjs/") && url.ends_with("/websocket"));
+    }
+
+}
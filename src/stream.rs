@@ -0,0 +1,154 @@
+//! Combinators over streams of server messages, such as [`crate::Messages`],
+//! for the common ways of picking messages out of them.
+//!
+//! ```ignore
+//! use siderite::DdpStreamExt;
+//!
+//! let mut rooms = messages.by_ref().filter_collection("rooms").added_only();
+//! ```
+
+use futures::{Future, Stream, StreamExt};
+use serde_json::Value;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use crate::connection::RPCError;
+use crate::protocol::ServerMessage;
+use crate::subscription::SubscriptionResult;
+
+/// The collection of a document message.
+fn collection(msg: &ServerMessage) -> Option<&str> {
+    match msg {
+        ServerMessage::Added { collection, .. } |
+        ServerMessage::AddedBefore { collection, .. } |
+        ServerMessage::Changed { collection, .. } |
+        ServerMessage::Removed { collection, .. } => Some(collection),
+        _ => None,
+    }
+}
+
+/// Extension methods for streams of [`ServerMessage`]s. They need the
+/// stream to be [`Unpin`]; pin others with [`Box::pin`] first.
+pub trait DdpStreamExt: Stream<Item = ServerMessage> + Unpin + Sized {
+
+    /// Only the document messages of `collection`.
+    fn filter_collection(self, collection: &str) -> FilterCollection<Self> {
+        FilterCollection { stream: self, collection: collection.to_string() }
+    }
+
+    /// Only the `added` and `addedBefore` messages.
+    fn added_only(self) -> AddedOnly<Self> {
+        AddedOnly { stream: self }
+    }
+
+    /// Wait for the subscription `id` to be ready, or refused by a `nosub`
+    /// message. The messages up to that one are discarded. Resolves to
+    /// `None` if the stream ends first.
+    fn ready_for(&mut self, id: &str) -> ReadyFor<'_, Self> {
+        ReadyFor { stream: self, id: id.to_string() }
+    }
+
+}
+
+impl<S: Stream<Item = ServerMessage> + Unpin> DdpStreamExt for S {}
+
+/// See [`DdpStreamExt::filter_collection`].
+#[derive(Debug)]
+pub struct FilterCollection<S> {
+    stream: S,
+    collection: String,
+}
+
+impl<S: Stream<Item = ServerMessage> + Unpin> Stream for FilterCollection<S> {
+    type Item = ServerMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ServerMessage>> {
+        loop {
+            match self.stream.poll_next_unpin(cx) {
+                Poll::Ready(Some(msg)) if collection(&msg) != Some(self.collection.as_str()) => continue,
+                other => return other,
+            }
+        }
+    }
+}
+
+/// See [`DdpStreamExt::added_only`].
+#[derive(Debug)]
+pub struct AddedOnly<S> {
+    stream: S,
+}
+
+impl<S: Stream<Item = ServerMessage> + Unpin> Stream for AddedOnly<S> {
+    type Item = ServerMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ServerMessage>> {
+        loop {
+            match self.stream.poll_next_unpin(cx) {
+                Poll::Ready(Some(msg @ ServerMessage::Added { .. })) |
+                Poll::Ready(Some(msg @ ServerMessage::AddedBefore { .. })) => return Poll::Ready(Some(msg)),
+                Poll::Ready(Some(_)) => continue,
+                other => return other,
+            }
+        }
+    }
+}
+
+/// See [`DdpStreamExt::ready_for`].
+#[derive(Debug)]
+pub struct ReadyFor<'a, S> {
+    stream: &'a mut S,
+    id: String,
+}
+
+impl<S: Stream<Item = ServerMessage> + Unpin> Future for ReadyFor<'_, S> {
+    type Output = Option<SubscriptionResult>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<SubscriptionResult>> {
+        loop {
+            let msg = match self.stream.poll_next_unpin(cx) {
+                Poll::Ready(Some(msg)) => msg,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            match msg {
+                ServerMessage::Ready { subs } if subs.contains(&self.id) => return Poll::Ready(Some(Ok(()))),
+                ServerMessage::Nosub { id, error } if id == self.id => {
+                    return Poll::Ready(Some(Err(RPCError(error.unwrap_or(Value::Null)))))
+                },
+                _ => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_combinators() {
+        let added = |collection: &str, id: &str| ServerMessage::Added {
+            collection: collection.into(), id: id.into(), fields: Some(json!({}).into())
+        };
+        let messages = vec![
+            added("users", "u1"),
+            ServerMessage::Removed { collection: "rooms".into(), id: "r0".into() },
+            added("rooms", "r1"),
+            ServerMessage::Ready { subs: vec!["s1".to_string()] },
+            ServerMessage::Nosub { id: "s2".to_string(), error: Some(json!({"error": 403})) },
+        ];
+
+        let rooms: Vec<_> = futures::executor::block_on(
+            futures::stream::iter(messages.clone()).filter_collection("rooms").added_only().collect()
+        );
+        assert_eq!(rooms, vec![added("rooms", "r1")]);
+
+        let mut stream = futures::stream::iter(messages);
+        assert_eq!(futures::executor::block_on(stream.ready_for("s1")), Some(Ok(())));
+        let refused = futures::executor::block_on(stream.ready_for("s2"));
+        assert_eq!(refused, Some(Err(RPCError(json!({"error": 403})))));
+        assert_eq!(futures::executor::block_on(stream.ready_for("s3")), None);
+    }
+
+}
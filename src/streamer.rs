@@ -0,0 +1,134 @@
+//! Streamers, the event channels of the `meteor-streamer` package, as used
+//! by Rocket.Chat.
+//!
+//! A streamer is a publication named like `stream-notify-all`, subscribed
+//! once per event name. Its events come as `changed` messages on a
+//! collection named after the streamer, with the event name and arguments
+//! as the `eventName` and `args` fields.
+//!
+//! ```ignore
+//! let mut events = Streamer::subscribe(&mut handle, "stream-notify-all", &["updateAvatar", "deleteEmojiCustom"]).await?;
+//! while let Some((event, args)) = events.next().await {
+//!     println!("{}: {:?}", event, args);
+//! }
+//! ```
+
+use futures::{Stream, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use crate::connection::Handle;
+use crate::error::{Error, Result};
+use crate::protocol::ServerMessage;
+use crate::subscription::SubscriptionGuard;
+
+/// The fields of a streamer's `changed` message.
+#[derive(Deserialize)]
+struct Event {
+    #[serde(rename = "eventName")]
+    event_name: String,
+    #[serde(default)]
+    args: Value,
+}
+
+/// The events of a streamer, as `(event, args)`. Only the events subscribed
+/// to are yielded; the subscriptions are stopped when this is dropped.
+pub struct Streamer {
+    name: String,
+    events: Vec<String>,
+    messages: Pin<Box<dyn Stream<Item = ServerMessage> + Send>>,
+    _subscriptions: Vec<SubscriptionGuard>,
+}
+
+impl Streamer {
+
+    /// Subscribe to the given events of the streamer `name`, and wait until
+    /// the subscriptions are ready.
+    pub async fn subscribe(handle: &mut Handle, name: &str, events: &[&str]) -> Result<Self> {
+        let messages = Box::pin(handle.collection_stream(name).await?);
+        let mut subscriptions = Vec::new();
+        for event in events {
+            let params = (event, json!({ "useCollection": false, "args": [] }));
+            let id = handle.subscription_id();
+            let mut subscription = handle.subscribe(id, name.to_string(), params).await?;
+            if let Err(e) = subscription.ready().await? {
+                return Err(Error::Protocol(format!("subscription to {} refused: {}", name, e)))
+            }
+            subscriptions.push(subscription);
+        }
+        Ok(Self {
+            name: name.to_string(),
+            events: events.iter().map(|event| event.to_string()).collect(),
+            messages,
+            _subscriptions: subscriptions,
+        })
+    }
+
+    /// The name of the streamer.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The events subscribed to.
+    pub fn events(&self) -> &[String] {
+        &self.events
+    }
+
+}
+
+impl Stream for Streamer {
+    type Item = (String, Vec<Value>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<(String, Vec<Value>)>> {
+        loop {
+            let fields = match self.messages.poll_next_unpin(cx) {
+                Poll::Ready(Some(ServerMessage::Changed { fields: Some(fields), .. })) => fields,
+                Poll::Ready(Some(_)) => continue,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            let event = match fields.parse::<Event>() {
+                Ok(event) if self.events.contains(&event.event_name) => event,
+                _ => continue,
+            };
+            let args = match event.args {
+                Value::Array(args) => args,
+                _ => vec![],
+            };
+            return Poll::Ready(Some((event.event_name, args)))
+        }
+    }
+}
+
+impl std::fmt::Debug for Streamer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Streamer").field("name", &self.name).field("events", &self.events).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::{block_on, MockServer};
+    use crate::Connection;
+
+    #[test]
+    fn test_streamer() {
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            server.publish("stream-notify-all", vec![]);
+            let connection = Connection::connect(&server.url()).await.unwrap();
+            let mut handle = connection.handle();
+
+            let mut events = Streamer::subscribe(&mut handle, "stream-notify-all", &["updateAvatar", "public-settings-changed"]).await.unwrap();
+            for (event, args) in &[("updateAvatar", json!([{"username": "bob"}])), ("deleteEmojiCustom", json!([])), ("public-settings-changed", json!(["updated", {"_id": "Site_Name"}]))] {
+                server.changed("stream-notify-all", "id", json!({"eventName": event, "args": args}), vec![]);
+            }
+            assert_eq!(events.next().await, Some(("updateAvatar".to_string(), vec![json!({"username": "bob"})])));
+            assert_eq!(events.next().await, Some(("public-settings-changed".to_string(), vec![json!("updated"), json!({"_id": "Site_Name"})])));
+        });
+    }
+
+}
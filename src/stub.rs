@@ -0,0 +1,115 @@
+//! Client-side simulations of methods, for latency compensation as with
+//! Meteor's method stubs. Register them with [`crate::ConnectionBuilder::stub`].
+//!
+//! When a method with a stub is called, the stub runs right away against the
+//! [`Cache`], and its writes are visible at once. The documents it wrote
+//! ignore the server meanwhile; when the server reports the method `updated`,
+//! they are replaced with the server's version, which includes the real
+//! effects of the method.
+
+use serde_json::Value;
+use std::sync::Arc;
+use crate::cache::{Cache, Document};
+use crate::ids::{self, RandomStream};
+
+/// A method stub: it gets the parameters of the call.
+pub type Stub = Arc<dyn Fn(&mut StubContext<'_>, &[Value]) + Send + Sync>;
+
+/// What a stub can do to the cache.
+pub struct StubContext<'a> {
+    cache: &'a Cache,
+    call_id: &'a str,
+    random: Option<RandomStream>,
+}
+
+impl<'a> StubContext<'a> {
+
+    pub(crate) fn new(cache: &'a Cache, call_id: &'a str, random_seed: Option<&str>) -> Self {
+        Self { cache, call_id, random: random_seed.map(RandomStream::new) }
+    }
+
+    /// A copy of a document, as currently seen by the client.
+    pub fn get(&self, collection: &str, id: &str) -> Option<Document> {
+        self.cache.get(collection, id)
+    }
+
+    /// An id for a new document. With [`crate::ConnectionBuilder::random_seeds`],
+    /// it is the same as the one the server makes for its own insert.
+    pub fn insert_id(&mut self, collection: &str) -> String {
+        match &mut self.random {
+            Some(random) => random.insert_id(collection),
+            None => ids::random_id(),
+        }
+    }
+
+    pub fn insert(&mut self, collection: &str, id: &str, fields: Document) {
+        self.cache.simulate(self.call_id, collection, id, Some(fields));
+    }
+
+    /// Set some fields of a document, and remove the `cleared` ones.
+    pub fn update(&mut self, collection: &str, id: &str, fields: Document, cleared: &[&str]) {
+        let mut doc = self.get(collection, id).unwrap_or_default();
+        doc.extend(fields);
+        for field in cleared {
+            doc.remove(*field);
+        }
+        self.cache.simulate(self.call_id, collection, id, Some(doc));
+    }
+
+    pub fn remove(&mut self, collection: &str, id: &str) {
+        self.cache.simulate(self.call_id, collection, id, None);
+    }
+
+}
+
+impl std::fmt::Debug for StubContext<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StubContext").field("call_id", &self.call_id).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::Connection;
+    use crate::protocol::ClientMessage;
+    use crate::testing::{block_on, MockServer};
+    use serde_json::json;
+
+    #[test]
+    fn test_stub() {
+        block_on(async {
+            let mut server = MockServer::start().await.unwrap();
+            server.on_method("echo", |params| Ok(params[0].clone()));
+            let mut connection = Connection::builder(&server.url())
+                .stub("rename", |ctx, params| {
+                    let fields = json!({"name": params[0]}).as_object().unwrap().clone();
+                    ctx.update("rooms", "r1", fields, &[]);
+                })
+                .connect().await.unwrap();
+            let name = |connection: &Connection| connection.cache().unwrap().get("rooms", "r1").unwrap()["name"].clone();
+
+            server.added("rooms", "r1", json!({"name": "general"}));
+            connection.call("echo".to_string(), (0,)).await.unwrap().unwrap();
+            assert_eq!(name(&connection), json!("general"));
+
+            // The stub applies at once, and hides the server's writes until the method is done
+            let mut call = connection.method("rename".to_string(), ("random",)).await.unwrap();
+            server.changed("rooms", "r1", json!({"name": "off-topic"}), vec![]);
+            connection.call("echo".to_string(), (0,)).await.unwrap().unwrap();
+            assert_eq!(name(&connection), json!("random"));
+
+            let id = loop {
+                match server.next_message().await.unwrap() {
+                    ClientMessage::Method { id, method, .. } if method == "rename" => break id,
+                    _ => continue,
+                }
+            };
+            server.reply(&id, Ok(Value::Null));
+            call.updated().await.unwrap();
+            assert_eq!(name(&connection), json!("off-topic"));
+        });
+    }
+
+}
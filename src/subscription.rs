@@ -0,0 +1,220 @@
+use futures::channel::oneshot;
+use serde::Serialize;
+use std::ops::{Deref, DerefMut};
+use crate::connection::{Handle, RPCError, to_params};
+use crate::error::{Error, Result};
+
+/// Whether a subscription became ready, or was refused by the server.
+pub type SubscriptionResult = std::result::Result<(), RPCError>;
+
+/// An active subscription, see [`SubscriptionGuard`].
+/// The data it publishes still arrives on the connection's inbound stream.
+#[derive(Debug)]
+pub struct Subscription {
+    id: String,
+    name: String,
+    handle: Handle,
+    ready: Option<oneshot::Receiver<SubscriptionResult>>,
+    outcome: Option<SubscriptionResult>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl Subscription {
+
+    pub(crate) fn new(id: String, name: String, handle: Handle, ready: oneshot::Receiver<SubscriptionResult>) -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            span: tracing::debug_span!("ddp_subscription", id = id.as_str(), name = name.as_str()),
+            id, name, handle, ready: Some(ready), outcome: None,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Wait until the server has sent the initial batch of documents. If the server
+    /// refuses the subscription, the error payload of its `nosub` message is returned.
+    pub async fn ready(&mut self) -> Result<SubscriptionResult> {
+        if let Some(rx) = self.ready.take() {
+            let outcome = rx.await?;
+            #[cfg(feature = "tracing")]
+            match &outcome {
+                Ok(()) => tracing::debug!(parent: &self.span, "subscription ready"),
+                Err(e) => tracing::debug!(parent: &self.span, error = %e, "subscription refused"),
+            }
+            self.outcome = Some(outcome);
+        }
+        // If we get here without an outcome, the error was already returned
+        self.outcome.clone().ok_or(Error::Disconnected)
+    }
+
+    /// Switch to new parameters, e.g. to show another page of results. DDP
+    /// has no way to change the parameters of a subscription, so this starts
+    /// a new one, under a new id, then stops the current one. Documents
+    /// published by both stay in place meanwhile, and [`Subscription::ready`]
+    /// then tells when the new subscription is ready.
+    pub async fn update_params<P: Serialize>(&mut self, params: P) -> Result<()> {
+        let id = self.handle.subscription_id();
+        let ready = self.handle.resubscribe(self.id.clone(), id.clone(), self.name.clone(), to_params(params)?).await?;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(parent: &self.span, new_id = id.as_str(), "updating parameters");
+        self.id = id;
+        self.ready = Some(ready);
+        self.outcome = None;
+        Ok(())
+    }
+
+    /// Stop the subscription.
+    pub async fn unsubscribe(mut self) -> Result<()> {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(parent: &self.span, "unsubscribing");
+        self.handle.unsubscribe(self.id.clone()).await
+    }
+
+}
+
+/// A [`Subscription`] that is stopped when dropped, so that server-side
+/// subscriptions can't leak when a task exits early. Returned by [`Handle::subscribe`].
+#[derive(Debug)]
+#[must_use = "dropping the guard unsubscribes"]
+pub struct SubscriptionGuard {
+    inner: Option<Subscription>,
+}
+
+impl SubscriptionGuard {
+
+    pub(crate) fn new(subscription: Subscription) -> Self {
+        Self { inner: Some(subscription) }
+    }
+
+    /// Keep the subscription running after the guard is dropped.
+    pub fn detach(mut self) -> Subscription {
+        self.inner.take().expect("subscription guard")
+    }
+
+    /// Stop the subscription now, waiting for the request to be sent.
+    pub async fn unsubscribe(self) -> Result<()> {
+        self.detach().unsubscribe().await
+    }
+
+}
+
+impl Deref for SubscriptionGuard {
+    type Target = Subscription;
+
+    fn deref(&self) -> &Subscription {
+        self.inner.as_ref().expect("subscription guard")
+    }
+}
+
+impl DerefMut for SubscriptionGuard {
+    fn deref_mut(&mut self) -> &mut Subscription {
+        self.inner.as_mut().expect("subscription guard")
+    }
+}
+
+impl Drop for SubscriptionGuard {
+    fn drop(&mut self) {
+        if let Some(mut sub) = self.inner.take() {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(parent: &sub.span, "unsubscribing on drop");
+            sub.handle.unsubscribe_nowait(sub.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{Connection, ReconnectPolicy};
+    use crate::protocol::ClientMessage;
+    use crate::testing::{block_on, MockServer};
+    use serde_json::json;
+
+    #[test]
+    fn test_update_params() {
+        block_on(async {
+            let mut server = MockServer::start().await.unwrap();
+            server.publish("messages", vec![]);
+            let mut connection = Connection::connect(&server.url()).await.unwrap();
+
+            let mut sub = connection.subscribe("s1".to_string(), "messages".to_string(), (1,)).await.unwrap();
+            assert_eq!(sub.ready().await.unwrap(), Ok(()));
+            sub.update_params((2,)).await.unwrap();
+            assert_eq!(sub.ready().await.unwrap(), Ok(()));
+            assert_ne!(sub.id(), "s1");
+
+            assert!(matches!(server.next_message().await, Some(ClientMessage::Connect { .. })));
+            assert!(matches!(server.next_message().await, Some(ClientMessage::Sub { .. })));
+            match server.next_message().await {
+                Some(ClientMessage::Sub { id, params, .. }) => assert_eq!((id.as_str(), params), (sub.id(), vec![json!(2)])),
+                other => panic!("unexpected {:?}", other),
+            }
+            assert_eq!(server.next_message().await, Some(ClientMessage::Unsub { id: "s1".to_string() }));
+            let subs = connection.handle().subscriptions().await.unwrap();
+            assert_eq!(subs.iter().map(|s| s.id.as_str()).collect::<Vec<_>>(), vec![sub.id()]);
+        });
+    }
+
+    #[test]
+    fn test_ready_after_disconnect() {
+        block_on(async {
+            // Unknown publications are never answered
+            let server = MockServer::start().await.unwrap();
+            let mut connection = Connection::builder(&server.url()).reconnect(ReconnectPolicy::Never).connect().await.unwrap();
+
+            let mut sub = connection.subscribe("s1".to_string(), "pending".to_string(), ()).await.unwrap();
+            server.disconnect();
+            assert!(matches!(sub.ready().await, Err(Error::Disconnected)));
+            assert!(matches!(sub.ready().await, Err(Error::Disconnected)));
+        });
+    }
+
+    #[test]
+    fn test_discarded_guard() {
+        block_on(async {
+            let mut server = MockServer::start().await.unwrap();
+            server.publish("rooms", vec![]);
+            let mut connection = Connection::connect(&server.url()).await.unwrap();
+
+            // A guard that is not kept stops the subscription right away
+            let _ = connection.subscribe("s1".to_string(), "rooms".to_string(), ()).await.unwrap();
+            assert!(matches!(server.next_message().await, Some(ClientMessage::Connect { .. })));
+            assert!(matches!(server.next_message().await, Some(ClientMessage::Sub { id, .. }) if id == "s1"));
+            assert_eq!(server.next_message().await, Some(ClientMessage::Unsub { id: "s1".to_string() }));
+        });
+    }
+
+    #[test]
+    fn test_share_subscriptions() {
+        block_on(async {
+            let mut server = MockServer::start().await.unwrap();
+            server.publish("rooms", vec![]);
+            let mut connection = Connection::builder(&server.url()).share_subscriptions(true).connect().await.unwrap();
+
+            let mut first = connection.subscribe("s1".to_string(), "rooms".to_string(), ()).await.unwrap();
+            assert_eq!(first.ready().await.unwrap(), Ok(()));
+            let mut second = connection.subscribe("s2".to_string(), "rooms".to_string(), ()).await.unwrap();
+            assert_eq!(second.ready().await.unwrap(), Ok(()));
+            let subs = connection.handle().subscriptions().await.unwrap();
+            assert_eq!((subs.len(), subs[0].users), (1, 2));
+
+            first.unsubscribe().await.unwrap();
+            assert_eq!(connection.handle().subscriptions().await.unwrap()[0].users, 1);
+            second.unsubscribe().await.unwrap();
+            assert!(connection.handle().subscriptions().await.unwrap().is_empty());
+
+            assert!(matches!(server.next_message().await, Some(ClientMessage::Connect { .. })));
+            assert!(matches!(server.next_message().await, Some(ClientMessage::Sub { id, .. }) if id == "s1"));
+            assert_eq!(server.next_message().await, Some(ClientMessage::Unsub { id: "s1".to_string() }));
+        });
+    }
+
+}
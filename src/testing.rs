@@ -0,0 +1,346 @@
+//! A scriptable DDP server on a local port, to test client code without a
+//! Meteor application.
+//!
+//! ```ignore
+//! let mut server = MockServer::start().await?;
+//! server.on_method("add", |params| Ok(json!(params[0].as_i64() + params[1].as_i64())));
+//! server.publish("rooms", vec![ServerMessage::Added { .. }]);
+//!
+//! let mut connection = Connection::connect(&server.url()).await?;
+//! assert_eq!(connection.call("add".to_string(), (1, 2)).await?, Ok(json!(3)));
+//! ```
+
+use futures::{channel::mpsc, future::Future, select, sink::SinkExt, stream::StreamExt};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use async_tungstenite::tungstenite::Message;
+use tokio::net::{TcpListener, TcpStream};
+use crate::connection::{MethodResult, RPCError};
+use crate::error::Result;
+use crate::protocol::{ClientMessage, MethodResponse, ServerMessage};
+use log::{debug, warn};
+
+type MethodHandler = Box<dyn Fn(&[Value]) -> MethodResult + Send>;
+
+/// How the server answers subscriptions to a publication.
+enum Publication {
+    Documents(Vec<ServerMessage>),
+    Refuse(Value),
+}
+
+enum Outgoing {
+    Message(ServerMessage),
+    Disconnect,
+}
+
+#[derive(Default)]
+struct State {
+    methods: HashMap<String, MethodHandler>,
+    publications: HashMap<String, Publication>,
+    /// The connected client, if any.
+    client: Option<mpsc::UnboundedSender<Outgoing>>,
+    sessions: usize,
+    /// Never resume sessions.
+    fresh_sessions: bool,
+    /// Leave the client's pings unanswered.
+    ignore_pings: bool,
+    /// The websocket pings received, answered by the websocket library.
+    websocket_pings: usize,
+}
+
+impl State {
+
+    /// The automatic replies to a client message.
+    fn replies(&mut self, msg: &ClientMessage) -> Vec<ServerMessage> {
+        match msg {
+            ClientMessage::Connect { session, .. } => {
+                let session = match session {
+                    Some(session) if !self.fresh_sessions => session.clone(),
+                    _ => {
+                        self.sessions += 1;
+                        format!("mock-session-{}", self.sessions)
+                    },
+                };
+                vec![ServerMessage::Connected { session }]
+            },
+            ClientMessage::Ping { .. } if self.ignore_pings => vec![],
+            ClientMessage::Ping { id } => vec![ServerMessage::Pong { id: id.clone() }],
+            ClientMessage::Pong { .. } => vec![],
+            ClientMessage::Method { id, method, params, .. } => match self.methods.get(method) {
+                Some(handler) => reply(id, handler(params)),
+                None => vec![],
+            },
+            ClientMessage::Sub { id, name, .. } => match self.publications.get(name) {
+                Some(Publication::Documents(docs)) => {
+                    let mut replies = docs.clone();
+                    replies.push(ServerMessage::Ready { subs: vec![id.clone()] });
+                    replies
+                },
+                Some(Publication::Refuse(error)) => {
+                    vec![ServerMessage::Nosub { id: id.clone(), error: Some(error.clone()) }]
+                },
+                None => vec![],
+            },
+            ClientMessage::Unsub { id } => vec![ServerMessage::Nosub { id: id.clone(), error: None }],
+        }
+    }
+
+}
+
+/// The `result` and `updated` messages concluding a method call.
+fn reply(id: &str, result: MethodResult) -> Vec<ServerMessage> {
+    let response = match result {
+        Ok(result) => MethodResponse { id: id.to_string(), result: Some(result), error: None },
+        Err(RPCError(error)) => MethodResponse { id: id.to_string(), result: None, error: Some(error) },
+    };
+    vec![ServerMessage::Result(response), ServerMessage::Updated { methods: vec![id.to_string()] }]
+}
+
+/// A DDP server listening on a local port. It performs the handshake, answers
+/// pings, and replies to methods and subscriptions as scripted. Messages can
+/// also be pushed to the client at any time, and everything the client sends
+/// can be inspected with [`MockServer::next_message`].
+///
+/// One client is served at a time; a new connection replaces the previous one.
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    received: mpsc::UnboundedReceiver<ClientMessage>,
+    acceptor: tokio::task::JoinHandle<()>,
+}
+
+impl MockServer {
+
+    /// Listen on a random local port.
+    pub async fn start() -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(State::default()));
+        let (received_tx, received) = mpsc::unbounded();
+
+        let acceptor = tokio::spawn({
+            let state = state.clone();
+            async move {
+                while let Ok((stream, peer)) = listener.accept().await {
+                    debug!("Mock server: connection from {}", peer);
+                    let (state, received) = (state.clone(), received_tx.clone());
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(stream, state, received).await {
+                            warn!("Mock server: connection failed: {}", e);
+                        }
+                    });
+                }
+            }
+        });
+
+        Ok(Self { addr, state, received, acceptor })
+    }
+
+    /// The address the server listens on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The url to connect to.
+    pub fn url(&self) -> String {
+        format!("ws://{}/websocket", self.addr)
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Answer calls to a method automatically.
+    pub fn on_method<F>(&self, name: &str, handler: F)
+        where F: Fn(&[Value]) -> MethodResult + Send + 'static
+    {
+        self.state().methods.insert(name.to_string(), Box::new(handler));
+    }
+
+    /// Answer subscriptions to a publication with these document messages,
+    /// followed by `ready`.
+    pub fn publish(&self, name: &str, documents: Vec<ServerMessage>) {
+        self.state().publications.insert(name.to_string(), Publication::Documents(documents));
+    }
+
+    /// Refuse subscriptions to a publication with a `nosub` carrying this error.
+    pub fn refuse(&self, name: &str, error: Value) {
+        self.state().publications.insert(name.to_string(), Publication::Refuse(error));
+    }
+
+    /// Start a new session on every connection, as Meteor servers do,
+    /// instead of resuming the session asked for.
+    pub fn fresh_sessions(&self) {
+        self.state().fresh_sessions = true;
+    }
+
+    /// Stop answering the client's pings, as a server that hangs would.
+    pub fn ignore_pings(&self) {
+        self.state().ignore_pings = true;
+    }
+
+    /// How many websocket pings were received, as sent by
+    /// [`crate::ConnectionBuilder::keepalive`].
+    pub fn websocket_pings(&self) -> usize {
+        self.state().websocket_pings
+    }
+
+    /// Send a message to the connected client. Returns false if there is none.
+    pub fn send(&self, msg: ServerMessage) -> bool {
+        match &self.state().client {
+            Some(client) => client.unbounded_send(Outgoing::Message(msg)).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Answer a method call that has no automatic handler.
+    pub fn reply(&self, id: &str, result: MethodResult) -> bool {
+        reply(id, result).into_iter().all(|msg| self.send(msg))
+    }
+
+    pub fn added(&self, collection: &str, id: &str, fields: Value) -> bool {
+        self.send(ServerMessage::Added {
+            collection: collection.into(),
+            id: id.into(),
+            fields: Some(fields.into()),
+        })
+    }
+
+    pub fn changed(&self, collection: &str, id: &str, fields: Value, cleared: Vec<String>) -> bool {
+        self.send(ServerMessage::Changed {
+            collection: collection.into(),
+            id: id.into(),
+            fields: Some(fields.into()),
+            cleared: Some(cleared),
+        })
+    }
+
+    pub fn removed(&self, collection: &str, id: &str) -> bool {
+        self.send(ServerMessage::Removed { collection: collection.into(), id: id.into() })
+    }
+
+    /// End a subscription from the server side.
+    pub fn nosub(&self, id: &str, error: Option<Value>) -> bool {
+        self.send(ServerMessage::Nosub { id: id.to_string(), error })
+    }
+
+    /// Drop the connected client, e.g. to test reconnections.
+    pub fn disconnect(&self) {
+        if let Some(client) = self.state().client.take() {
+            let _ = client.unbounded_send(Outgoing::Disconnect);
+        }
+    }
+
+    /// The next message sent by a client, including the ones answered automatically.
+    pub async fn next_message(&mut self) -> Option<ClientMessage> {
+        self.received.next().await
+    }
+
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.acceptor.abort();
+        self.disconnect();
+    }
+}
+
+impl std::fmt::Debug for MockServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MockServer").field("addr", &self.addr).finish()
+    }
+}
+
+/// Run a future to completion on a new single-threaded tokio runtime, from
+/// a synchronous test.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()
+        .expect("failed to start a tokio runtime");
+    runtime.block_on(future)
+}
+
+/// Reconnect after `delay` every time, for tests of reconnections.
+#[cfg(test)]
+pub(crate) fn reconnect_every(delay: std::time::Duration) -> crate::ReconnectPolicy {
+    crate::ReconnectPolicy::Backoff { delay, max_delay: delay, max_attempts: None }
+}
+
+/// Run the DDP side of a client connection.
+async fn serve(stream: TcpStream, state: Arc<Mutex<State>>, received: mpsc::UnboundedSender<ClientMessage>) -> Result<()> {
+
+    let (mut up, down) = async_tungstenite::tokio::accept_async(stream).await?.split();
+    let mut down = down.fuse();
+    let (tx, mut outgoing) = mpsc::unbounded();
+    state.lock().unwrap_or_else(|e| e.into_inner()).client = Some(tx);
+
+    up.send(Message::Text(r#"{"server_id":"0"}"#.to_string())).await?;
+
+    loop {
+        let replies = select! {
+            msg = down.next() => match msg {
+                Some(Ok(Message::Text(txt))) => {
+                    let msg: ClientMessage = serde_json::from_str(&txt)?;
+                    let replies = state.lock().unwrap_or_else(|e| e.into_inner()).replies(&msg);
+                    let _ = received.unbounded_send(msg);
+                    replies
+                },
+                Some(Ok(Message::Ping(_))) => {
+                    state.lock().unwrap_or_else(|e| e.into_inner()).websocket_pings += 1;
+                    vec![]
+                },
+                Some(Ok(_)) => vec![],
+                _ => return Ok(()),
+            },
+            out = outgoing.next() => match out {
+                Some(Outgoing::Message(msg)) => vec![msg],
+                Some(Outgoing::Disconnect) | None => {
+                    let _ = up.close().await;
+                    return Ok(())
+                },
+            },
+        };
+
+        for msg in replies {
+            up.send(Message::Text(serde_json::to_string(&msg)?)).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::Connection;
+    use serde_json::json;
+
+    #[test]
+    fn test_mock_server() {
+        block_on(async {
+            let mut server = MockServer::start().await.unwrap();
+            server.on_method("add", |params| Ok(json!(params[0].as_i64().unwrap() + params[1].as_i64().unwrap())));
+            server.publish("rooms", vec![ServerMessage::Added {
+                collection: "rooms".into(), id: "r1".into(), fields: Some(json!({"name": "general"}).into())
+            }]);
+
+            let mut connection = Connection::builder(&server.url()).cache(true).connect().await.unwrap();
+            assert_eq!(connection.info().version, "1");
+            assert_eq!(connection.info().http_status.map(|s| s.as_u16()), Some(101));
+            assert!(connection.info().http_headers.as_ref().unwrap().contains_key("sec-websocket-accept"));
+            assert_eq!(connection.call("add".to_string(), (1, 2)).await.unwrap(), Ok(json!(3)));
+
+            let mut sub = connection.subscribe("s1".to_string(), "rooms".to_string(), ()).await.unwrap();
+            assert_eq!(sub.ready().await.unwrap(), Ok(()));
+            assert!(connection.cache().unwrap().get("rooms", "r1").is_some());
+            let subs = connection.handle().subscriptions().await.unwrap();
+            assert_eq!((subs[0].name.as_str(), subs[0].ready), ("rooms", true));
+            assert!(connection.handle().pending_calls().await.unwrap().is_empty());
+
+            assert!(matches!(server.next_message().await, Some(ClientMessage::Connect { .. })));
+            assert!(matches!(server.next_message().await, Some(ClientMessage::Method { .. })));
+            assert!(matches!(server.next_message().await, Some(ClientMessage::Sub { .. })));
+        });
+    }
+
+}
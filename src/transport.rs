@@ -0,0 +1,158 @@
+use futures::{AsyncRead, AsyncWrite, Sink, SinkExt, Stream, StreamExt};
+use futures::future::{BoxFuture, FutureExt};
+use async_tungstenite::{WebSocketStream, tungstenite::Message};
+use std::{pin::Pin, task::{Context, Poll}, time::Duration};
+use crate::error::{Error, Result};
+use crate::runtime;
+
+/// A bidirectional channel of text frames carrying DDP messages, such as a
+/// websocket. Any `Stream` and `Sink` of strings with these properties is a
+/// transport. The stream ends when the transport is closed.
+pub trait Transport: Stream<Item = Result<String>> + Sink<String, Error = Error> + Send + Unpin + 'static {}
+
+impl<T> Transport for T
+    where T: Stream<Item = Result<String>> + Sink<String, Error = Error> + Send + Unpin + 'static {}
+
+/// Why the server closed a websocket, from its Close frame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CloseReason {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl CloseReason {
+
+    /// Whether the server ended the connection on purpose, with code 1000
+    /// (normal closure). Connections are not re-established after such a
+    /// close. Other codes, such as 1001 (going away) from a server shutting
+    /// down, follow the [`crate::ReconnectPolicy`].
+    pub fn is_normal(&self) -> bool {
+        self.code == 1000
+    }
+
+}
+
+impl std::fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "code {}", self.code)?;
+        if !self.reason.is_empty() {
+            write!(f, ": {}", self.reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// The text frames of a websocket. Pings from the server are answered by the
+/// websocket library, and other control frames are skipped. Binary frames are
+/// read as UTF-8 text, as some proxies turn text frames into binary ones.
+/// A Close frame from the server yields [`Error::ClosedByServer`].
+pub fn websocket<S>(ws: WebSocketStream<S>) -> WebSocket<S>
+    where S: AsyncRead + AsyncWrite + Send + Unpin + 'static
+{
+    WebSocket { ws, keepalive: None }
+}
+
+/// The transport over a websocket, see [`websocket`].
+pub struct WebSocket<S> {
+    ws: WebSocketStream<S>,
+    keepalive: Option<(Duration, BoxFuture<'static, ()>)>,
+}
+
+impl<S> WebSocket<S> {
+
+    /// Send a websocket ping at every `interval`, so that proxies do not
+    /// close the connection while no DDP message is exchanged. Unlike DDP
+    /// heartbeats, nothing checks for the server's answer.
+    pub fn keepalive(mut self, interval: Duration) -> Self {
+        self.keepalive = Some((interval, runtime::sleep(interval).boxed()));
+        self
+    }
+
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocket<S> {
+
+    /// Send a ping whenever the keepalive timer expires. This is best effort:
+    /// if the websocket is busy or broken, reading from it will tell.
+    fn poll_keepalive(&mut self, cx: &mut Context<'_>) {
+        let (interval, timer) = match &mut self.keepalive {
+            Some(keepalive) => keepalive,
+            None => return,
+        };
+        while timer.poll_unpin(cx).is_ready() {
+            *timer = runtime::sleep(*interval).boxed();
+            if let Poll::Ready(Ok(())) = self.ws.poll_ready_unpin(cx) {
+                if self.ws.start_send_unpin(Message::Ping(Vec::new())).is_ok() {
+                    let _ = self.ws.poll_flush_unpin(cx);
+                }
+            }
+        }
+    }
+
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream for WebSocket<S> {
+    type Item = Result<String>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<String>>> {
+        self.poll_keepalive(cx);
+        loop {
+            return match futures::ready!(self.ws.poll_next_unpin(cx)) {
+                Some(Ok(Message::Text(text))) => Poll::Ready(Some(Ok(text))),
+                Some(Ok(Message::Binary(data))) => Poll::Ready(Some(String::from_utf8(data)
+                    .map_err(|e| Error::Protocol(format!("binary frame is not UTF-8: {}", e))))),
+                Some(Ok(Message::Close(frame))) => {
+                    let reason = match frame {
+                        Some(frame) => CloseReason { code: frame.code.into(), reason: frame.reason.into_owned() },
+                        // No status received
+                        None => CloseReason { code: 1005, reason: String::new() },
+                    };
+                    Poll::Ready(Some(Err(Error::ClosedByServer(reason))))
+                },
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => Poll::Ready(Some(Err(e.into()))),
+                None => Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Sink<String> for WebSocket<S> {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.ws.poll_ready_unpin(cx).map_err(Error::from)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, text: String) -> Result<()> {
+        self.ws.start_send_unpin(Message::Text(text)).map_err(Error::from)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.ws.poll_flush_unpin(cx).map_err(Error::from)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.ws.poll_close_unpin(cx).map_err(Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::Connection;
+    use crate::testing::{block_on, MockServer};
+
+    #[test]
+    fn test_keepalive() {
+        block_on(async {
+            let server = MockServer::start().await.unwrap();
+            let connection = Connection::builder(&server.url()).keepalive(Duration::from_millis(50)).connect().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            assert!(server.websocket_pings() >= 2);
+            assert_eq!(*connection.status().borrow(), crate::ConnectionStatus::Connected);
+        });
+    }
+
+}